@@ -0,0 +1,46 @@
+//! Hashing throughput of a large file with the default and a 1MB I/O buffer
+//!
+//! Run with `cargo bench --bench io_buffer`. The file is read from the page
+//! cache after the first pass, so the difference is mostly syscall overhead.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use diffpatch::diff::{HashAlgorithm, calculate_file_hash_buffered};
+use diffpatch::utils::DEFAULT_IO_BUFFER_SIZE;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Size of the file hashed by each iteration
+const FILE_SIZE: usize = 64 << 20;
+
+fn hash_with_buffer_sizes(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large.bin");
+    let mut writer = BufWriter::new(File::create(&path).unwrap());
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    for _ in 0..FILE_SIZE / 8 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        writer.write_all(&state.to_le_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+
+    let mut group = c.benchmark_group("hash_large_file");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    for buffer_size in [DEFAULT_IO_BUFFER_SIZE, 1 << 20] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &buffer_size,
+            |b, &buffer_size| {
+                b.iter(|| {
+                    calculate_file_hash_buffered(&path, HashAlgorithm::Sha256, buffer_size).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hash_with_buffer_sizes);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+//! Time to compare two large trees with sequential and concurrent scans
+//!
+//! Run with `cargo bench --bench scan`. Both trees are read from the page
+//! cache after the first pass, so this measures hashing and thread overlap
+//! more than the storage itself.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use diffpatch::CreateOptions;
+use diffpatch::diff::compare_directories;
+use std::fs;
+use std::path::Path;
+
+/// Files in each tree, spread over `DIRS` directories
+const FILES: usize = 2000;
+const DIRS: usize = 20;
+/// Size of every file
+const FILE_SIZE: usize = 32 << 10;
+
+/// Write a tree of files whose content depends on `seed`
+fn write_tree(root: &Path, seed: u64) {
+    for i in 0..FILES {
+        let dir = root.join(format!("dir{}", i % DIRS));
+        fs::create_dir_all(&dir).unwrap();
+        // Every tenth file differs between the trees
+        let salt = if i % 10 == 0 { seed } else { 0 };
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64 ^ (i as u64) ^ salt;
+        let content: Vec<u8> = (0..FILE_SIZE / 8)
+            .flat_map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()
+            })
+            .collect();
+        fs::write(dir.join(format!("file{}.bin", i)), content).unwrap();
+    }
+}
+
+fn compare_large_trees(c: &mut Criterion) {
+    let root = tempfile::tempdir().unwrap();
+    let source = root.path().join("source");
+    let target = root.path().join("target");
+    write_tree(&source, 1);
+    write_tree(&target, 2);
+
+    let mut group = c.benchmark_group("compare_directories");
+    group.sample_size(10);
+    for parallel_scan in [false, true] {
+        let options = CreateOptions::new().parallel_scan(parallel_scan);
+        let name = if parallel_scan {
+            "parallel"
+        } else {
+            "sequential"
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &options, |b, options| {
+            b.iter(|| compare_directories(&source, &target, options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compare_large_trees);
+criterion_main!(benches);
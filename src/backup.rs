@@ -0,0 +1,242 @@
+use crate::patch::PatchData;
+use crate::utils::{copy_dir_all, create_symlink};
+use anyhow::{Context, Result, anyhow};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory under the patched directory that holds `--backup` backups
+pub const BACKUP_DIR: &str = ".diffpatch-backup";
+
+/// Directory under the patched directory holding the originals while a patch
+/// is applied without `--backup`; it is deleted once the apply succeeds
+pub const ROLLBACK_DIR: &str = ".diffpatch-rollback";
+
+/// File under the patched directory recording an apply that has not finished
+pub const RESUME_FILE: &str = ".diffpatch-resume.json";
+
+/// File under the patched directory holding the version of the last patch applied
+pub const VERSION_FILE: &str = ".diffpatch-version";
+
+/// Record of the backup, written once every entry has been saved
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Directory under `BACKUP_DIR` mirroring the saved entries' relative paths
+const FILES_DIR: &str = "files";
+
+/// What a backup holds and how to undo the patch with it
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BackupManifest {
+    pub saved: Vec<PathBuf>, // entries copied before being overwritten or removed
+    pub created: Vec<PathBuf>, // entries that did not exist and are deleted on restore
+}
+
+/// How far an apply got before it was interrupted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Originals were being saved, nothing was written yet
+    Backup,
+    /// Originals are saved and changes are being written
+    Write,
+}
+
+/// Apply in progress, kept until it finishes so `--resume` can pick it up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeState {
+    /// SHA256 of the patch data, so only the same patch is resumed
+    pub patch: String,
+    pub stage: Stage,
+}
+
+impl ResumeState {
+    /// The state left in a directory, if an apply did not finish there
+    pub fn load(current_dir: &Path) -> Result<Option<Self>> {
+        let path = current_dir.join(RESUME_FILE);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read resume state: {}", path.display()));
+            }
+        };
+        let state = serde_json::from_slice(&content)
+            .with_context(|| format!("Invalid resume state: {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    pub fn save(&self, current_dir: &Path) -> Result<()> {
+        let content =
+            serde_json::to_vec_pretty(self).context("Failed to serialize resume state")?;
+        fs::write(current_dir.join(RESUME_FILE), content).context("Failed to write resume state")
+    }
+
+    /// Forget the state once the apply finished or was undone
+    pub fn clear(current_dir: &Path) -> Result<()> {
+        remove_entry(&current_dir.join(RESUME_FILE))
+    }
+}
+
+/// Save every entry the patch will overwrite or remove
+///
+/// Existing files (and directories replaced by a type change) are copied to
+/// `backup_dir`, and paths the patch creates are recorded so `restore_from`
+/// can delete them again.
+pub fn backup_before_apply(
+    backup_dir: &Path,
+    current_dir: &Path,
+    patch_data: &PatchData,
+    removals: &[PathBuf],
+) -> Result<BackupManifest> {
+    if backup_dir.join(MANIFEST_FILE).exists() {
+        return Err(anyhow!(
+            "A backup from an earlier apply exists in {}. Restore or delete it first.",
+            backup_dir.display()
+        ));
+    }
+
+    // A versioned patch also records its version, which a restore puts back
+    let version_file = patch_data
+        .metadata
+        .patch_version
+        .as_ref()
+        .map(|_| Path::new(VERSION_FILE));
+    let written = patch_data
+        .written_paths()
+        .chain(
+            patch_data
+                .type_changes
+                .iter()
+                .map(|c| c.relative_path.as_path()),
+        )
+        .chain(version_file);
+
+    // A type-changed path is also listed as the file that replaces it
+    let mut seen = HashSet::new();
+    let mut manifest = BackupManifest::default();
+    for path in written.filter(|path| seen.insert(*path)) {
+        if current_dir.join(path).symlink_metadata().is_ok() {
+            save_entry(current_dir, backup_dir, path)?;
+            manifest.saved.push(path.to_path_buf());
+        } else {
+            manifest.created.push(path.to_path_buf());
+        }
+    }
+    // Renamed files leave their old path like removed ones
+    let removed = removals
+        .iter()
+        .map(PathBuf::as_path)
+        .chain(patch_data.renamed_files.iter().map(|r| r.from.as_path()));
+    for path in removed.filter(|path| seen.insert(*path)) {
+        if current_dir.join(path).symlink_metadata().is_ok() {
+            save_entry(current_dir, backup_dir, path)?;
+            manifest.saved.push(path.to_path_buf());
+        }
+    }
+
+    let content =
+        serde_json::to_vec_pretty(&manifest).context("Failed to serialize backup manifest")?;
+    fs::create_dir_all(backup_dir)
+        .with_context(|| format!("Failed to create directory: {}", backup_dir.display()))?;
+    fs::write(backup_dir.join(MANIFEST_FILE), content)
+        .context("Failed to write backup manifest")?;
+    info!(
+        "Backed up {} entries to {}",
+        manifest.saved.len(),
+        backup_dir.display()
+    );
+    Ok(manifest)
+}
+
+/// Copy one file or directory into the backup
+fn save_entry(current_dir: &Path, backup_dir: &Path, relative_path: &Path) -> Result<()> {
+    let src = current_dir.join(relative_path);
+    let dest = backup_dir.join(FILES_DIR).join(relative_path);
+    let is_link = src.symlink_metadata().is_ok_and(|m| m.is_symlink());
+    if src.is_dir() && !is_link {
+        return copy_dir_all(&src, &dest);
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    if is_link {
+        let link_target = fs::read_link(&src)
+            .with_context(|| format!("Failed to read link: {}", src.display()))?;
+        return create_symlink(&link_target, &dest)
+            .with_context(|| format!("Failed to back up link: {}", src.display()));
+    }
+    fs::copy(&src, &dest).with_context(|| format!("Failed to back up file: {}", src.display()))?;
+    Ok(())
+}
+
+/// Undo an apply made with `--backup`, or finish rolling back an interrupted one
+pub fn restore(current_dir: &Path) -> Result<BackupManifest> {
+    let rollback_dir = current_dir.join(ROLLBACK_DIR);
+    let manifest = if rollback_dir.join(MANIFEST_FILE).exists() {
+        restore_from(&rollback_dir, current_dir)?
+    } else {
+        restore_from(&current_dir.join(BACKUP_DIR), current_dir)?
+    };
+    // An interrupted apply that was undone can no longer be resumed
+    ResumeState::clear(current_dir)?;
+    Ok(manifest)
+}
+
+/// Put back the entries saved in `backup_dir`, then delete the backup
+pub fn restore_from(backup_dir: &Path, current_dir: &Path) -> Result<BackupManifest> {
+    let manifest_path = backup_dir.join(MANIFEST_FILE);
+    let content = fs::read(&manifest_path)
+        .with_context(|| format!("No backup found: {}", manifest_path.display()))?;
+    let manifest: BackupManifest =
+        serde_json::from_slice(&content).context("Failed to parse backup manifest")?;
+
+    // Deeper paths first, so files created inside new directories go before them
+    let mut created: Vec<&PathBuf> = manifest.created.iter().collect();
+    created.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    for path in created {
+        remove_entry(&current_dir.join(path))?;
+        remove_empty_parents(current_dir, path);
+    }
+
+    for path in &manifest.saved {
+        let dest = current_dir.join(path);
+        remove_entry(&dest)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        let saved = backup_dir.join(FILES_DIR).join(path);
+        fs::rename(&saved, &dest)
+            .with_context(|| format!("Failed to restore: {}", dest.display()))?;
+    }
+
+    fs::remove_dir_all(backup_dir)
+        .with_context(|| format!("Failed to delete backup: {}", backup_dir.display()))?;
+    Ok(manifest)
+}
+
+/// Remove a file or directory if it exists
+fn remove_entry(path: &Path) -> Result<()> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(());
+    };
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    result.with_context(|| format!("Failed to remove: {}", path.display()))
+}
+
+/// Remove directories left empty after deleting `relative_path`
+fn remove_empty_parents(current_dir: &Path, relative_path: &Path) {
+    for parent in relative_path.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || fs::remove_dir(current_dir.join(parent)).is_err() {
+            break;
+        }
+    }
+}
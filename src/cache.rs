@@ -0,0 +1,130 @@
+use crate::diff::HashAlgorithm;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+/// File name of the hash cache in the user's cache directory
+pub const CACHE_FILE: &str = "hash-cache.json";
+
+/// Where the hash cache is kept unless `--cache-file` says otherwise
+///
+/// This is `diffpatch/hash-cache.json` in the per-user cache directory, so
+/// other users on the machine can neither read nor replace it. `None` when
+/// the platform has no such directory.
+pub fn default_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("diffpatch").join(CACHE_FILE))
+}
+
+/// Hash of a file as it was when it was last read
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct CachedHash {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    algorithm: HashAlgorithm,
+    hash: String,
+}
+
+/// Whole-file hashes kept between runs, keyed by absolute path
+///
+/// An entry is only used while the file keeps the size and modification time
+/// it had when it was hashed.
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, CachedHash>>,
+    changed: AtomicBool,
+}
+
+impl HashCache {
+    /// Read the cache file, starting empty if it is missing or unreadable
+    pub fn load(path: &Path) -> Self {
+        let entries = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+                debug!("Ignoring unreadable hash cache {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// The cached hash of a file, if it hasn't changed since it was hashed
+    pub fn get(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        algorithm: HashAlgorithm,
+    ) -> Option<String> {
+        let key = std::path::absolute(path).ok()?;
+        let entries = self.entries.lock().unwrap();
+        let cached = entries.get(&key)?;
+        let current = entry_for(metadata, algorithm, cached.hash.clone())?;
+        (current == *cached).then_some(current.hash)
+    }
+
+    pub fn insert(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        algorithm: HashAlgorithm,
+        hash: &str,
+    ) {
+        let (Some(entry), Ok(key)) = (
+            entry_for(metadata, algorithm, hash.to_string()),
+            std::path::absolute(path),
+        ) else {
+            return;
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the cache back if anything was hashed
+    pub fn save(&self) -> Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = serde_json::to_vec(&*self.entries.lock().unwrap())
+            .context("Failed to serialize hash cache")?;
+        // Written aside and renamed, so a concurrent run never reads half a cache
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let write = || -> Result<()> {
+            fs::create_dir_all(dir)?;
+            let mut staged = tempfile::NamedTempFile::new_in(dir)?;
+            staged.write_all(&content)?;
+            staged.persist(&self.path)?;
+            Ok(())
+        };
+        write().with_context(|| format!("Failed to write hash cache: {}", self.path.display()))
+    }
+}
+
+fn entry_for(
+    metadata: &fs::Metadata,
+    algorithm: HashAlgorithm,
+    hash: String,
+) -> Option<CachedHash> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(CachedHash {
+        size: metadata.len(),
+        modified_secs: modified.as_secs(),
+        modified_nanos: modified.subsec_nanos(),
+        algorithm,
+        hash,
+    })
+}
@@ -1,43 +1,154 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use diffpatch::diff::{DiffAlgorithm, HashAlgorithm};
+use diffpatch::options::ApplyOptions;
+use diffpatch::patch::{Compression, OnConflict, PatchFormat};
+use diffpatch::transform::EolMode;
+use diffpatch::utils::ProgressMode;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Buffer size in bytes for file reads and writes (default: 65536)
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub io_buffer_size: Option<NonZeroUsize>,
+
+    /// Threads for hashing, compressing and copying files, 0 for one per logical core
+    /// (default: chosen from the storage type, or DIFFPATCH_IO_THREADS)
+    #[arg(long, global = true, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Answer yes when Enter is pressed at a confirmation prompt
+    #[arg(long, global = true)]
+    pub default_yes: bool,
+
+    /// Answer yes to every confirmation without prompting (also DIFFPATCH_ASSUME_YES=1)
+    #[arg(short = 'y', long, global = true)]
+    pub yes: bool,
+
+    /// When to show progress bars (auto: only on a terminal, plain lines otherwise)
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t)]
+    pub progress: ProgressMode,
+
+    /// Log more detail (-v for debugging, -vv for tracing)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Create a patch file
-    Create {
-        /// Source directory path
+    Create(Box<CreateArgs>),
+
+    /// Finish an interrupted apply, rewriting only the entries that drifted
+    Repair {
+        /// Patch file path
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
+
+        /// Directory the patch was partially applied to
         #[arg(short, long, value_name = "DIR")]
-        source: PathBuf,
+        target: PathBuf,
+    },
 
-        /// Target directory path
+    /// Undo a patch applied with --backup, or finish rolling back an interrupted apply
+    Restore {
+        /// Directory the patch was applied to
         #[arg(short, long, value_name = "DIR")]
         target: PathBuf,
+    },
 
-        /// Output patch file path
-        #[arg(short, long, value_name = "FILE")]
-        output: PathBuf,
+    /// Check that a patch file is well-formed before distributing it
+    Verify {
+        /// Patch file path
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
+    },
+
+    /// Check that a directory matches the end state of an applied patch
+    VerifyApplied {
+        /// Patch file path
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
+
+        /// Directory the patch was applied to
+        #[arg(short, long, value_name = "DIR")]
+        target: PathBuf,
+    },
+
+    /// Show how one patch differs from another
+    DiffPatches {
+        /// Previous patch file
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+
+        /// New patch file
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+    },
+
+    /// Apply several patches in order, each to the result of the previous one
+    ApplyChain {
+        /// Patch files in the order to apply them (archives or patch executables)
+        #[arg(value_name = "PATCH", required = true)]
+        patches: Vec<PathBuf>,
+
+        /// Directory to patch (defaults to the current directory)
+        #[arg(short, long, value_name = "DIR")]
+        target: Option<PathBuf>,
+
+        /// Object store location to patch instead of a directory, e.g. s3://bucket/prefix
+        /// (credentials are read from the AWS_* environment variables)
+        #[arg(long, value_name = "URI", conflicts_with = "target")]
+        target_uri: Option<String>,
 
-        /// Verification file list (comma-separated relative paths)
-        #[arg(short, long, value_name = "FILES", value_delimiter = ',')]
-        check_files: Vec<String>,
+        #[command(flatten)]
+        options: ApplyArgs,
+    },
+
+    /// Hash every file in a directory into a manifest, e.g. to build patches from
+    /// with `create --source-manifest`
+    Manifest(ManifestArgs),
+
+    /// Apply a patch written with `create --format archive`
+    ApplyArchive {
+        /// Patch archive path
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
+
+        /// Directory to patch (defaults to the current directory)
+        #[arg(short, long, value_name = "DIR")]
+        target: Option<PathBuf>,
+
+        /// Object store location to patch instead of a directory, e.g. s3://bucket/prefix
+        /// (credentials are read from the AWS_* environment variables)
+        #[arg(long, value_name = "URI", conflicts_with = "target")]
+        target_uri: Option<String>,
+
+        #[command(flatten)]
+        options: ApplyArgs,
+    },
 
-        /// Exclude file extensions (comma-separated, e.g., .tmp,.bak,.log)
-        #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
-        exclude_extensions: Option<Vec<String>>,
+    /// Show what a patch file contains without running it
+    Inspect {
+        /// Patch file path
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
 
-        /// Exclude directories (comma-separated relative paths, e.g., node_modules,dist,target)
-        #[arg(long, value_name = "DIRECTORIES", value_delimiter = ',')]
-        exclude_dirs: Option<Vec<String>>,
+        /// Print the decoded patch data as JSON
+        #[arg(long)]
+        json: bool,
 
-        /// Use file difference patches instead of storing full files (default: false)
-        #[arg(long, default_value = "true")]
-        use_diff_patches: bool,
+        /// Also print the line changes of every diffed file as a unified diff
+        #[arg(long, conflicts_with = "json")]
+        diffs: bool,
     },
 
     #[command(hide = true)]
@@ -45,9 +156,459 @@ pub enum Commands {
         /// Patch data file path
         #[arg(short, long, value_name = "FILE")]
         patch_data: PathBuf,
+
+        /// Directory to patch (defaults to the current directory)
+        #[arg(short, long, value_name = "DIR")]
+        target: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: ApplyArgs,
     },
 }
 
-pub fn parse_args() -> Cli {
-    Cli::parse()
+/// Options accepted when creating a patch
+#[derive(Args, Debug)]
+pub struct CreateArgs {
+    /// Source directory path (not needed with --source-manifest)
+    #[arg(short, long, value_name = "DIR")]
+    pub source: Option<PathBuf>,
+
+    /// Compare against the files in this manifest (JSON) instead of a source directory;
+    /// modified files are stored in full since there is no old content to diff against
+    #[arg(long, value_name = "FILE")]
+    pub source_manifest: Option<PathBuf>,
+
+    /// Target directory path (required here or in the config file)
+    #[arg(short, long, value_name = "DIR")]
+    pub target: Option<PathBuf>,
+
+    /// Output patch file path (required here or in the config file)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Read option defaults from this TOML file instead of <SOURCE>/diffpatch.toml;
+    /// options given on the command line take precedence
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Verification file list (comma-separated relative paths); add :<SHA256> to a
+    /// path to also require that exact content, e.g. bin/game.dll:3a7bd3e2...
+    #[arg(short, long, value_name = "FILES", value_delimiter = ',')]
+    pub check_files: Vec<String>,
+
+    /// Create a patch without verification files, which applies to whatever directory
+    /// it is run in, without asking first
+    #[arg(long)]
+    pub allow_unverified: bool,
+
+    /// Exclude file extensions (comma-separated, e.g., .tmp,.bak,.log)
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub exclude_extensions: Option<Vec<String>>,
+
+    /// Exclude directories (comma-separated relative paths, e.g., node_modules,dist,target)
+    #[arg(long, value_name = "DIRECTORIES", value_delimiter = ',')]
+    pub exclude_dirs: Option<Vec<String>>,
+
+    /// Exclude relative paths matching a gitignore-style glob (repeatable, e.g. build/**/*.o)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude_glob: Vec<String>,
+
+    /// Read gitignore-style exclude patterns from a file (one per line)
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Option<PathBuf>,
+
+    /// Only consider relative paths matching this glob (repeatable, e.g. config/*.yaml);
+    /// excludes still apply to matching files
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Use file difference patches instead of storing full files (default: false)
+    #[arg(long, default_value = "true")]
+    pub use_diff_patches: bool,
+
+    /// License/EULA text file that must be accepted before the patch is applied
+    #[arg(long, value_name = "FILE")]
+    pub eula_file: Option<PathBuf>,
+
+    /// Also create a reverse patch (target -> source) that undoes this one
+    #[arg(long, visible_alias = "with-reverse", value_name = "FILE")]
+    pub emit_reverse: Option<PathBuf>,
+
+    /// Attempts at writing the output executable before giving up (e.g. when locked by antivirus)
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub copy_retries: u32,
+
+    /// Leave out files that cannot be read or copied, or whose names aren't valid UTF-8,
+    /// with a warning instead of failing. The patch is then incomplete
+    #[arg(long)]
+    pub continue_on_error: bool,
+
+    /// Compute content-defined chunk fingerprints to detect near-duplicate files
+    #[arg(long)]
+    pub cdc: bool,
+
+    /// Only patch the files listed in this file (one relative path per line) instead of scanning
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// Files to remove when using --manifest (one relative path per line)
+    #[arg(long, value_name = "FILE", requires = "manifest")]
+    pub manifest_removals: Option<PathBuf>,
+
+    /// Scan the source and target directories at the same time (helps when scans wait on storage)
+    #[arg(long)]
+    pub parallel_scan: bool,
+
+    /// Skip hashing files whose size and modification time match between the source and
+    /// target, treating them as unchanged (faster, but misses edits that kept both)
+    #[arg(long)]
+    pub fast: bool,
+
+    /// Keep file hashes between runs in this file so unchanged files are not read again
+    /// (default: diffpatch/hash-cache.json in the user's cache directory)
+    #[arg(long, value_name = "FILE")]
+    pub cache_file: Option<PathBuf>,
+
+    /// Hash every file instead of reusing hashes from the cache file
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Abort unless the source directory matches this manifest (JSON)
+    #[arg(long, value_name = "FILE")]
+    pub expect_source_manifest: Option<PathBuf>,
+
+    /// Print a unified diff of every modified file before confirming
+    #[arg(long)]
+    pub show_diff: bool,
+
+    /// List what the patch would contain, with sizes, without writing it
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// File listing relative paths or glob patterns to always remove (one per line),
+    /// for leftovers from older versions that are not in the source directory
+    #[arg(long, value_name = "FILE")]
+    pub force_remove: Option<PathBuf>,
+
+    /// Diff C-like source files by indentation and brace/semicolon tokens instead of
+    /// whole lines, giving smaller diffs for reformatted code
+    #[arg(long)]
+    pub syntax_diff: bool,
+
+    /// Unchanged lines stored before each changed block of a diff; more context helps
+    /// --three-way find the right spot in files that drifted, less keeps patches small
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub context: usize,
+
+    /// Algorithm for finding changed lines; patience often gives smaller, more readable
+    /// hunks for source code
+    #[arg(long, value_enum, value_name = "ALGORITHM", default_value_t)]
+    pub diff_algorithm: DiffAlgorithm,
+
+    /// Store modified files larger than this whole instead of diffing them, which is
+    /// slow and memory-hungry for huge files such as logs
+    #[arg(long, value_name = "BYTES")]
+    pub max_diff_size: Option<u64>,
+
+    /// Fail with exit code 2 instead of succeeding when no differences are found,
+    /// e.g. because the excludes filtered out every file
+    #[arg(long)]
+    pub require_changes: bool,
+
+    /// Store each diff as a separate zip entry read on demand, keeping the patch
+    /// metadata small for patches with many diffed files
+    #[arg(long)]
+    pub diffs_in_zip: bool,
+
+    /// Build a targeted patch that only updates modified files whose source
+    /// content matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    pub only_if_contains: Option<String>,
+
+    /// Hash algorithm for the file hashes recorded in the patch
+    #[arg(long = "hash", value_enum, value_name = "ALGORITHM", default_value_t)]
+    pub hash_algorithm: HashAlgorithm,
+
+    /// Treat symbolic links as the files and directories they point to instead of
+    /// recreating them as links
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Compression of the files in the patch (stored skips it for compressed assets)
+    #[arg(long, value_enum, value_name = "METHOD", default_value_t)]
+    pub compression: Compression,
+
+    /// Compression level (deflate 0-9, zstd 1-22)
+    #[arg(long, value_name = "N", allow_negative_numbers = true)]
+    pub compression_level: Option<i64>,
+
+    /// Encrypt the patch with a passphrase (prompted, or read from DIFFPATCH_PASSPHRASE)
+    #[arg(long)]
+    pub encrypt: bool,
+
+    /// Version of this patch, shown when it is applied (e.g. 1.4.2)
+    #[arg(long, value_name = "VERSION", value_parser = parse_patch_version)]
+    pub patch_version: Option<String>,
+
+    /// Only apply on top of this --patch-version, as recorded in .diffpatch-version
+    /// by the previous patch (e.g. 1.4.1)
+    #[arg(long, value_name = "VERSION", value_parser = parse_patch_version)]
+    pub requires_version: Option<String>,
+
+    /// Author recorded in the patch
+    #[arg(long, value_name = "NAME")]
+    pub author: Option<String>,
+
+    /// Description of the patch, shown when it is applied
+    #[arg(long, value_name = "TEXT")]
+    pub description: Option<String>,
+
+    /// Directory for temporary files (default: TMPDIR or the system temporary directory)
+    #[arg(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Name temporary files after this seed instead of at random, so repeated builds
+    /// use the same scratch paths (not allowed with --encrypt)
+    #[arg(long, value_name = "N", conflicts_with = "encrypt")]
+    pub seed: Option<u64>,
+
+    /// Include files and directories whose name starts with a dot (e.g. .env, .htaccess),
+    /// which are skipped by default
+    #[arg(long)]
+    pub include_hidden: bool,
+
+    /// Write a self-applying Windows executable, or a .dpatch archive applied with
+    /// `diffpatch apply-archive` on any OS
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t)]
+    pub format: PatchFormat,
+
+    /// diffpatch binary the patch is appended to instead of this one, e.g. a Linux or
+    /// macOS build to make a self-applying patch for that platform
+    #[arg(long, value_name = "FILE")]
+    pub stub: Option<PathBuf>,
+}
+
+/// Options accepted when writing a manifest, filtering files the same way as `create`
+#[derive(Args, Debug)]
+pub struct ManifestArgs {
+    /// Directory to hash
+    #[arg(short, long, value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Manifest file to write (JSON)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+
+    /// Exclude file extensions (comma-separated, e.g., .tmp,.bak,.log)
+    #[arg(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub exclude_extensions: Vec<String>,
+
+    /// Exclude directories (comma-separated relative paths, e.g., node_modules,dist,target)
+    #[arg(long, value_name = "DIRECTORIES", value_delimiter = ',')]
+    pub exclude_dirs: Vec<String>,
+
+    /// Exclude relative paths matching a gitignore-style glob (repeatable, e.g. build/**/*.o)
+    #[arg(long, value_name = "GLOB")]
+    pub exclude_glob: Vec<String>,
+
+    /// Read gitignore-style exclude patterns from a file (one per line)
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Option<PathBuf>,
+
+    /// Only hash relative paths matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Include files and directories whose name starts with a dot
+    #[arg(long)]
+    pub include_hidden: bool,
+
+    /// Hash the files symbolic links point to instead of recording the links
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Hash algorithm, which must match the --hash of patches built from the manifest
+    #[arg(long = "hash", value_enum, value_name = "ALGORITHM", default_value_t)]
+    pub hash_algorithm: HashAlgorithm,
+}
+
+/// Accept semver-like versions: dot-separated numbers with an optional -/+ suffix
+pub fn parse_patch_version(value: &str) -> Result<String, String> {
+    let (core, suffix) = match value.find(['-', '+']) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let numeric = !core.is_empty()
+        && core
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if !numeric || suffix.len() == 1 {
+        return Err(format!(
+            "expected a version like 1.4.2 or 2.0.0-beta, got \"{}\"",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Options accepted when applying a patch
+#[derive(Args, Debug, Clone, Default)]
+pub struct ApplyArgs {
+    /// Accept the embedded license/EULA without prompting
+    #[arg(long)]
+    pub accept_eula: bool,
+
+    /// Convert line endings of text files as they are written
+    #[arg(long, value_enum, value_name = "STYLE")]
+    pub eol: Option<EolMode>,
+
+    /// Refuse to run unless the patch file has this published SHA256
+    #[arg(long, value_name = "HEX")]
+    pub expect_sha256: Option<String>,
+
+    /// Directory to copy before patching (defaults to the directory being patched)
+    #[arg(long, value_name = "DIR", requires = "out")]
+    pub base: Option<PathBuf>,
+
+    /// Apply to a fresh copy of the base directory in this new directory,
+    /// leaving the base untouched
+    #[arg(long, value_name = "DIR")]
+    pub out: Option<PathBuf>,
+
+    /// Merge diffs into locally edited files, writing conflict markers where
+    /// both sides changed the same lines
+    #[arg(long)]
+    pub three_way: bool,
+
+    /// Print a hash of the whole directory before and after applying, as a
+    /// compact fingerprint of the transition
+    #[arg(long)]
+    pub merkle: bool,
+
+    /// List the files that would be added, overwritten, patched, or removed
+    /// without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Copy files that are overwritten or removed to .diffpatch-backup so the
+    /// apply can be undone with `diffpatch restore`
+    #[arg(long)]
+    pub backup: bool,
+
+    /// Unpack the whole patch to a temporary directory and check it before
+    /// writing any file, instead of extracting files straight into place
+    #[arg(long)]
+    pub safe: bool,
+
+    /// Write a JSON report of each file added, modified, diffed, or removed,
+    /// whether it succeeded or was skipped, and how long the apply took
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Only warn when a written file's hash differs from the patch, instead of
+    /// rolling the apply back
+    #[arg(long)]
+    pub allow_hash_mismatch: bool,
+
+    /// Finish an apply that was interrupted (e.g. by a power loss), leaving files
+    /// it already wrote alone
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Directory for temporary files (default: TMPDIR or the system temporary directory),
+    /// e.g. on the same volume as the target when the system one is small
+    #[arg(long, value_name = "DIR")]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Keep directories left empty after removing files (e.g. a pre-created layout)
+    #[arg(long)]
+    pub keep_empty_dirs: bool,
+
+    /// What to do with a fully replaced file that was changed since the patch was made
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t)]
+    pub on_conflict: OnConflict,
+
+    /// Ask before each file is added, changed or removed, showing what diffs change
+    #[arg(long)]
+    pub interactive: bool,
+
+    /// Make read-only files writable and retry when overwriting or removing them is refused
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl From<&ApplyArgs> for ApplyOptions {
+    fn from(args: &ApplyArgs) -> Self {
+        ApplyOptions::new()
+            .accept_eula(args.accept_eula)
+            .eol(args.eol)
+            .expect_sha256(args.expect_sha256.clone())
+            .base(args.base.clone())
+            .out(args.out.clone())
+            .three_way(args.three_way)
+            .merkle(args.merkle)
+            .dry_run(args.dry_run)
+            .backup(args.backup)
+            .safe(args.safe)
+            .report(args.report.clone())
+            .allow_hash_mismatch(args.allow_hash_mismatch)
+            .resume(args.resume)
+            .temp_dir(args.temp_dir.clone())
+            .keep_empty_dirs(args.keep_empty_dirs)
+            .on_conflict(args.on_conflict)
+            .interactive(args.interactive)
+            .force(args.force)
+    }
+}
+
+/// Command line used when running as a self-applying patch executable
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Apply the embedded patch", long_about = None)]
+pub struct PatchCli {
+    /// Directory to patch (defaults to the current directory)
+    #[arg(value_name = "DIR")]
+    pub target: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub options: ApplyArgs,
+
+    /// Buffer size in bytes for file reads and writes (default: 65536)
+    #[arg(long, value_name = "BYTES")]
+    pub io_buffer_size: Option<NonZeroUsize>,
+
+    /// Threads for hashing and copying files, 0 for one per logical core
+    /// (default: chosen from the storage type, or DIFFPATCH_IO_THREADS)
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Answer yes when Enter is pressed at a confirmation prompt
+    #[arg(long)]
+    pub default_yes: bool,
+
+    /// Answer yes to every confirmation without prompting (also DIFFPATCH_ASSUME_YES=1)
+    #[arg(short = 'y', long)]
+    pub yes: bool,
+
+    /// When to show progress bars (auto: only on a terminal, plain lines otherwise)
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t)]
+    pub progress: ProgressMode,
+
+    /// Log more detail (-v for debugging, -vv for tracing)
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+}
+
+/// Parse the command line, keeping the matches to tell given options from defaults
+pub fn parse_args() -> (Cli, ArgMatches) {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    (cli, matches)
+}
+
+pub fn parse_patch_args() -> PatchCli {
+    PatchCli::parse()
 }
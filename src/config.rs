@@ -0,0 +1,174 @@
+use crate::cli::{CreateArgs, parse_patch_version};
+use anyhow::{Context, Result, anyhow};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use diffpatch::diff::{DiffAlgorithm, HashAlgorithm};
+use diffpatch::patch::{Compression, PatchFormat};
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Config file looked for in the source directory when `--config` isn't given
+pub const CONFIG_FILE: &str = "diffpatch.toml";
+
+/// Defaults for `diffpatch create`, read from a TOML file
+///
+/// Keys are the long option names (`exclude-dirs = ["target"]`). Relative
+/// paths are resolved against the directory holding the config file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CreateConfig {
+    target: Option<PathBuf>,
+    output: Option<PathBuf>,
+    check_files: Option<Vec<String>>,
+    allow_unverified: Option<bool>,
+    exclude_extensions: Option<Vec<String>>,
+    exclude_dirs: Option<Vec<String>>,
+    exclude_glob: Option<Vec<String>>,
+    exclude_from: Option<PathBuf>,
+    include: Option<Vec<String>>,
+    use_diff_patches: Option<bool>,
+    eula_file: Option<PathBuf>,
+    #[serde(alias = "with-reverse")]
+    emit_reverse: Option<PathBuf>,
+    copy_retries: Option<u32>,
+    continue_on_error: Option<bool>,
+    cdc: Option<bool>,
+    manifest: Option<PathBuf>,
+    manifest_removals: Option<PathBuf>,
+    parallel_scan: Option<bool>,
+    fast: Option<bool>,
+    cache_file: Option<PathBuf>,
+    no_cache: Option<bool>,
+    expect_source_manifest: Option<PathBuf>,
+    source_manifest: Option<PathBuf>,
+    show_diff: Option<bool>,
+    force_remove: Option<PathBuf>,
+    syntax_diff: Option<bool>,
+    context: Option<usize>,
+    diff_algorithm: Option<DiffAlgorithm>,
+    max_diff_size: Option<u64>,
+    require_changes: Option<bool>,
+    diffs_in_zip: Option<bool>,
+    only_if_contains: Option<String>,
+    hash: Option<HashAlgorithm>,
+    follow_symlinks: Option<bool>,
+    compression: Option<Compression>,
+    compression_level: Option<i64>,
+    encrypt: Option<bool>,
+    patch_version: Option<String>,
+    requires_version: Option<String>,
+    author: Option<String>,
+    description: Option<String>,
+    temp_dir: Option<PathBuf>,
+    seed: Option<u64>,
+    include_hidden: Option<bool>,
+    format: Option<PatchFormat>,
+    stub: Option<PathBuf>,
+}
+
+impl CreateConfig {
+    /// Read the `--config` file, or `diffpatch.toml` in the source directory if there is one
+    pub fn load(args: &CreateArgs) -> Result<Option<(Self, PathBuf)>> {
+        let path = match (&args.config, &args.source) {
+            (Some(path), _) => path.clone(),
+            (None, Some(source)) => {
+                let path = source.join(CONFIG_FILE);
+                if !path.is_file() {
+                    return Ok(None);
+                }
+                path
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let config: Self = toml::from_str(&text)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        if let Some(version) = &config.patch_version {
+            parse_patch_version(version)
+                .map_err(|e| anyhow!("Invalid patch-version in {}: {}", path.display(), e))?;
+        }
+        if let Some(version) = &config.requires_version {
+            parse_patch_version(version)
+                .map_err(|e| anyhow!("Invalid requires-version in {}: {}", path.display(), e))?;
+        }
+        info!("Using create options from: {}", path.display());
+        Ok(Some((config, path)))
+    }
+
+    /// Fill in every option not given on the command line from the config
+    pub fn merge_into(self, args: &mut CreateArgs, matches: &ArgMatches, config_path: &Path) {
+        let base = config_path.parent().unwrap_or(Path::new(""));
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let resolve = |path: PathBuf| base.join(path);
+
+        macro_rules! merge {
+            ($($field:ident => $arg:ident),* $(,)?) => {
+                $(if let Some(value) = self.$field && !from_cli(stringify!($arg)) {
+                    args.$arg = value.into();
+                })*
+            };
+        }
+        macro_rules! merge_path {
+            ($($field:ident),* $(,)?) => {
+                $(if let Some(value) = self.$field && !from_cli(stringify!($field)) {
+                    args.$field = Some(resolve(value));
+                })*
+            };
+        }
+
+        merge!(
+            check_files => check_files,
+            allow_unverified => allow_unverified,
+            exclude_extensions => exclude_extensions,
+            exclude_dirs => exclude_dirs,
+            exclude_glob => exclude_glob,
+            include => include,
+            use_diff_patches => use_diff_patches,
+            copy_retries => copy_retries,
+            continue_on_error => continue_on_error,
+            cdc => cdc,
+            parallel_scan => parallel_scan,
+            fast => fast,
+            no_cache => no_cache,
+            show_diff => show_diff,
+            syntax_diff => syntax_diff,
+            context => context,
+            diff_algorithm => diff_algorithm,
+            max_diff_size => max_diff_size,
+            require_changes => require_changes,
+            diffs_in_zip => diffs_in_zip,
+            only_if_contains => only_if_contains,
+            hash => hash_algorithm,
+            follow_symlinks => follow_symlinks,
+            compression => compression,
+            compression_level => compression_level,
+            encrypt => encrypt,
+            patch_version => patch_version,
+            requires_version => requires_version,
+            author => author,
+            description => description,
+            seed => seed,
+            include_hidden => include_hidden,
+            format => format,
+        );
+        merge_path!(
+            target,
+            output,
+            exclude_from,
+            eula_file,
+            emit_reverse,
+            manifest,
+            manifest_removals,
+            expect_source_manifest,
+            source_manifest,
+            force_remove,
+            temp_dir,
+            stub,
+            cache_file,
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::target::ReadSeek;
+use anyhow::{Context, Result, anyhow};
+use fastcdc::v2020::StreamCDC;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, SeekFrom, Write};
+use std::path::Path;
+
+/// Chunk sizes used to find data shared between two versions of a binary file
+const DELTA_MIN_CHUNK: u32 = 512;
+const DELTA_AVG_CHUNK: u32 = 2 * 1024;
+const DELTA_MAX_CHUNK: u32 = 16 * 1024;
+
+/// Delta op: copy `len` bytes from `offset` of the original file
+const OP_COPY: u8 = 0;
+/// Delta op: insert the `len` bytes that follow
+const OP_INSERT: u8 = 1;
+
+/// Encode a delta that rebuilds `target` from `source`
+///
+/// Both files are split into content-defined chunks. Target chunks found in
+/// the source become copy ops, the rest are stored literally, so data that
+/// moved or was shifted by an insertion is still reused.
+pub fn compute_delta(source: &Path, target: &Path, buffer_size: usize) -> Result<Vec<u8>> {
+    let mut known: HashMap<[u8; 32], (u64, u64)> = HashMap::new();
+    for chunk in chunks(source, buffer_size)? {
+        let chunk = chunk.with_context(|| format!("Failed to read file: {}", source.display()))?;
+        known
+            .entry(Sha256::digest(&chunk.data).into())
+            .or_insert((chunk.offset, chunk.length as u64));
+    }
+
+    let mut delta = Vec::new();
+    let mut pending_copy: Option<(u64, u64)> = None;
+    let mut pending_insert: Vec<u8> = Vec::new();
+    for chunk in chunks(target, buffer_size)? {
+        let chunk = chunk.with_context(|| format!("Failed to read file: {}", target.display()))?;
+        let hash: [u8; 32] = Sha256::digest(&chunk.data).into();
+        match known.get(&hash) {
+            Some(&(offset, len)) => {
+                push_insert(&mut delta, &mut pending_insert);
+                pending_copy = match pending_copy {
+                    // Consecutive source chunks become a single copy
+                    Some((start, copied)) if start + copied == offset => {
+                        Some((start, copied + len))
+                    }
+                    Some(copy) => {
+                        push_copy(&mut delta, copy);
+                        Some((offset, len))
+                    }
+                    None => Some((offset, len)),
+                };
+            }
+            None => {
+                if let Some(copy) = pending_copy.take() {
+                    push_copy(&mut delta, copy);
+                }
+                pending_insert.extend_from_slice(&chunk.data);
+            }
+        }
+    }
+    if let Some(copy) = pending_copy {
+        push_copy(&mut delta, copy);
+    }
+    push_insert(&mut delta, &mut pending_insert);
+    Ok(delta)
+}
+
+/// Split a file into content-defined chunks while reading it
+fn chunks(path: &Path, buffer_size: usize) -> Result<StreamCDC<BufReader<File>>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let reader = BufReader::with_capacity(buffer_size, file);
+    Ok(StreamCDC::new(
+        reader,
+        DELTA_MIN_CHUNK,
+        DELTA_AVG_CHUNK,
+        DELTA_MAX_CHUNK,
+    ))
+}
+
+fn push_copy(delta: &mut Vec<u8>, (offset, len): (u64, u64)) {
+    delta.push(OP_COPY);
+    delta.extend_from_slice(&offset.to_le_bytes());
+    delta.extend_from_slice(&len.to_le_bytes());
+}
+
+fn push_insert(delta: &mut Vec<u8>, data: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    delta.push(OP_INSERT);
+    delta.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    delta.append(data);
+}
+
+/// Rebuild a file from its original version and a delta made by `compute_delta`
+pub fn apply_delta(
+    source: &mut impl ReadSeek,
+    delta: &[u8],
+    output: &Path,
+    buffer_size: usize,
+) -> Result<()> {
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create file: {}", output.display()))?;
+    let mut writer = BufWriter::with_capacity(buffer_size, file);
+
+    let mut rest = delta;
+    while let Some((&op, tail)) = rest.split_first() {
+        match op {
+            OP_COPY => {
+                let (offset, tail) = read_u64(tail)?;
+                let (len, tail) = read_u64(tail)?;
+                source.seek(SeekFrom::Start(offset))?;
+                let copied = std::io::copy(&mut (&mut *source).take(len), &mut writer)?;
+                if copied != len {
+                    return Err(anyhow!("Delta reads past the end of the original file"));
+                }
+                rest = tail;
+            }
+            OP_INSERT => {
+                let (len, tail) = read_u64(tail)?;
+                let len = usize::try_from(len).map_err(|_| anyhow!("Corrupt delta"))?;
+                if tail.len() < len {
+                    return Err(anyhow!("Corrupt delta: insert is truncated"));
+                }
+                writer.write_all(&tail[..len])?;
+                rest = &tail[len..];
+            }
+            _ => return Err(anyhow!("Corrupt delta: unknown op {}", op)),
+        }
+    }
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write file: {}", output.display()))?;
+    Ok(())
+}
+
+fn read_u64(data: &[u8]) -> Result<(u64, &[u8])> {
+    if data.len() < 8 {
+        return Err(anyhow!("Corrupt delta: truncated op"));
+    }
+    let (bytes, rest) = data.split_at(8);
+    Ok((u64::from_le_bytes(bytes.try_into().unwrap()), rest))
+}
@@ -1,31 +1,131 @@
-use crate::utils::get_io_thread_count;
-use anyhow::{Context, Result};
-use log::info;
+use crate::backup::{BACKUP_DIR, RESUME_FILE, ROLLBACK_DIR, VERSION_FILE};
+use crate::cache::HashCache;
+use crate::delta::compute_delta;
+use crate::merge::apply_line_changes;
+use crate::options::CreateOptions;
+use crate::tokenize::{apply_token_changes, is_c_like, tokenize};
+use crate::transform::{LineStyle, file_looks_binary};
+use crate::utils::{
+    DEFAULT_IO_BUFFER_SIZE, build_io_pool, collected_failures, file_mode, get_io_thread_count,
+    io_buffer_size, is_glob_pattern, progress_bar,
+};
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use console::Style;
+use fastcdc::v2020::StreamCDC;
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use rayon::ThreadPool;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use similar::TextDiff;
-use std::collections::HashMap;
+use similar::{Algorithm, DiffTag, TextDiff, capture_diff_slices};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+/// Content-defined chunking parameters (bytes)
+const CDC_MIN_CHUNK: u32 = 2 * 1024;
+const CDC_AVG_CHUNK: u32 = 8 * 1024;
+const CDC_MAX_CHUNK: u32 = 64 * 1024;
+
+/// Minimum fraction of shared chunks for files to be reported as similar
+const CDC_SIMILARITY_THRESHOLD: f64 = 0.5;
+
+/// Files at least this large are copied in verified, resumable blocks when applied
+pub const RESUMABLE_COPY_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// Size of the blocks large files are verified and copied in
+pub const COPY_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Unchanged text (bytes) below which neighbouring token changes are merged
+const TOKEN_MERGE_GAP: usize = 48;
+
 /// File information structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub relative_path: PathBuf,
     pub hash: String,
     pub size: u64,
+    /// Content-defined chunk hashes, only recorded with --cdc
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// Hashes of consecutive `COPY_BLOCK_SIZE` blocks, recorded for large files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub block_hashes: Vec<String>,
+    /// Where the entry points if it is a symbolic link; `hash` covers this path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+    /// Unix permission bits, restored when the file is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// File with identical content that is stored in the patch in place of this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub same_as: Option<PathBuf>,
+    /// Hash of the file this one replaces, recorded for modified files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_hash: Option<String>,
+}
+
+impl FileInfo {
+    /// Rewrite the path, link target and duplicate source, e.g. to `/` separators
+    pub fn map_paths(&mut self, map: fn(&Path) -> PathBuf) {
+        self.relative_path = map(&self.relative_path);
+        for path in self.link_target.iter_mut().chain(self.same_as.iter_mut()) {
+            *path = map(path);
+        }
+    }
 }
 
 /// File difference types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DiffType {
-    Added(FileInfo),        // Added file
-    Modified(FileInfo),     // Modified file with full content
-    ModifiedDiff(FileDiff), // Modified file with only the differences
-    Removed(PathBuf),       // Removed file
+    Added(FileInfo),          // Added file
+    Modified(FileInfo),       // Modified file with full content
+    ModifiedDiff(FileDiff),   // Modified file with only the differences
+    BinaryDelta(BinaryDelta), // Modified binary file stored as a delta
+    Symlink(Symlink),         // Added or changed symbolic link
+    Renamed(Rename),          // File moved to a new path with unchanged content
+    Removed(PathBuf),         // Removed file
+    TypeChanged(TypeChange),  // Path changed between file and directory
+}
+
+/// Kind of filesystem entry at a path
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A path whose entry type differs between source and target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypeChange {
+    pub relative_path: PathBuf,
+    pub new_kind: EntryKind, // entry type in the target directory
+    /// Files of a directory replaced by a file, the only entries removed with it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<PathBuf>,
+}
+
+/// A symbolic link to create, replacing whatever is at its path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Symlink {
+    pub relative_path: PathBuf,
+    pub target: PathBuf, // link target, as stored in the link
+}
+
+/// A removed file whose content reappears unchanged at an added path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub hash: String, // hash of the moved file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>, // Unix permission bits at the new path
 }
 
 /// Structure to hold file differences
@@ -35,6 +135,36 @@ pub struct FileDiff {
     pub hash: String,             // hash of target file
     pub original_hash: String,    // hash of source file
     pub changes: Vec<DiffChange>, // changes to apply
+    #[serde(default)]
+    pub tokenized: bool, // ranges count source tokens instead of lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_style: Option<LineStyle>, // line endings of the target file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>, // Unix permission bits of the target file
+}
+
+impl FileDiff {
+    /// Line endings to write the patched file with
+    ///
+    /// Patches from older versions don't record them, so the style of the
+    /// content being patched is kept instead.
+    pub fn line_style_for(&self, content: &str) -> LineStyle {
+        self.line_style
+            .unwrap_or_else(|| LineStyle::detect(content))
+    }
+}
+
+/// A modified binary file shipped as a delta against its original version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BinaryDelta {
+    pub relative_path: PathBuf,
+    pub hash: String,          // hash of target file
+    pub original_hash: String, // hash of source file
+    pub size: u64,             // size of target file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>, // Unix permission bits of the target file
+    #[serde(skip)]
+    pub delta: Vec<u8>, // encoded delta, stored as a zip entry
 }
 
 /// Structure to represent a single change in a file
@@ -44,6 +174,10 @@ pub struct DiffChange {
     pub content: String,
     pub old_range: Option<(usize, usize)>, // start line, length
     pub new_range: Option<(usize, usize)>, // start line, length
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_content: Option<String>, // replaced lines, used for three-way merges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>, // unchanged lines before the change, for merges
 }
 
 /// Tags to represent different types of changes
@@ -55,65 +189,318 @@ pub enum DiffChangeTag {
     Replace,
 }
 
-/// Calculate SHA256 hash of a file with buffered reading
-pub fn calculate_file_hash(path: &Path) -> Result<String> {
+/// Algorithm used to find the changed lines (or tokens) of modified text files
+///
+/// Only affects how diffs are generated; any of them applies the same way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    /// Anchors on unique lines, often giving more readable hunks for source code
+    Patience,
+    Lcs,
+}
+
+impl DiffAlgorithm {
+    fn similar(self) -> Algorithm {
+        match self {
+            DiffAlgorithm::Myers => Algorithm::Myers,
+            DiffAlgorithm::Patience => Algorithm::Patience,
+            DiffAlgorithm::Lcs => Algorithm::Lcs,
+        }
+    }
+}
+
+/// Algorithm used for the whole-file hashes recorded in a patch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+/// Incremental hasher for either algorithm
+enum FileHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+            FileHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            FileHasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            FileHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Calculate the hash of a file with buffered reading
+pub fn calculate_file_hash(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    calculate_file_hash_buffered(path, algorithm, DEFAULT_IO_BUFFER_SIZE)
+}
+
+/// Calculate the hash of a file, reading it through a buffer of `buffer_size` bytes
+pub fn calculate_file_hash_buffered(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> Result<String> {
     let file = fs::File::open(path)
         .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
 
     // Use a buffered reader for better I/O performance
-    let mut reader = BufReader::with_capacity(65536, file); // 64KB buffer
+    let mut reader = BufReader::with_capacity(buffer_size, file);
 
-    let mut hasher = Sha256::new();
+    let mut hasher = FileHasher::new(algorithm);
     std::io::copy(&mut reader, &mut hasher)
         .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
 
-    let hash = hasher.finalize();
-    Ok(format!("{:x}", hash))
+    Ok(hasher.finalize())
 }
 
-/// Check if a file should be excluded based on exclude patterns
-fn should_exclude(
+/// Calculate the hash of everything a reader yields
+pub fn calculate_reader_hash(
+    reader: &mut impl Read,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<String> {
+    let mut hasher = FileHasher::new(algorithm);
+    std::io::copy(reader, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Hash the target path of a symbolic link, so changed links compare as changed
+fn hash_link_target(link_target: &Path, algorithm: HashAlgorithm) -> String {
+    let mut hasher = FileHasher::new(algorithm);
+    hasher.update(link_target.as_os_str().as_encoded_bytes());
+    hasher.finalize()
+}
+
+/// Calculate the hash of a file together with its content-defined chunk hashes
+///
+/// The file is read once; chunks are fed to the whole-file hasher in order.
+/// Chunk hashes are always SHA256, they are only compared within one scan.
+pub fn calculate_file_hash_and_chunks(
     path: &Path,
-    exclude_extensions: Option<&[String]>,
-    exclude_dirs: Option<&[String]>,
-) -> bool {
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> Result<(String, Vec<String>)> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+    let reader = BufReader::with_capacity(buffer_size, file);
+
+    let mut hasher = FileHasher::new(algorithm);
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(reader, CDC_MIN_CHUNK, CDC_AVG_CHUNK, CDC_MAX_CHUNK) {
+        let chunk = chunk
+            .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+        hasher.update(&chunk.data);
+        chunks.push(format!("{:x}", Sha256::digest(&chunk.data)));
+    }
+
+    Ok((hasher.finalize(), chunks))
+}
+
+/// SHA256 of a single copy block
+pub fn hash_block(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Fill `buf` from a reader, returning fewer bytes only at end of input
+pub fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Hash a file in consecutive `COPY_BLOCK_SIZE` blocks
+pub fn calculate_block_hashes(path: &Path, buffer_size: usize) -> Result<Vec<String>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open file for hashing: {}", path.display()))?;
+    let mut reader = BufReader::with_capacity(buffer_size, file);
+
+    let mut buf = vec![0u8; COPY_BLOCK_SIZE];
+    let mut hashes = Vec::new();
+    loop {
+        let n = read_block(&mut reader, &mut buf)
+            .with_context(|| format!("Failed to read file for hashing: {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hashes.push(hash_block(&buf[..n]));
+    }
+    Ok(hashes)
+}
+
+/// Fraction of the chunks of `a` that also appear in `b`
+pub fn chunk_similarity(a: &FileInfo, b: &FileInfo) -> f64 {
+    if a.chunks.is_empty() {
+        return 0.0;
+    }
+    let b_chunks: HashSet<&String> = b.chunks.iter().collect();
+    let shared = a.chunks.iter().filter(|c| b_chunks.contains(c)).count();
+    shared as f64 / a.chunks.len() as f64
+}
+
+/// For each added file, find the source file sharing the most chunks with it
+pub fn find_chunk_similar_files(
+    source_files: &HashMap<PathBuf, FileInfo>,
+    added_files: &[&FileInfo],
+    threshold: f64,
+) -> Vec<(PathBuf, PathBuf, f64)> {
+    // Index source files by chunk so only candidates sharing a chunk are compared
+    let mut by_chunk: HashMap<&String, Vec<&FileInfo>> = HashMap::new();
+    for info in source_files.values() {
+        for chunk in &info.chunks {
+            by_chunk.entry(chunk).or_default().push(info);
+        }
+    }
+
+    let mut similar = Vec::new();
+    for added in added_files {
+        let candidates: HashSet<&PathBuf> = added
+            .chunks
+            .iter()
+            .filter_map(|c| by_chunk.get(c))
+            .flatten()
+            .map(|info| &info.relative_path)
+            .collect();
+
+        let best = candidates
+            .into_iter()
+            .map(|path| (path, chunk_similarity(added, &source_files[path])))
+            .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.cmp(a.0)));
+
+        if let Some((path, score)) = best
+            && score >= threshold
+        {
+            similar.push((added.relative_path.clone(), path.clone(), score));
+        }
+    }
+    similar
+}
+
+/// Check if a file should be excluded based on exclude patterns
+fn should_exclude(path: &Path, options: &CreateOptions) -> bool {
     // Check if path has an excluded extension
-    if let Some(extensions) = exclude_extensions
-        && let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-            let dot_ext = format!(".{}", ext);
-            if extensions.iter().any(|e| e == &dot_ext || e == ext) {
-                return true;
-            }
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        let dot_ext = format!(".{}", ext);
+        if options
+            .exclude_extensions
+            .iter()
+            .any(|e| e == &dot_ext || e == ext)
+        {
+            return true;
         }
+    }
+
+    // Check gitignore-style patterns against the whole relative path
+    let normalized = path.to_string_lossy().replace('\\', "/");
+    if let Some(globs) = &options.exclude_globs
+        && globs.is_match(&normalized)
+    {
+        return true;
+    }
+
+    // With an allowlist, everything it doesn't match is left out
+    if let Some(globs) = &options.include_globs
+        && !globs.is_match(&normalized)
+    {
+        return true;
+    }
 
     // Check if the path is within an excluded directory
-    if let Some(dirs) = exclude_dirs {
+    let dirs = &options.exclude_dirs;
+    if !dirs.is_empty() {
         let mut path_ancestors = path.ancestors();
         // Skip the first ancestor, which is the path itself
         path_ancestors.next();
 
         for ancestor in path_ancestors {
             if let Some(dir_name) = ancestor.file_name().and_then(|n| n.to_str())
-                && dirs.iter().any(|excluded_dir| excluded_dir == dir_name) {
-                    return true;
-                }
+                && dirs.iter().any(|excluded_dir| excluded_dir == dir_name)
+            {
+                return true;
+            }
         }
     }
 
     false
 }
 
-/// Scan directory and collect file information
+/// Scan directory and collect file information, hashing on the given thread pool
 pub fn scan_directory(
+    pool: &ThreadPool,
     dir_path: &Path,
-    exclude_extensions: Option<&[String]>,
-    exclude_dirs: Option<&[String]>,
+    options: &CreateOptions,
 ) -> Result<HashMap<PathBuf, FileInfo>> {
-    // Collect all valid files first
+    let files = walk_directory(dir_path, options)?;
+    let pb = progress_bar(files.len() as u64, "Hashing files...");
+    let scanned = hash_files(pool, dir_path, files, options, None, None, &pb);
+    pb.finish();
+    scanned
+}
+
+/// Relative paths of the files and links in a directory that are not excluded
+///
+/// Entries that can't be read, and files whose names aren't valid UTF-8, fail
+/// the walk unless `continue_on_error` is set, since they would otherwise look
+/// removed.
+fn walk_directory(dir_path: &Path, options: &CreateOptions) -> Result<Vec<PathBuf>> {
+    let mut hidden_files = 0;
+    let mut errors = Vec::new();
     let files_to_process: Vec<_> = WalkDir::new(dir_path)
+        .follow_links(options.follow_symlinks)
         .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                // Entries that would be left out anyway don't matter
+                let path = e.path().unwrap_or(dir_path);
+                let relative_path = path.strip_prefix(dir_path).unwrap_or(Path::new(""));
+                if !is_diffpatch_entry(relative_path)
+                    && (options.include_hidden || !is_hidden(relative_path))
+                    && !should_exclude(relative_path, options)
+                {
+                    errors.push(format!("{}: {}", path.display(), e));
+                }
+                None
+            }
+        })
+        .filter(|e| e.file_type().is_file() || e.file_type().is_symlink())
         .filter(|e| {
             let full_path = e.path();
             let relative_path = full_path
@@ -121,78 +508,275 @@ pub fn scan_directory(
                 .unwrap_or_else(|_| Path::new(""))
                 .to_path_buf();
 
+            // diffpatch's own backups and state are never part of a patch
+            if is_diffpatch_entry(&relative_path) {
+                return false;
+            }
+
             // Skip hidden files and directories
-            if relative_path.components().any(|c| {
-                if let Some(s) = c.as_os_str().to_str() {
-                    s.starts_with('.')
-                } else {
-                    false
-                }
-            }) {
+            if !options.include_hidden && is_hidden(&relative_path) {
+                hidden_files += 1;
                 return false;
             }
 
             // Skip files based on exclude patterns
-            !should_exclude(&relative_path, exclude_extensions, exclude_dirs)
+            !should_exclude(&relative_path, options)
         })
+        .filter_map(|e| e.path().strip_prefix(dir_path).ok().map(Path::to_path_buf))
         .collect();
 
-    // Create a thread pool with limited threads to avoid I/O contention
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(get_io_thread_count())
-        .build()
-        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
+    // Patch data is JSON, which can't hold other names
+    let (files_to_process, non_utf8): (Vec<_>, Vec<_>) = files_to_process
+        .into_iter()
+        .partition(|path| path.to_str().is_some());
+    errors.extend(
+        non_utf8
+            .iter()
+            .map(|path| format!("{}: name is not valid UTF-8", dir_path.join(path).display())),
+    );
+    if hidden_files > 0 {
+        warn!(
+            "Skipped {} hidden files in {}; use --include-hidden to include them",
+            hidden_files,
+            dir_path.display()
+        );
+    }
+    collected_failures("list", errors, options.continue_on_error)?;
+    Ok(files_to_process)
+}
+
+/// Whether any component of a relative path starts with a dot
+fn is_hidden(relative_path: &Path) -> bool {
+    relative_path
+        .components()
+        .any(|c| c.as_os_str().as_encoded_bytes().starts_with(b"."))
+}
+
+/// Whether a relative path is a backup or state file written by `diffpatch` itself
+pub(crate) fn is_diffpatch_entry(relative_path: &Path) -> bool {
+    relative_path.starts_with(BACKUP_DIR)
+        || relative_path.starts_with(ROLLBACK_DIR)
+        || relative_path == Path::new(RESUME_FILE)
+        || relative_path == Path::new(VERSION_FILE)
+}
+
+/// Collect file information for an explicit list of relative paths
+///
+/// Listed paths that don't exist in the directory are left out of the result.
+pub fn scan_listed_files(
+    pool: &ThreadPool,
+    dir_path: &Path,
+    paths: &[PathBuf],
+    options: &CreateOptions,
+) -> Result<HashMap<PathBuf, FileInfo>> {
+    let files = existing_files(dir_path, paths);
+    let pb = progress_bar(files.len() as u64, "Hashing files...");
+    let scanned = hash_files(pool, dir_path, files, options, None, None, &pb);
+    pb.finish();
+    scanned
+}
+
+/// The listed paths that are a file or link in the directory
+fn existing_files(dir_path: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter(|path| {
+            fs::symlink_metadata(dir_path.join(path)).is_ok_and(|m| m.is_file() || m.is_symlink())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Hash files in parallel and collect their information
+///
+/// With `known`, a directory and its scanned files, a file with the same size
+/// and modification time as the file at the same path there takes its hash
+/// instead of being read. Hashes found in `cache` are used the same way.
+fn hash_files(
+    pool: &ThreadPool,
+    dir_path: &Path,
+    files_to_process: Vec<PathBuf>,
+    options: &CreateOptions,
+    known: Option<(&Path, &FileMap)>,
+    cache: Option<&HashCache>,
+    pb: &ProgressBar,
+) -> Result<HashMap<PathBuf, FileInfo>> {
+    let buffer_size = io_buffer_size(options.io_buffer_size);
 
     // Process files in parallel with the custom thread pool
     let results = pool.install(|| {
         files_to_process
-            .par_iter()
-            .map(|entry| {
-                let full_path = entry.path();
-                let relative_path = match full_path.strip_prefix(dir_path) {
-                    Ok(path) => path.to_path_buf(),
-                    Err(_) => return None,
-                };
+            .into_par_iter()
+            .map(|relative_path| -> std::result::Result<_, String> {
+                let full_path = &dir_path.join(&relative_path);
+                let failed = |e: &dyn fmt::Display| format!("{}: {:#}", full_path.display(), e);
+
+                // Links are recorded as links unless they should be followed
+                if !options.follow_symlinks
+                    && fs::symlink_metadata(full_path).is_ok_and(|m| m.is_symlink())
+                {
+                    let link_target = fs::read_link(full_path).map_err(|e| failed(&e))?;
+                    return Ok((
+                        relative_path.clone(),
+                        FileInfo {
+                            relative_path,
+                            hash: hash_link_target(&link_target, options.hash_algorithm),
+                            size: 0,
+                            chunks: Vec::new(),
+                            block_hashes: Vec::new(),
+                            link_target: Some(link_target),
+                            mode: None,
+                            same_as: None,
+                            original_hash: None,
+                        },
+                    ));
+                }
 
                 // Get metadata
-                let metadata = match fs::metadata(full_path) {
-                    Ok(meta) => meta,
-                    Err(_) => return None,
-                };
+                let metadata = fs::metadata(full_path).map_err(|e| failed(&e))?;
 
-                // Calculate hash
-                let hash = match calculate_file_hash(full_path) {
-                    Ok(h) => h,
-                    Err(_) => return None,
+                // Calculate hash, and chunk fingerprints if requested
+                let unchanged = known.and_then(|(known_dir, known_files)| {
+                    unchanged_file(known_dir, known_files, &relative_path, &metadata)
+                });
+                let (hash, chunks) = if let Some(info) = unchanged {
+                    (info.hash.clone(), info.chunks.clone())
+                } else if options.cdc {
+                    calculate_file_hash_and_chunks(full_path, options.hash_algorithm, buffer_size)
+                        .map_err(|e| failed(&e))?
+                } else if let Some(hash) =
+                    cache.and_then(|c| c.get(full_path, &metadata, options.hash_algorithm))
+                {
+                    (hash, Vec::new())
+                } else {
+                    let hash = calculate_file_hash_buffered(
+                        full_path,
+                        options.hash_algorithm,
+                        buffer_size,
+                    )
+                    .map_err(|e| failed(&e))?;
+                    if let Some(cache) = cache {
+                        cache.insert(full_path, &metadata, options.hash_algorithm, &hash);
+                    }
+                    (hash, Vec::new())
                 };
 
-                Some((
+                Ok((
                     relative_path.clone(),
                     FileInfo {
                         relative_path,
                         hash,
                         size: metadata.len(),
+                        chunks,
+                        block_hashes: Vec::new(),
+                        link_target: None,
+                        mode: file_mode(&metadata),
+                        same_as: None,
+                        original_hash: None,
                     },
                 ))
             })
+            .inspect(|_| pb.inc(1))
             .collect::<Vec<_>>()
     });
 
     // Add results to HashMap
     let mut files_map = HashMap::with_capacity(results.len());
-    for result in results.into_iter().flatten() {
-        files_map.insert(result.0, result.1);
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok((path, info)) => {
+                files_map.insert(path, info);
+            }
+            Err(e) => errors.push(e),
+        }
     }
+    collected_failures("hash", errors, options.continue_on_error)?;
 
     Ok(files_map)
 }
 
+/// Paths listed on both sides whose regular files have the same size and modification time
+fn same_size_and_mtime(
+    source_dir: &Path,
+    target_dir: &Path,
+    source_files: &[PathBuf],
+    target_files: &[PathBuf],
+) -> HashSet<PathBuf> {
+    let target_files: HashSet<&PathBuf> = target_files.iter().collect();
+    let stat = |path: &Path| {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        metadata
+            .is_file()
+            .then_some((metadata.len(), metadata.modified().ok()?))
+    };
+    source_files
+        .par_iter()
+        .filter(|path| target_files.contains(path))
+        .filter(|path| {
+            let source = stat(&source_dir.join(path));
+            source.is_some() && source == stat(&target_dir.join(path))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Information about a file `--fast` took as unchanged, without reading it
+///
+/// Its hash is the same placeholder on both sides, so it compares as unchanged
+/// and never ends up in a patch.
+fn unhashed_file(dir: &Path, relative_path: PathBuf) -> Result<FileInfo> {
+    let full_path = dir.join(&relative_path);
+    let metadata = fs::metadata(&full_path)
+        .with_context(|| format!("Failed to read metadata: {}", full_path.display()))?;
+    Ok(FileInfo {
+        relative_path,
+        hash: String::new(),
+        size: metadata.len(),
+        chunks: Vec::new(),
+        block_hashes: Vec::new(),
+        link_target: None,
+        mode: file_mode(&metadata),
+        same_as: None,
+        original_hash: None,
+    })
+}
+
+/// The scanned file at the same path if it has the same size and modification time
+fn unchanged_file<'a>(
+    known_dir: &Path,
+    known_files: &'a FileMap,
+    relative_path: &Path,
+    metadata: &fs::Metadata,
+) -> Option<&'a FileInfo> {
+    let info = known_files.get(relative_path)?;
+    if info.link_target.is_some() {
+        return None;
+    }
+    let known_metadata = fs::metadata(known_dir.join(relative_path)).ok()?;
+    let same = known_metadata.len() == metadata.len()
+        && known_metadata.modified().ok()? == metadata.modified().ok()?;
+    same.then_some(info)
+}
+
 /// Calculate file differences between two files
+///
+/// The hashes come from the caller's directory scan, so each file is only
+/// read once more here. Each line change records up to `context` unchanged
+/// lines preceding it, which `--three-way` uses to find where it belongs.
 pub fn calculate_file_diff(
     source_path: &Path,
     target_path: &Path,
-    relative_path: &Path,
+    source_info: &FileInfo,
+    target_info: &FileInfo,
+    syntax_aware: bool,
+    context: usize,
+    algorithm: DiffAlgorithm,
 ) -> Result<FileDiff> {
+    let relative_path = &target_info.relative_path;
+    let source_hash = source_info.hash.clone();
+    let target_hash = target_info.hash.clone();
+
     // Read source file content
     let mut source_content = String::new();
     let mut source_file = fs::File::open(source_path).with_context(|| {
@@ -227,127 +811,934 @@ pub fn calculate_file_diff(
             )
         })?;
 
-    // Calculate hashes
-    let source_hash = calculate_file_hash(source_path)?;
-    let target_hash = calculate_file_hash(target_path)?;
+    // Source code is diffed token by token so reindented blocks yield small hunks
+    if syntax_aware && is_c_like(relative_path) {
+        let changes = calculate_token_changes(&source_content, &target_content, algorithm);
+        if apply_token_changes(&source_content, &changes)? != target_content {
+            return Err(anyhow!(
+                "Token diff of {} does not reproduce the target file",
+                relative_path.display()
+            ));
+        }
+        return Ok(FileDiff {
+            relative_path: relative_path.to_path_buf(),
+            hash: target_hash,
+            original_hash: source_hash,
+            changes,
+            tokenized: true,
+            line_style: None,
+            mode: target_info.mode,
+        });
+    }
 
-    // Calculate diff
-    let diff = TextDiff::from_lines(&source_content, &target_content);
+    // Diff the lines exactly as the applier splits them, so the stored line
+    // numbers match (`similar`'s own line splitting also breaks at a lone `\r`)
+    let source_lines: Vec<&str> = source_content.lines().collect();
+    let target_lines: Vec<&str> = target_content.lines().collect();
 
+    // Every changed op is stored with absolute line ranges, and unchanged lines
+    // between nearby changes are never stored as changes, so whether ops would be
+    // grouped into hunks makes no difference to the back-to-front application
     let mut changes = Vec::new();
+    for op in capture_diff_slices(algorithm.similar(), &source_lines, &target_lines) {
+        // Unchanged context lines need no change
+        if op.tag() == DiffTag::Equal {
+            continue;
+        }
 
-    for group in diff.grouped_ops(3).iter() {
-        for op in group {
-            // Use the operations directly instead of iter_inline_changes
-            let (old_start, old_len) = (op.old_range().start, op.old_range().len());
-            let (new_start, new_len) = (op.new_range().start, op.new_range().len());
-
-            // Get old and new slices
-            let old_lines: Vec<&str> = source_content
-                .lines()
-                .skip(old_start)
-                .take(old_len)
-                .collect();
-            let new_lines: Vec<&str> = target_content
-                .lines()
-                .skip(new_start)
-                .take(new_len)
-                .collect();
-
-            // Create changes based on operation type
-            if old_len > 0 && new_len > 0 {
-                // Replace
-                changes.push(DiffChange {
-                    tag: DiffChangeTag::Replace,
-                    content: new_lines.join("\n"),
-                    old_range: Some((old_start, old_len)),
-                    new_range: Some((new_start, new_len)),
-                });
-            } else if old_len > 0 {
-                // Delete
-                changes.push(DiffChange {
-                    tag: DiffChangeTag::Delete,
-                    content: old_lines.join("\n"),
-                    old_range: Some((old_start, old_len)),
-                    new_range: None,
-                });
-            } else if new_len > 0 {
-                // Insert
-                changes.push(DiffChange {
-                    tag: DiffChangeTag::Insert,
-                    content: new_lines.join("\n"),
-                    old_range: None,
-                    new_range: Some((new_start, new_len)),
-                });
-            }
+        let (old_start, old_len) = (op.old_range().start, op.old_range().len());
+        let (new_start, new_len) = (op.new_range().start, op.new_range().len());
+        let old_lines = &source_lines[old_start..old_start + old_len];
+        let new_lines = &target_lines[new_start..new_start + new_len];
+        let context_start = old_start.saturating_sub(context);
+        let context =
+            (context_start < old_start).then(|| source_lines[context_start..old_start].join("\n"));
+
+        // Create changes based on operation type
+        if old_len > 0 && new_len > 0 {
+            // Replace
+            changes.push(DiffChange {
+                tag: DiffChangeTag::Replace,
+                content: new_lines.join("\n"),
+                old_range: Some((old_start, old_len)),
+                new_range: Some((new_start, new_len)),
+                old_content: Some(old_lines.join("\n")),
+                context,
+            });
+        } else if old_len > 0 {
+            // Delete
+            changes.push(DiffChange {
+                tag: DiffChangeTag::Delete,
+                content: old_lines.join("\n"),
+                old_range: Some((old_start, old_len)),
+                new_range: None,
+                old_content: None,
+                context,
+            });
+        } else if new_len > 0 {
+            // Insert
+            changes.push(DiffChange {
+                tag: DiffChangeTag::Insert,
+                content: new_lines.join("\n"),
+                old_range: None,
+                new_range: Some((new_start, new_len)),
+                old_content: None,
+                context,
+            });
         }
     }
 
+    // Line endings are rewritten in one style, so a file mixing them cannot be
+    // rebuilt from a diff and has to be stored another way
+    let line_style = LineStyle::detect(&target_content);
+    if apply_line_changes(&source_content, &changes, line_style) != target_content {
+        return Err(anyhow!(
+            "Line diff of {} does not reproduce the target file",
+            relative_path.display()
+        ));
+    }
+
     // Create the file diff structure
     let file_diff = FileDiff {
         relative_path: relative_path.to_path_buf(),
         hash: target_hash,
         original_hash: source_hash,
         changes,
+        tokenized: false,
+        line_style: Some(line_style),
+        mode: target_info.mode,
     };
 
     Ok(file_diff)
 }
 
-/// Compare two directories and find file differences
-pub fn compare_directories(
+/// Diff two texts over brace/indent-aware tokens
+///
+/// Changes separated by less than `TOKEN_MERGE_GAP` bytes of unchanged text
+/// are stored as one change, which is cheaper than describing each separately.
+fn calculate_token_changes(
+    source_content: &str,
+    target_content: &str,
+    algorithm: DiffAlgorithm,
+) -> Vec<DiffChange> {
+    let old_tokens = tokenize(source_content);
+    let new_tokens = tokenize(target_content);
+
+    // Merged (old, new) token ranges that differ
+    let mut ranges: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+    for op in capture_diff_slices(algorithm.similar(), &old_tokens, &new_tokens) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            continue;
+        }
+        if let Some((last_old, last_new)) = ranges.last_mut() {
+            let gap: usize = new_tokens[last_new.end..new_range.start]
+                .iter()
+                .map(|t| t.len())
+                .sum();
+            if gap < TOKEN_MERGE_GAP {
+                last_old.end = old_range.end;
+                last_new.end = new_range.end;
+                continue;
+            }
+        }
+        ranges.push((old_range, new_range));
+    }
+
+    ranges
+        .into_iter()
+        .map(|(old_range, new_range)| {
+            let tag = match (old_range.is_empty(), new_range.is_empty()) {
+                (false, false) => DiffChangeTag::Replace,
+                (false, true) => DiffChangeTag::Delete,
+                _ => DiffChangeTag::Insert,
+            };
+            DiffChange {
+                tag,
+                content: new_tokens[new_range.clone()].concat(),
+                old_range: Some((old_range.start, old_range.len())),
+                new_range: Some((new_range.start, new_range.len())),
+                old_content: None,
+                context: None,
+            }
+        })
+        .collect()
+}
+
+type FileMap = HashMap<PathBuf, FileInfo>;
+
+/// Scan (or hash the manifest-listed files of) the source and target directories
+///
+/// With `parallel_scan` both directories are processed at the same time,
+/// sharing a single I/O thread pool.
+fn scan_both(
     source_dir: &Path,
     target_dir: &Path,
-    exclude_extensions: Option<&[String]>,
-    exclude_dirs: Option<&[String]>,
-    use_diff_patches: bool, // Add parameter to control whether to use diff patches
-) -> Result<Vec<DiffType>> {
-    info!("Scanning source directory: {}", source_dir.display());
-    let source_files = scan_directory(source_dir, exclude_extensions, exclude_dirs)?;
-
-    info!("Scanning target directory: {}", target_dir.display());
-    let target_files = scan_directory(target_dir, exclude_extensions, exclude_dirs)?;
-
-    let mut diffs = Vec::new();
-
-    // Find modified and added files
-    for (path, target_info) in &target_files {
-        match source_files.get(path) {
-            Some(source_info) => {
-                if source_info.hash != target_info.hash {
-                    if use_diff_patches {
-                        // Check if it's a text file that we can diff
-                        let source_path = source_dir.join(path);
-                        let target_path = target_dir.join(path);
-
-                        // Try to create a diff
-                        match calculate_file_diff(&source_path, &target_path, path) {
-                            Ok(file_diff) => {
-                                diffs.push(DiffType::ModifiedDiff(file_diff));
-                            }
-                            Err(_) => {
-                                // If diff fails (e.g., binary file), fall back to full file
-                                diffs.push(DiffType::Modified(target_info.clone()));
-                            }
-                        }
+    options: &CreateOptions,
+) -> Result<(FileMap, FileMap)> {
+    let threads = get_io_thread_count(source_dir, options.threads)
+        .max(get_io_thread_count(target_dir, options.threads));
+    let pool = build_io_pool(threads);
+    let cache = options.hash_cache.as_deref().map(HashCache::load);
+
+    let list = |dir: &Path, label: &str| -> Result<Vec<PathBuf>> {
+        match &options.manifest {
+            // Only the listed files are considered, no directory walking
+            Some(paths) => {
+                info!("Hashing manifest-listed files in {} directory", label);
+                Ok(existing_files(dir, paths))
+            }
+            None => {
+                info!("Scanning {} directory: {}", label, dir.display());
+                walk_directory(dir, options)
+            }
+        }
+    };
+    let hash = |dir: &Path, files: Vec<PathBuf>, known, pb: &ProgressBar| {
+        hash_files(&pool, dir, files, options, known, cache.as_ref(), pb)
+    };
+    let scan = |dir: &Path, label: &str, message, known| -> Result<FileMap> {
+        let files = list(dir, label)?;
+        let pb = progress_bar(files.len() as u64, message);
+        let scanned = hash(dir, files, known, &pb);
+        pb.finish();
+        scanned
+    };
+
+    let scanned = if let Some(manifest) = &options.source_manifest {
+        manifest.check_algorithm(options.hash_algorithm)?;
+        info!("Using source files from the manifest");
+        let source = manifest
+            .files
+            .iter()
+            .filter(|(path, _)| !is_diffpatch_entry(path) && !should_exclude(path, options))
+            .map(|(path, info)| (path.clone(), info.clone()))
+            .collect();
+        (
+            source,
+            scan(target_dir, "target", "Hashing target files...", None)?,
+        )
+    } else if options.fast && (options.cdc || options.expected_source.is_some()) {
+        // Chunks and the expected-source check need every source hash, so only
+        // target files are paired with the scanned source files
+        let source = scan(source_dir, "source", "Hashing source files...", None)?;
+        let target = scan(
+            target_dir,
+            "target",
+            "Hashing target files...",
+            Some((source_dir, &source)),
+        )?;
+        (source, target)
+    } else if options.fast {
+        // Files are paired by path and compared on size and modification time
+        // first, so neither side of a matching pair is read
+        let mut source_files = list(source_dir, "source")?;
+        let mut target_files = list(target_dir, "target")?;
+        let unchanged = pool
+            .install(|| same_size_and_mtime(source_dir, target_dir, &source_files, &target_files));
+        debug!(
+            "{} files have the same size and modification time on both sides",
+            unchanged.len()
+        );
+        source_files.retain(|path| !unchanged.contains(path));
+        target_files.retain(|path| !unchanged.contains(path));
+
+        let pb = progress_bar(source_files.len() as u64, "Hashing source files...");
+        let mut source = hash(source_dir, source_files, None, &pb)?;
+        pb.finish();
+        let pb = progress_bar(target_files.len() as u64, "Hashing target files...");
+        let mut target = hash(target_dir, target_files, None, &pb)?;
+        pb.finish();
+        for path in unchanged {
+            let info = unhashed_file(target_dir, path)?;
+            source.insert(info.relative_path.clone(), info.clone());
+            target.insert(info.relative_path.clone(), info);
+        }
+        (source, target)
+    } else if options.parallel_scan {
+        // Both directories share one progress bar while they are hashed together
+        let (source_files, target_files) = pool
+            .install(|| rayon::join(|| list(source_dir, "source"), || list(target_dir, "target")));
+        let (source_files, target_files) = (source_files?, target_files?);
+        let pb = progress_bar(
+            (source_files.len() + target_files.len()) as u64,
+            "Hashing files...",
+        );
+        let (source, target) = pool.install(|| {
+            rayon::join(
+                || hash(source_dir, source_files, None, &pb),
+                || hash(target_dir, target_files, None, &pb),
+            )
+        });
+        pb.finish();
+        (source?, target?)
+    } else {
+        let source = scan(source_dir, "source", "Hashing source files...", None)?;
+        let target = scan(target_dir, "target", "Hashing target files...", None)?;
+        (source, target)
+    };
+
+    // A cache that can't be written only costs the next run time
+    if let Some(cache) = &cache
+        && let Err(e) = cache.save()
+    {
+        warn!("{:#}", e);
+    }
+    Ok(scanned)
+}
+
+/// Render a unified diff of two file versions for display
+///
+/// Invalid UTF-8 is shown with replacement characters; this never affects
+/// the content stored in or applied from a patch.
+pub fn render_unified_diff(relative_path: &Path, old: &[u8], new: &[u8], context: usize) -> String {
+    let path = relative_path.display();
+    if old.contains(&0) || new.contains(&0) {
+        return format!("Binary files a/{} and b/{} differ\n", path, path);
+    }
+
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    TextDiff::from_lines(old_text.as_ref(), new_text.as_ref())
+        .unified_diff()
+        .context_radius(context)
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+/// Render the changes a diff applies as a unified diff, without the files
+///
+/// Hunk headers are rebuilt from the recorded ranges, and the unchanged lines
+/// stored for three-way merges are shown as context. Replacements recorded by
+/// older versions lack the replaced lines, so only their new lines are shown.
+/// Ranges of token diffs count tokens instead of lines.
+pub fn render_file_diff(file_diff: &FileDiff, color: bool) -> String {
+    let paint = |text: String, style: Style| {
+        if color {
+            style.force_styling(true).apply_to(text).to_string()
+        } else {
+            text
+        }
+    };
+    let path = file_diff.relative_path.display();
+    let mut out = paint(
+        format!("--- a/{}\n+++ b/{}", path, path),
+        Style::new().bold(),
+    ) + "\n";
+
+    // New line numbers run ahead of old ones by what earlier changes added
+    let mut offset = 0isize;
+    for change in &file_diff.changes {
+        let (removed, added) = change_text(change);
+        let removed_len = change.old_range.map_or(0, |(_, len)| len);
+        let added_len = change.new_range.map_or(0, |(_, len)| len);
+        let old_start = match (change.old_range, change.new_range) {
+            (Some((start, _)), _) => start,
+            (None, Some((start, _))) => start.saturating_add_signed(-offset),
+            (None, None) => continue,
+        };
+        let new_start = change
+            .new_range
+            .map_or(old_start.saturating_add_signed(offset), |(start, _)| start);
+        offset += added_len as isize - removed_len as isize;
+
+        let context: Vec<&str> = change
+            .context
+            .as_deref()
+            .map(|text| text.split('\n').collect())
+            .unwrap_or_default();
+        let shown = context.len().min(old_start).min(new_start);
+
+        let header = format!(
+            "@@ -{} +{} @@{}",
+            hunk_range(old_start - shown, removed_len + shown),
+            hunk_range(new_start - shown, added_len + shown),
+            if file_diff.tokenized { " tokens" } else { "" }
+        );
+        out += &paint(header, Style::new().cyan());
+        out.push('\n');
+        for line in &context[context.len() - shown..] {
+            out += &format!(" {}\n", line);
+        }
+        for line in change_lines(removed, removed_len, file_diff.tokenized) {
+            out += &paint(format!("-{}", line), Style::new().red());
+            out.push('\n');
+        }
+        for line in change_lines(added, added_len, file_diff.tokenized) {
+            out += &paint(format!("+{}", line), Style::new().green());
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Extensions of formats that are already compressed and barely shrink in the zip
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "bz2", "gz", "jpeg", "jpg", "mp3", "mp4", "ogg", "png", "rar", "webm", "webp", "xz",
+    "zip", "zst",
+];
+
+/// How much a set of changes adds up to, shown before the patch is written
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChangeStats {
+    /// Lines added by text diffs
+    pub inserted_lines: usize,
+    /// Lines removed by text diffs
+    pub deleted_lines: usize,
+    /// Bytes of the added and modified files stored in full
+    pub full_file_bytes: u64,
+    /// Rough size of the patch content once compressed
+    pub estimated_patch_bytes: u64,
+}
+
+/// Add up the size of a set of changes
+///
+/// The patch size is estimated without reading any file: full files in
+/// already compressed formats count in full, other files at half their size,
+/// and diffs and binary deltas as they are stored.
+pub fn change_stats(diffs: &[DiffType]) -> ChangeStats {
+    let mut stats = ChangeStats::default();
+    for diff in diffs {
+        match diff {
+            DiffType::Added(info) | DiffType::Modified(info) => {
+                let compressed = info
+                    .relative_path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(str::to_lowercase)
+                    .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str()));
+                stats.full_file_bytes += info.size;
+                stats.estimated_patch_bytes += if compressed { info.size } else { info.size / 2 };
+            }
+            DiffType::ModifiedDiff(file_diff) => {
+                for change in &file_diff.changes {
+                    let (removed, added) = change_text(change);
+                    if file_diff.tokenized {
+                        // Token ranges don't count lines, the changed text does
+                        stats.deleted_lines += removed.map_or(0, |text| text.lines().count());
+                        stats.inserted_lines += added.map_or(0, |text| text.lines().count());
                     } else {
-                        // Use full file mode
-                        diffs.push(DiffType::Modified(target_info.clone()));
+                        stats.deleted_lines += change.old_range.map_or(0, |(_, len)| len);
+                        stats.inserted_lines += change.new_range.map_or(0, |(_, len)| len);
                     }
+                    stats.estimated_patch_bytes += [&change.old_content, &change.context]
+                        .into_iter()
+                        .flatten()
+                        .map(|text| text.len() as u64)
+                        .sum::<u64>()
+                        + change.content.len() as u64;
                 }
             }
-            None => {
-                diffs.push(DiffType::Added(target_info.clone()));
+            DiffType::BinaryDelta(delta) => {
+                stats.estimated_patch_bytes += delta.delta.len() as u64;
             }
+            _ => {}
         }
     }
+    stats
+}
+
+/// Text a change removes and adds, if recorded
+fn change_text(change: &DiffChange) -> (Option<&str>, Option<&str>) {
+    match change.tag {
+        DiffChangeTag::Delete => (Some(&change.content), None),
+        DiffChangeTag::Insert => (None, Some(&change.content)),
+        DiffChangeTag::Replace => (change.old_content.as_deref(), Some(&change.content)),
+        DiffChangeTag::Equal => (None, None),
+    }
+}
+
+/// The `len` lines of a change's text; token text is shown as it is laid out
+fn change_lines(text: Option<&str>, len: usize, tokenized: bool) -> Vec<&str> {
+    match text {
+        Some(text) if len > 0 && tokenized => text.lines().collect(),
+        Some(text) if len > 0 => text.split('\n').collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `start,len` of a hunk header, from a zero-based start
+///
+/// Like `diff -u`, an empty range names the line before it.
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+/// Compare two directories and find file differences
+pub fn compare_directories(
+    source_dir: &Path,
+    target_dir: &Path,
+    options: &CreateOptions,
+) -> Result<Vec<DiffType>> {
+    let (source_files, target_files) = scan_both(source_dir, target_dir, options)?;
+
+    // Make sure the patch is built against the intended base
+    if let Some(expected) = &options.expected_source {
+        expected.check_algorithm(options.hash_algorithm)?;
+        let mismatches = expected.mismatches(&source_files);
+        if !mismatches.is_empty() {
+            return Err(anyhow!(
+                "Source directory does not match the expected manifest:\n  {}",
+                mismatches.join("\n  ")
+            ));
+        }
+        info!("Source directory matches the expected manifest.");
+    }
+
+    if let Some(paths) = &options.manifest {
+        let missing: Vec<_> = paths
+            .iter()
+            .filter(|p| !source_files.contains_key(*p) && !target_files.contains_key(*p))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Manifest lists files that exist in neither directory: {}",
+                missing
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+
+    // Find modified and added files, diffing modified files in parallel
+    let pool = build_io_pool(get_io_thread_count(target_dir, options.threads));
+    let classified: Vec<Option<DiffType>> = pool.install(|| {
+        target_files
+            .par_iter()
+            .map(|(path, target_info)| {
+                classify_target_file(
+                    source_dir,
+                    target_dir,
+                    source_files.get(path),
+                    target_info,
+                    options,
+                )
+            })
+            .collect::<Result<_>>()
+    })?;
+    let mut diffs: Vec<DiffType> = classified.into_iter().flatten().collect();
 
     // Find removed files
     for path in source_files.keys() {
-        if !target_files.contains_key(path) {
+        if !target_files.contains_key(path) && options.only_if_contains.is_none() {
             diffs.push(DiffType::Removed(path.clone()));
         }
     }
 
-    Ok(diffs)
+    // Removals listed alongside the manifest
+    for path in &options.manifest_removals {
+        if !target_files.contains_key(path) && !source_files.contains_key(path) {
+            diffs.push(DiffType::Removed(path.clone()));
+        }
+    }
+
+    // Leftover files to delete even though the source directory does not have them
+    for path in options.force_remove.iter().filter(|p| !is_glob_pattern(p)) {
+        if target_files.contains_key(path) {
+            warn!(
+                "Not force-removing {}: the file exists in the target directory",
+                path.display()
+            );
+        } else if !source_files.contains_key(path) {
+            diffs.push(DiffType::Removed(path.clone()));
+        }
+    }
+
+    // Report added files that are near-duplicates of existing source files
+    if options.cdc {
+        let added: Vec<&FileInfo> = diffs
+            .iter()
+            .filter_map(|d| match d {
+                DiffType::Added(info) => Some(info),
+                _ => None,
+            })
+            .collect();
+        for (added_path, source_path, score) in
+            find_chunk_similar_files(&source_files, &added, CDC_SIMILARITY_THRESHOLD)
+        {
+            info!(
+                "Added file {} shares {:.0}% of its chunks with {}",
+                added_path.display(),
+                score * 100.0,
+                source_path.display()
+            );
+        }
+    }
+
+    // Detect paths that switched between file and directory
+    let mut type_changes = if options.only_if_contains.is_some() {
+        Vec::new()
+    } else {
+        detect_type_changes(&source_files, &target_files)
+    };
+    if !type_changes.is_empty() {
+        // The old entry is removed as part of the type change, so the individual
+        // removals at or below those paths move into it
+        diffs.retain(|d| match d {
+            DiffType::Removed(path) => {
+                match type_changes
+                    .iter_mut()
+                    .find(|tc| path.starts_with(&tc.relative_path))
+                {
+                    Some(change) => {
+                        change.removed.push(path.clone());
+                        false
+                    }
+                    None => true,
+                }
+            }
+            _ => true,
+        });
+        for change in &mut type_changes {
+            change.removed.sort();
+        }
+        diffs.extend(type_changes.into_iter().map(DiffType::TypeChanged));
+    }
+
+    Ok(detect_renames(diffs, &source_files))
+}
+
+/// Collapse removed and added files with identical content into renames
+///
+/// Only one-to-one matches are collapsed; a hash shared by several added or
+/// removed files (empty files, duplicates) leaves those files as they are.
+fn detect_renames(mut diffs: Vec<DiffType>, source_files: &FileMap) -> Vec<DiffType> {
+    let mut by_hash: HashMap<&str, (Vec<&Path>, Vec<&FileInfo>)> = HashMap::new();
+    for diff in &diffs {
+        match diff {
+            DiffType::Removed(path) => {
+                if let Some(info) = source_files.get(path)
+                    && info.link_target.is_none()
+                {
+                    by_hash.entry(&info.hash).or_default().0.push(path);
+                }
+            }
+            DiffType::Added(info) => by_hash.entry(&info.hash).or_default().1.push(info),
+            _ => {}
+        }
+    }
+
+    let renames: Vec<Rename> = by_hash
+        .into_values()
+        .filter_map(
+            |(removed, added)| match (removed.as_slice(), added.as_slice()) {
+                ([from], [to]) => Some(Rename {
+                    from: from.to_path_buf(),
+                    to: to.relative_path.clone(),
+                    hash: to.hash.clone(),
+                    mode: to.mode,
+                }),
+                _ => None,
+            },
+        )
+        .collect();
+    if renames.is_empty() {
+        return diffs;
+    }
+
+    let moved: HashSet<&Path> = renames
+        .iter()
+        .flat_map(|r| [r.from.as_path(), r.to.as_path()])
+        .collect();
+    diffs.retain(|d| match d {
+        DiffType::Removed(path) => !moved.contains(path.as_path()),
+        DiffType::Added(info) => !moved.contains(info.relative_path.as_path()),
+        _ => true,
+    });
+    diffs.extend(renames.into_iter().map(DiffType::Renamed));
+    diffs
+}
+
+/// Decide how a file in the target directory goes into the patch
+///
+/// Returns `None` for unchanged files and files left out of a targeted patch.
+fn classify_target_file(
+    source_dir: &Path,
+    target_dir: &Path,
+    source_info: Option<&FileInfo>,
+    target_info: &FileInfo,
+    options: &CreateOptions,
+) -> Result<Option<DiffType>> {
+    let path = &target_info.relative_path;
+    let Some(source_info) = source_info else {
+        if options.only_if_contains.is_some() {
+            return Ok(None);
+        }
+        return Ok(Some(match symlink_of(target_info) {
+            Some(link) => DiffType::Symlink(link),
+            None => DiffType::Added(target_info.clone()),
+        }));
+    };
+    if source_info.hash == target_info.hash && source_info.link_target == target_info.link_target {
+        return Ok(None);
+    }
+
+    // Links carry no content, they are recreated pointing at the new target
+    if let Some(link) = symlink_of(target_info) {
+        if options.only_if_contains.is_some() {
+            return Ok(None);
+        }
+        return Ok(Some(DiffType::Symlink(link)));
+    }
+
+    // Targeted patches skip files that do not carry the signature
+    if let Some(pattern) = &options.only_if_contains
+        && !source_contains(&source_dir.join(path), pattern)?
+    {
+        return Ok(None);
+    }
+
+    // Full files remember what they replace, so local edits can be noticed when applying
+    let full_file = || {
+        DiffType::Modified(FileInfo {
+            original_hash: source_info
+                .link_target
+                .is_none()
+                .then(|| source_info.hash.clone()),
+            ..target_info.clone()
+        })
+    };
+
+    // A file replacing a link, or one only known from a manifest, has no original content
+    // to diff against, and huge files cost more to diff than they save
+    let too_large = options
+        .max_diff_size
+        .is_some_and(|max| source_info.size.max(target_info.size) > max);
+    if !options.use_diff_patches
+        || source_info.link_target.is_some()
+        || options.source_manifest.is_some()
+        || too_large
+    {
+        // Use full file mode
+        return Ok(Some(full_file()));
+    }
+
+    // Try to create a diff, unless a quick look shows binary content
+    let source_path = source_dir.join(path);
+    let target_path = target_dir.join(path);
+    let looks_binary = |path: &Path| {
+        file_looks_binary(path).with_context(|| format!("Failed to read {}", path.display()))
+    };
+    let binary = looks_binary(&source_path)? || looks_binary(&target_path)?;
+    if !binary
+        && let Ok(file_diff) = calculate_file_diff(
+            &source_path,
+            &target_path,
+            source_info,
+            target_info,
+            options.syntax_diff,
+            options.context_lines,
+            options.diff_algorithm,
+        )
+    {
+        // A rewritten file can need a diff larger than the file itself
+        let diff_size = serde_json::to_vec(&file_diff).map_or(u64::MAX, |d| d.len() as u64);
+        if diff_size >= target_info.size {
+            debug!(
+                "Diff of {} is larger than the file, storing it whole",
+                path.display()
+            );
+            return Ok(Some(full_file()));
+        }
+        return Ok(Some(DiffType::ModifiedDiff(file_diff)));
+    }
+
+    // Binary files are stored as a delta, or as the full file if that is smaller
+    debug!(
+        "{} is not a text file, computing a binary delta",
+        path.display()
+    );
+    let delta = compute_delta(
+        &source_path,
+        &target_path,
+        io_buffer_size(options.io_buffer_size),
+    )?;
+    if (delta.len() as u64) < target_info.size {
+        return Ok(Some(DiffType::BinaryDelta(BinaryDelta {
+            relative_path: path.clone(),
+            hash: target_info.hash.clone(),
+            original_hash: source_info.hash.clone(),
+            size: target_info.size,
+            mode: target_info.mode,
+            delta,
+        })));
+    }
+    Ok(Some(full_file()))
+}
+
+/// The link to create for a scanned symbolic link
+fn symlink_of(info: &FileInfo) -> Option<Symlink> {
+    info.link_target.as_ref().map(|target| Symlink {
+        relative_path: info.relative_path.clone(),
+        target: target.clone(),
+    })
+}
+
+/// Check whether a file's content matches a signature pattern
+fn source_contains(path: &Path, pattern: &regex::bytes::Regex) -> Result<bool> {
+    let content =
+        fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(pattern.is_match(&content))
+}
+
+/// Find paths that are a file on one side and a directory on the other
+fn detect_type_changes(
+    source_files: &HashMap<PathBuf, FileInfo>,
+    target_files: &HashMap<PathBuf, FileInfo>,
+) -> Vec<TypeChange> {
+    let source_dirs = collect_parent_dirs(source_files);
+    let target_dirs = collect_parent_dirs(target_files);
+    let mut changes = Vec::new();
+
+    // A source file whose path is a directory in the target
+    for path in source_files.keys() {
+        if target_dirs.contains(path.as_path()) {
+            changes.push(TypeChange {
+                relative_path: path.clone(),
+                new_kind: EntryKind::Directory,
+                removed: Vec::new(),
+            });
+        }
+    }
+
+    // A target file whose path was a directory in the source
+    for path in target_files.keys() {
+        if source_dirs.contains(path.as_path()) {
+            changes.push(TypeChange {
+                relative_path: path.clone(),
+                new_kind: EntryKind::File,
+                removed: Vec::new(),
+            });
+        }
+    }
+
+    changes
+}
+
+/// Collect every directory that contains at least one scanned file
+fn collect_parent_dirs(files: &HashMap<PathBuf, FileInfo>) -> HashSet<&Path> {
+    files
+        .keys()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes that don't repeat, different for each seed
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunked_file(dir: &Path, name: &str, content: &[u8]) -> FileInfo {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        let (hash, chunks) =
+            calculate_file_hash_and_chunks(&path, HashAlgorithm::Sha256, DEFAULT_IO_BUFFER_SIZE)
+                .unwrap();
+        FileInfo {
+            relative_path: PathBuf::from(name),
+            hash,
+            size: content.len() as u64,
+            chunks,
+            block_hashes: Vec::new(),
+            link_target: None,
+            mode: None,
+            same_as: None,
+            original_hash: None,
+        }
+    }
+
+    #[test]
+    fn files_sharing_a_large_block_are_chunk_similar() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = noise(1, 512 * 1024);
+        let original = [&shared[..], &noise(2, 16 * 1024)].concat();
+        let original = chunked_file(dir.path(), "original.pak", &original);
+        let unrelated = chunked_file(dir.path(), "unrelated.pak", &noise(3, 512 * 1024));
+        // The shared block is shifted, content-defined chunks still line up
+        let added = [&noise(4, 5000)[..], &shared[..]].concat();
+        let added = chunked_file(dir.path(), "added.pak", &added);
+
+        let source_files: HashMap<PathBuf, FileInfo> = [original, unrelated]
+            .into_iter()
+            .map(|info| (info.relative_path.clone(), info))
+            .collect();
+        let similar = find_chunk_similar_files(&source_files, &[&added], CDC_SIMILARITY_THRESHOLD);
+        assert_eq!(similar.len(), 1, "{:?}", similar);
+        assert_eq!(similar[0].0, Path::new("added.pak"));
+        assert_eq!(similar[0].1, Path::new("original.pak"));
+        assert!(similar[0].2 > 0.9, "{:?}", similar);
+    }
+
+    #[test]
+    fn invalid_utf8_still_renders_a_diff() {
+        let old = b"first\nsecond\n";
+        let new = b"first\nsec\xffond\n";
+        let rendered = render_unified_diff(Path::new("notes.txt"), old, new, 3);
+        assert!(rendered.contains("-second\n"), "{}", rendered);
+        assert!(rendered.contains("+sec\u{fffd}ond\n"), "{}", rendered);
+    }
+
+    /// Line diff between two texts, or why none could be made
+    fn line_diff(old: &str, new: &str) -> Result<FileDiff> {
+        let dir = tempfile::tempdir().unwrap();
+        let old_info = chunked_file(dir.path(), "old.txt", old.as_bytes());
+        let new_info = chunked_file(dir.path(), "new.txt", new.as_bytes());
+        let (old_path, new_path) = (dir.path().join("old.txt"), dir.path().join("new.txt"));
+        let algorithm = DiffAlgorithm::default();
+        calculate_file_diff(
+            &old_path, &new_path, &old_info, &new_info, false, 3, algorithm,
+        )
+    }
+
+    #[test]
+    fn line_diff_reproduces_the_target_or_is_refused() {
+        // A lone `\r` splits a line for `similar` but not for the applier
+        let old = "one\rtwo\nthree\nfour\nfive\n";
+        let new = "one\rtwo\nthree\n4\nfive\n";
+        let file_diff = line_diff(old, new).unwrap();
+        let style = file_diff.line_style.unwrap();
+        assert_eq!(apply_line_changes(old, &file_diff.changes, style), new);
+
+        // Mixed line endings cannot be written back from lines
+        assert!(line_diff(old, "one\r\ntwo\nthree\n").is_err());
+    }
+
+    #[test]
+    fn token_diff_reproduces_the_target_and_refuses_other_content() {
+        let old = "fn main() {\n    run(1);\n}\n\nfn tail() {\n    stop();\n}\n";
+        let new = "fn main() {\n    if ok {\n        run(2);\n    }\n}\n\nfn tail() {\n}\n";
+        let dir = tempfile::tempdir().unwrap();
+        let old_info = chunked_file(dir.path(), "old.rs", old.as_bytes());
+        let new_info = chunked_file(dir.path(), "new.rs", new.as_bytes());
+        let (old_path, new_path) = (dir.path().join("old.rs"), dir.path().join("new.rs"));
+        let algorithm = DiffAlgorithm::default();
+        let file_diff = calculate_file_diff(
+            &old_path, &new_path, &old_info, &new_info, true, 3, algorithm,
+        )
+        .unwrap();
+        assert!(file_diff.tokenized);
+        assert_eq!(apply_token_changes(old, &file_diff.changes).unwrap(), new);
+
+        // Content shorter than the diff expects is an error, not a partial patch
+        assert!(apply_token_changes("fn main() {}", &file_diff.changes).is_err());
+    }
 }
@@ -0,0 +1,136 @@
+//! Compare directories and build self-applying patches
+//!
+//! The `diffpatch` command line tool is a thin wrapper over this library, so
+//! build tooling can create, inspect and apply patches without shelling out:
+//!
+//! ```no_run
+//! use diffpatch::{ApplyOptions, CreateOptions, PatchBuilder};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let options = CreateOptions::new().use_diff_patches(true);
+//! let patch = PatchBuilder::new(Path::new("v1"), Path::new("v2"))
+//!     .options(options)
+//!     .create_patch_to_path(Path::new("update.exe"))?;
+//! if let Some(patch) = patch {
+//!     patch.apply(Path::new("install"), &ApplyOptions::new())?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backup;
+pub mod cache;
+mod crypto;
+mod delta;
+pub mod diff;
+pub mod manifest;
+mod merge;
+pub mod options;
+pub mod patch;
+pub mod patch_diff;
+pub mod repair;
+pub mod report;
+mod review;
+pub mod target;
+mod tokenize;
+pub mod transform;
+pub mod utils;
+pub mod verify;
+
+pub use options::{ApplyOptions, CreateOptions};
+pub use patch::{ApplyStatus, PatchData};
+pub use target::{LocalTarget, ObjectStoreTarget, PatchTarget};
+
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A patch file, decoded without running it
+pub struct Patch {
+    path: PathBuf,
+    data: PatchData,
+}
+
+impl Patch {
+    /// Read the patch data appended to a patch file
+    pub fn open(path: &Path) -> Result<Self> {
+        let (data, _) = patch::extract_patch_data(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            data,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks and changes recorded in the patch
+    pub fn data(&self) -> &PatchData {
+        &self.data
+    }
+
+    /// Apply the patch to a directory
+    pub fn apply(&self, target_dir: &Path, options: &ApplyOptions) -> Result<ApplyStatus> {
+        patch::apply_patch_file(&self.path, target_dir, options)
+    }
+
+    /// Apply the patch to other storage, such as an `ObjectStoreTarget`
+    pub fn apply_to_target(
+        &self,
+        target: &dyn PatchTarget,
+        options: &ApplyOptions,
+    ) -> Result<ApplyStatus> {
+        patch::apply_patch_to_target(&self.path, target, options)
+    }
+}
+
+/// Builds a patch that turns a source directory into a target directory
+pub struct PatchBuilder {
+    source: PathBuf,
+    target: PathBuf,
+    options: CreateOptions,
+}
+
+impl PatchBuilder {
+    pub fn new(source: &Path, target: &Path) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            options: CreateOptions::new(),
+        }
+    }
+
+    pub fn options(mut self, options: CreateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Compare the directories and write the patch file
+    ///
+    /// Returns `None` without writing anything if the directories don't
+    /// differ. The patch runs `CreateOptions::stub`, or the running
+    /// executable if none is set, so tools other than `diffpatch` itself
+    /// should point it at a `diffpatch` binary.
+    pub fn create_patch_to_path(&self, output: &Path) -> Result<Option<Patch>> {
+        let diffs = diff::compare_directories(&self.source, &self.target, &self.options)?;
+        if diffs.is_empty() {
+            return Ok(None);
+        }
+        let path = patch::create_patch(&self.source, &self.target, output, diffs, &self.options)?;
+        Patch::open(&path).map(Some)
+    }
+}
+
+/// Apply a patch held in memory to a directory
+pub fn apply_patch_from_bytes(
+    patch_bytes: &[u8],
+    target_dir: &Path,
+    options: &ApplyOptions,
+) -> Result<ApplyStatus> {
+    let mut file = tempfile::NamedTempFile::new().context("Failed to create temporary file")?;
+    file.write_all(patch_bytes)
+        .context("Failed to write patch to temporary file")?;
+    patch::apply_patch_file(file.path(), target_dir, options)
+}
@@ -1,41 +1,183 @@
 mod cli;
-mod diff;
-mod patch;
-mod utils;
+mod config;
 
-use anyhow::{Context, Result};
-use cli::{parse_args, Commands};
-use log::{info, warn};
+use anyhow::{Context, Result, anyhow};
+use cli::{Commands, CreateArgs, parse_args, parse_patch_args};
+use config::CreateConfig;
+use diffpatch::patch::PatchFormat;
+use diffpatch::utils::{self, check_is_directory, check_path_exists};
+use diffpatch::{ApplyOptions, ApplyStatus, CreateOptions, ObjectStoreTarget};
+use diffpatch::{backup, cache, diff, manifest, patch, patch_diff, repair, verify};
+use globset::GlobSet;
+use indicatif::HumanBytes;
+use log::{LevelFilter, info, warn};
+use std::collections::BTreeMap;
 use std::env;
-use utils::{check_is_directory, check_path_exists};
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+/// Exit code of `create --require-changes` when the patch would be empty
+const EXIT_NO_CHANGES: i32 = 2;
 
 fn main() -> Result<()> {
-    // Initialize logger
-    env_logger::init();
     // Check if running in patch mode
     if is_patch_executable() {
+        let args = parse_patch_args();
+        init_logger(args.verbose, args.quiet);
         info!("Running in patch mode with parallel processing...");
-        let current_dir = env::current_dir().context("Failed to get current directory")?;
-        return patch::apply_patch(&current_dir);
+        utils::set_default_yes(args.default_yes);
+        utils::set_assume_yes(args.yes);
+        utils::set_progress_mode(args.progress);
+        let result = resolve_target_dir(args.target)
+            .and_then(|dir| {
+                let options = ApplyOptions::from(&args.options)
+                    .threads(args.threads)
+                    .io_buffer_size(args.io_buffer_size);
+                patch::apply_patch(&dir, &options)
+            })
+            .map(drop);
+
+        // A double-clicked patch gets its own console window that closes on exit,
+        // so keep it open long enough for the user to read why the patch failed
+        if let Err(err) = &result
+            && utils::is_interactive()
+        {
+            let _ = utils::show_error_and_wait(err, &mut io::stderr(), &mut io::stdin().lock());
+            std::process::exit(1);
+        }
+        return result;
     }
 
     // Parse command line arguments
-    let args = parse_args();
+    let (args, matches) = parse_args();
+    init_logger(args.verbose, args.quiet);
+    utils::set_default_yes(args.default_yes);
+    utils::set_assume_yes(args.yes);
+    utils::set_progress_mode(args.progress);
 
+    let threads = args.threads;
+    let io_buffer_size = args.io_buffer_size;
     match args.command {
-        Commands::Create {
-            source,
-            target,
-            output,
-            check_files,
-            exclude_extensions,
-            exclude_dirs,
-            use_diff_patches,
-        } => {
-            // Validate arguments
-            check_path_exists(&source, "Source directory")
-                .context("Source directory check failed")?;
-            check_is_directory(&source).context("Source directory check failed")?;
+        Commands::Create(mut args) => {
+            // The config file fills in whatever the command line leaves out
+            if let Some((config, path)) = CreateConfig::load(&args)?
+                && let Some(create_matches) = matches.subcommand_matches("create")
+            {
+                config.merge_into(&mut args, create_matches, &path);
+                // A config kept in the source directory is not part of the patch
+                if args
+                    .source
+                    .as_deref()
+                    .is_some_and(|s| path == s.join(config::CONFIG_FILE))
+                {
+                    args.exclude_glob.push(format!("/{}", config::CONFIG_FILE));
+                }
+            }
+
+            let CreateArgs {
+                source,
+                target,
+                output,
+                config: _,
+                check_files,
+                allow_unverified,
+                exclude_extensions,
+                exclude_dirs,
+                exclude_glob,
+                exclude_from,
+                include,
+                use_diff_patches,
+                eula_file,
+                emit_reverse,
+                copy_retries,
+                continue_on_error,
+                cdc,
+                manifest,
+                manifest_removals,
+                parallel_scan,
+                fast,
+                cache_file,
+                no_cache,
+                expect_source_manifest,
+                source_manifest,
+                show_diff,
+                dry_run,
+                force_remove,
+                syntax_diff,
+                context,
+                diff_algorithm,
+                max_diff_size,
+                require_changes,
+                diffs_in_zip,
+                only_if_contains,
+                hash_algorithm,
+                follow_symlinks,
+                compression,
+                compression_level,
+                encrypt,
+                patch_version,
+                requires_version,
+                author,
+                description,
+                temp_dir,
+                seed,
+                include_hidden,
+                format,
+                stub,
+            } = *args;
+
+            let target = target.ok_or_else(|| {
+                anyhow!(
+                    "--target is required, on the command line or in {}",
+                    config::CONFIG_FILE
+                )
+            })?;
+            let output = output.ok_or_else(|| {
+                anyhow!(
+                    "--output is required, on the command line or in {}",
+                    config::CONFIG_FILE
+                )
+            })?;
+
+            // A source manifest stands in for the source directory, whose files are never read
+            let source_manifest = source_manifest
+                .map(|path| manifest::DirectoryManifest::load(&path))
+                .transpose()?;
+            let source = match (source, &source_manifest) {
+                (Some(_), Some(_)) => {
+                    return Err(anyhow!(
+                        "--source and --source-manifest can't be used together"
+                    ));
+                }
+                (Some(source), None) => {
+                    check_path_exists(&source, "Source directory")
+                        .context("Source directory check failed")?;
+                    check_is_directory(&source).context("Source directory check failed")?;
+                    source
+                }
+                // Bare output names are then placed in the current directory
+                (None, Some(_)) => PathBuf::new(),
+                (None, None) => {
+                    return Err(anyhow!("--source or --source-manifest is required"));
+                }
+            };
+            if source_manifest.is_some() {
+                let needs_source = [
+                    (emit_reverse.is_some(), "--emit-reverse"),
+                    (only_if_contains.is_some(), "--only-if-contains"),
+                    (show_diff, "--show-diff"),
+                    (fast, "--fast"),
+                ];
+                if let Some((_, option)) = needs_source.iter().find(|(given, _)| *given) {
+                    return Err(anyhow!(
+                        "{} reads the source directory and can't be used with --source-manifest",
+                        option
+                    ));
+                }
+                if use_diff_patches {
+                    info!("Storing modified files in full: a manifest has no content to diff.");
+                }
+            }
 
             check_path_exists(&target, "Target directory")
                 .context("Target directory check failed")?;
@@ -43,36 +185,159 @@ fn main() -> Result<()> {
 
             // Display exclude patterns if specified
             if let Some(exts) = &exclude_extensions
-                && !exts.is_empty() {
-                    info!("Excluding file extensions:");
-                    for ext in exts {
-                        info!("  - {}", ext);
-                    }
+                && !exts.is_empty()
+            {
+                info!("Excluding file extensions:");
+                for ext in exts {
+                    info!("  - {}", ext);
                 }
+            }
 
             if let Some(dirs) = &exclude_dirs
-                && !dirs.is_empty() {
-                    info!("Excluding directories:");
-                    for dir in dirs {
-                        info!("  - {}", dir);
-                    }
+                && !dirs.is_empty()
+            {
+                info!("Excluding directories:");
+                for dir in dirs {
+                    info!("  - {}", dir);
+                }
+            }
+
+            let (exclude_globs, include_globs) =
+                build_glob_filters(exclude_glob, exclude_from.as_deref(), &include)?;
+
+            if let Some(level) = compression_level {
+                compression.check_level(level)?;
+            }
+            if let Some(stub) = &stub {
+                if format == PatchFormat::Archive {
+                    return Err(anyhow!("--stub does not apply to --format archive"));
+                }
+                check_path_exists(stub, "Stub executable").context("Stub check failed")?;
+            }
+
+            // Encryption salts and nonces must stay random
+            if encrypt && seed.is_some() {
+                return Err(anyhow!("--seed cannot be used with --encrypt"));
+            }
+            let passphrase = if encrypt {
+                Some(utils::read_passphrase("Patch passphrase", true)?)
+            } else {
+                None
+            };
+
+            // Load license text to embed in the patch
+            let eula = match &eula_file {
+                Some(path) => {
+                    check_path_exists(path, "EULA file").context("EULA file check failed")?;
+                    let text = std::fs::read_to_string(path)
+                        .with_context(|| format!("Failed to read EULA file: {}", path.display()))?;
+                    info!("Embedding license from: {}", path.display());
+                    Some(text)
                 }
+                None => None,
+            };
 
             // Display if using diff patches
             if use_diff_patches {
                 info!("Using diff patches for modified files.");
             }
 
+            // Load explicit file lists
+            let manifest = manifest
+                .map(|path| utils::read_path_list(&path))
+                .transpose()?;
+            let manifest_removals = manifest_removals
+                .map(|path| utils::read_path_list(&path))
+                .transpose()?
+                .unwrap_or_default();
+            let force_remove = force_remove
+                .map(|path| utils::read_path_list(&path))
+                .transpose()?
+                .unwrap_or_default();
+            let only_if_contains = only_if_contains
+                .map(|pattern| {
+                    regex::bytes::Regex::new(&pattern)
+                        .with_context(|| format!("Invalid --only-if-contains pattern: {}", pattern))
+                })
+                .transpose()?;
+            let expected_source = expect_source_manifest
+                .map(|path| manifest::DirectoryManifest::load(&path))
+                .transpose()?;
+            let hash_cache = if no_cache {
+                None
+            } else {
+                cache_file.or_else(cache::default_cache_path)
+            };
+
+            // `path:<sha256>` entries also pin the content of the check file
+            let mut check_hashes = BTreeMap::new();
+            let check_files = check_files
+                .into_iter()
+                .map(|entry| {
+                    let (path, hash) = patch::parse_check_file(&entry);
+                    if let Some(hash) = hash {
+                        check_hashes.insert(path.clone(), hash);
+                    }
+                    path
+                })
+                .collect();
+
+            let options = CreateOptions::new()
+                .exclude_extensions(exclude_extensions.unwrap_or_default())
+                .exclude_dirs(exclude_dirs.unwrap_or_default())
+                .exclude_globs(exclude_globs)
+                .include_globs(include_globs)
+                .use_diff_patches(use_diff_patches)
+                .check_files(check_files)
+                .check_hashes(check_hashes)
+                .eula(eula)
+                .copy_retries(copy_retries)
+                .continue_on_error(continue_on_error)
+                .cdc(cdc)
+                .manifest(manifest)
+                .manifest_removals(manifest_removals)
+                .parallel_scan(parallel_scan)
+                .fast(fast)
+                .hash_cache(hash_cache)
+                .expected_source(expected_source)
+                .source_manifest(source_manifest)
+                .force_remove(force_remove)
+                .syntax_diff(syntax_diff)
+                .context_lines(context)
+                .diff_algorithm(diff_algorithm)
+                .max_diff_size(max_diff_size)
+                .diffs_in_zip(diffs_in_zip)
+                .only_if_contains(only_if_contains)
+                .hash_algorithm(hash_algorithm)
+                .follow_symlinks(follow_symlinks)
+                .compression(compression)
+                .compression_level(compression_level)
+                .passphrase(passphrase)
+                .patch_version(patch_version)
+                .requires_version(requires_version)
+                .author(author)
+                .description(description)
+                .temp_dir(temp_dir)
+                .seed(seed)
+                .include_hidden(include_hidden)
+                .format(format)
+                .stub(stub)
+                .threads(threads)
+                .io_buffer_size(io_buffer_size);
+
             // Create patch
-            let diffs = diff::compare_directories(
-                &source,
-                &target,
-                exclude_extensions.as_deref(),
-                exclude_dirs.as_deref(),
-                use_diff_patches,
-            )?;
+            let diffs = diff::compare_directories(&source, &target, &options)?;
 
-            if diffs.is_empty() {
+            // Glob removals are only expanded when the patch is applied
+            let has_removed_globs = options
+                .force_remove
+                .iter()
+                .any(|p| utils::is_glob_pattern(p));
+            if diffs.is_empty() && !has_removed_globs {
+                if require_changes {
+                    eprintln!("Error: No differences found, but --require-changes was given.");
+                    std::process::exit(EXIT_NO_CHANGES);
+                }
                 info!("No differences found, no need to create a patch.");
                 return Ok(());
             }
@@ -89,60 +354,670 @@ fn main() -> Result<()> {
                 .iter()
                 .filter(|d| matches!(d, diff::DiffType::ModifiedDiff(_)))
                 .count();
+            let delta_count = diffs
+                .iter()
+                .filter(|d| matches!(d, diff::DiffType::BinaryDelta(_)))
+                .count();
             let del_count = diffs
                 .iter()
                 .filter(|d| matches!(d, diff::DiffType::Removed(_)))
                 .count();
+            let type_change_count = diffs
+                .iter()
+                .filter(|d| matches!(d, diff::DiffType::TypeChanged(_)))
+                .count();
+            let rename_count = diffs
+                .iter()
+                .filter(|d| matches!(d, diff::DiffType::Renamed(_)))
+                .count();
+            let symlink_count = diffs
+                .iter()
+                .filter(|d| matches!(d, diff::DiffType::Symlink(_)))
+                .count();
 
-            info!("Found {} file differences:", diffs.len());
-            info!("  Added: {} files", add_count);
-            info!("  Modified (full files): {} files", mod_count);
+            // Printed whatever the log level, the confirmation below is about them
+            println!("Found {} file differences:", diffs.len());
+            println!("  Added: {} files", add_count);
+            println!("  Modified (full files): {} files", mod_count);
             if use_diff_patches {
-                info!("  Modified (diff patches): {} files", mod_diff_count);
+                println!("  Modified (diff patches): {} files", mod_diff_count);
+                println!("  Modified (binary deltas): {} files", delta_count);
+            }
+            if rename_count > 0 {
+                println!("  Renamed: {} files", rename_count);
+            }
+            println!("  Deleted: {} files", del_count);
+            if type_change_count > 0 {
+                println!("  Type changed: {} paths", type_change_count);
+            }
+            if symlink_count > 0 {
+                println!("  Symlinks: {} links", symlink_count);
+            }
+
+            let stats = diff::change_stats(&diffs);
+            if mod_diff_count > 0 {
+                println!(
+                    "  Lines in diffs: +{} -{}",
+                    stats.inserted_lines, stats.deleted_lines
+                );
+            }
+            println!("  Full file content: {}", HumanBytes(stats.full_file_bytes));
+            println!(
+                "  Estimated patch size: about {}",
+                HumanBytes(stats.estimated_patch_bytes)
+            );
+
+            // Modified files that could not be diffed or delta-encoded compactly
+            if use_diff_patches && mod_count > 0 {
+                let mut full_files: Vec<&diff::FileInfo> = diffs
+                    .iter()
+                    .filter_map(|d| match d {
+                        diff::DiffType::Modified(info) => Some(info),
+                        _ => None,
+                    })
+                    .collect();
+                full_files.sort_by_key(|info| std::cmp::Reverse(info.size));
+                println!("Will be shipped as full files (no smaller binary delta):");
+                for info in full_files {
+                    println!("  - {} ({} bytes)", info.relative_path.display(), info.size);
+                }
+            }
+
+            if show_diff {
+                print_modified_diffs(&source, &target, &diffs, options.context_lines)?;
             }
-            info!("  Deleted: {} files", del_count);
 
             // Check verification file list
-            for check_file in &check_files {
+            for check_file in &options.check_files {
                 let check_path = source.join(check_file);
-                if !check_path.exists() {
+                if let Some(manifest) = &options.source_manifest {
+                    if !manifest.files.contains_key(Path::new(check_file)) {
+                        warn!(
+                            "Verification file is not in the source manifest: {}",
+                            check_file
+                        );
+                    }
+                } else if !check_path.exists() {
+                    warn!("Verification file does not exist: {}", check_path.display());
+                } else if let Some(expected) = options.check_hashes.get(check_file)
+                    && diff::calculate_file_hash(&check_path, diff::HashAlgorithm::Sha256)?
+                        != *expected
+                {
                     warn!(
-                        "Verification file does not exist: {}",
+                        "Verification file does not match its expected SHA256: {}",
                         check_path.display()
                     );
                 }
             }
 
-            if check_files.is_empty() {
-                warn!(
-                    "No verification files specified, patch will be applied to any directory."
-                );
+            if options.check_files.is_empty() {
+                warn!("No verification files specified, patch will be applied to any directory.");
             } else {
                 info!("Specified verification files:");
-                for file in &check_files {
-                    info!("  - {}", file);
+                for file in &options.check_files {
+                    match options.check_hashes.get(file) {
+                        Some(hash) => info!("  - {} (SHA256 {})", file, hash),
+                        None => info!("  - {}", file),
+                    }
+                }
+            }
+
+            if dry_run {
+                print_create_dry_run(&diffs);
+                return Ok(());
+            }
+
+            // A patch without verification files overwrites whatever directory it is run in,
+            // so --yes alone doesn't make one
+            if options.check_files.is_empty() && !allow_unverified {
+                if !std::io::stdin().is_terminal() {
+                    return Err(anyhow!(
+                        "Refusing to create a patch without verification files. Add \
+                         --check-files, or pass --allow-unverified to create one that applies \
+                         to any directory."
+                    ));
+                }
+                if !utils::confirm_prompt(
+                    "This patch has no verification files and will overwrite files in any \
+                     directory it is run in. Create it anyway?",
+                    false,
+                )? {
+                    info!("Operation cancelled.");
+                    return Ok(());
                 }
             }
 
-            // Confirm patch creation
-            if !utils::confirm_action("Confirm creating patch file?")? {
+            // Confirm patch creation, which only writes the output file
+            if !utils::confirm_action("Confirm creating patch file?", false)? {
                 info!("Operation cancelled.");
                 return Ok(());
             }
 
-            patch::create_patch(&source, &target, &output, diffs, check_files)?;
+            patch::create_patch(&source, &target, &output, diffs, &options)?;
+
+            if let Some(reverse_output) = emit_reverse {
+                info!("Creating reverse patch...");
+                // Bare file names are placed next to the forward patch in the source directory
+                let reverse_output = if reverse_output.components().count() == 1 {
+                    source.join(reverse_output)
+                } else {
+                    reverse_output
+                };
+
+                // Check files for the reverse patch must exist once the forward patch is applied
+                let reverse_check_files: Vec<String> = options
+                    .check_files
+                    .iter()
+                    .filter(|file| target.join(file).exists())
+                    .cloned()
+                    .collect();
+                // Pinned check files are pinned to their updated content
+                let mut reverse_check_hashes = BTreeMap::new();
+                for file in &reverse_check_files {
+                    if options.check_hashes.contains_key(file) {
+                        let hash = diff::calculate_file_hash(
+                            &target.join(file),
+                            diff::HashAlgorithm::Sha256,
+                        )?;
+                        reverse_check_hashes.insert(file.clone(), hash);
+                    }
+                }
+                // The reverse patch goes back from the version the forward patch installs
+                let reverse_options = options
+                    .clone()
+                    .check_files(reverse_check_files)
+                    .check_hashes(reverse_check_hashes)
+                    .patch_version(options.requires_version.clone())
+                    .requires_version(options.patch_version.clone())
+                    // The source was checked for the forward patch, the target won't match it
+                    .expected_source(None);
+
+                let reverse_diffs = diff::compare_directories(&target, &source, &reverse_options)?;
+                patch::create_patch(
+                    &target,
+                    &source,
+                    &reverse_output,
+                    reverse_diffs,
+                    &reverse_options,
+                )?;
+            }
+        }
+
+        Commands::Repair { patch, target } => {
+            check_path_exists(&patch, "Patch file").context("Patch file check failed")?;
+            check_path_exists(&target, "Target directory")
+                .context("Target directory check failed")?;
+            check_is_directory(&target).context("Target directory check failed")?;
+
+            let outcome = repair::repair(&patch, &target)?;
+            for item in &outcome.repaired {
+                println!("repaired {}", item);
+            }
+            if !outcome.remaining.is_empty() {
+                for item in &outcome.remaining {
+                    println!("{}", item);
+                }
+                return Err(anyhow::anyhow!(
+                    "Could not repair {} entries",
+                    outcome.remaining.len()
+                ));
+            }
+            if outcome.repaired.is_empty() {
+                println!("Directory already matches the patch end state.");
+            } else {
+                println!("Directory now matches the patch end state.");
+            }
+        }
+
+        Commands::Restore { target } => {
+            check_path_exists(&target, "Target directory")
+                .context("Target directory check failed")?;
+            check_is_directory(&target).context("Target directory check failed")?;
+
+            let manifest = backup::restore(&target)?;
+            println!(
+                "Restored {} entries and removed {} entries added by the patch.",
+                manifest.saved.len(),
+                manifest.created.len()
+            );
+        }
+
+        Commands::Verify { patch } => {
+            check_path_exists(&patch, "Patch file").context("Patch file check failed")?;
+
+            let problems = patch::verify_patch_file(&patch)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                return Err(anyhow::anyhow!(
+                    "Patch file is broken: {} problems found",
+                    problems.len()
+                ));
+            }
+            println!("Patch file is well-formed.");
+        }
+
+        Commands::VerifyApplied { patch, target } => {
+            check_path_exists(&patch, "Patch file").context("Patch file check failed")?;
+            check_path_exists(&target, "Target directory")
+                .context("Target directory check failed")?;
+            check_is_directory(&target).context("Target directory check failed")?;
+
+            let drift = verify::verify_applied(&patch, &target)?;
+            if !drift.is_empty() {
+                for item in &drift {
+                    println!("{}", item);
+                }
+                return Err(anyhow::anyhow!(
+                    "Directory does not match the patch: {} entries drifted",
+                    drift.len()
+                ));
+            }
+            println!("Directory matches the patch end state.");
+        }
+
+        Commands::DiffPatches { old, new } => {
+            check_path_exists(&old, "Patch file").context("Patch file check failed")?;
+            check_path_exists(&new, "Patch file").context("Patch file check failed")?;
+
+            let (old_data, _) = patch::extract_patch_data(&old)?;
+            let (new_data, _) = patch::extract_patch_data(&new)?;
+            let deltas = patch_diff::diff_patch_data(&old_data, &new_data);
+
+            if deltas.iter().all(|d| d.is_empty()) {
+                println!("Patches contain the same changes.");
+            }
+            for delta in deltas.iter().filter(|d| !d.is_empty()) {
+                println!("{}:", delta.category);
+                for path in &delta.only_in_new {
+                    println!("  + {}", path.display());
+                }
+                for path in &delta.only_in_old {
+                    println!("  - {}", path.display());
+                }
+                for path in &delta.changed {
+                    println!("  ~ {}", path.display());
+                }
+            }
+        }
+
+        Commands::Inspect { patch, json, diffs } => {
+            check_path_exists(&patch, "Patch file").context("Patch file check failed")?;
+
+            let (patch_data, payload) = patch::extract_patch_data(&patch)?;
+            if json {
+                let text = serde_json::to_string_pretty(&patch_data)
+                    .context("Failed to serialize patch data")?;
+                println!("{}", text);
+            } else {
+                print_patch_summary(&patch_data);
+                if diffs {
+                    let color = console::colors_enabled();
+                    for file_diff in patch::load_file_diffs(&patch, &patch_data, payload)? {
+                        print!("{}", diff::render_file_diff(&file_diff, color));
+                    }
+                }
+            }
+        }
+
+        Commands::ApplyChain {
+            patches,
+            target,
+            target_uri,
+            options,
+        } => {
+            for patch in &patches {
+                check_path_exists(patch, "Patch file").context("Patch file check failed")?;
+            }
+            let options = ApplyOptions::from(&options)
+                .threads(threads)
+                .io_buffer_size(io_buffer_size);
+            let status = if let Some(uri) = target_uri {
+                let target = ObjectStoreTarget::from_uri(&uri)?;
+                patch::apply_patch_chain_to_target(&patches, &target, &options)?
+            } else {
+                let target_dir = resolve_target_dir(target)?;
+                patch::apply_patch_chain(&patches, &target_dir, &options)?
+            };
+            if status == ApplyStatus::Applied {
+                println!("Applied {} patches.", patches.len());
+            }
+        }
+
+        Commands::Manifest(args) => {
+            check_path_exists(&args.dir, "Directory").context("Directory check failed")?;
+            check_is_directory(&args.dir).context("Directory check failed")?;
+
+            let (exclude_globs, include_globs) = build_glob_filters(
+                args.exclude_glob,
+                args.exclude_from.as_deref(),
+                &args.include,
+            )?;
+            let options = CreateOptions::new()
+                .exclude_extensions(args.exclude_extensions)
+                .exclude_dirs(args.exclude_dirs)
+                .exclude_globs(exclude_globs)
+                .include_globs(include_globs)
+                .include_hidden(args.include_hidden)
+                .follow_symlinks(args.follow_symlinks)
+                .hash_algorithm(args.hash_algorithm)
+                .threads(threads)
+                .io_buffer_size(io_buffer_size);
+
+            let manifest = manifest::DirectoryManifest::scan(&args.dir, &options)?;
+            manifest.save(&args.output)?;
+            println!(
+                "Wrote {} files to manifest: {}",
+                manifest.files.len(),
+                args.output.display()
+            );
+        }
+
+        Commands::ApplyArchive {
+            patch,
+            target,
+            target_uri,
+            options,
+        } => {
+            check_path_exists(&patch, "Patch file").context("Patch file check failed")?;
+            let options = ApplyOptions::from(&options)
+                .threads(threads)
+                .io_buffer_size(io_buffer_size);
+            if let Some(uri) = target_uri {
+                let target = ObjectStoreTarget::from_uri(&uri)?;
+                patch::apply_patch_to_target(&patch, &target, &options)?;
+            } else {
+                let target_dir = resolve_target_dir(target)?;
+                patch::apply_patch_file(&patch, &target_dir, &options)?;
+            }
         }
 
-        Commands::Apply { patch_data: _ } => {
+        Commands::Apply {
+            patch_data: _,
+            target,
+            options,
+        } => {
             // Apply patch, typically called directly by the generated patch program, not by users
-            let current_dir = env::current_dir().context("Failed to get current directory")?;
-            patch::apply_patch(&current_dir)?;
+            let target_dir = resolve_target_dir(target)?;
+            let options = ApplyOptions::from(&options)
+                .threads(threads)
+                .io_buffer_size(io_buffer_size);
+            patch::apply_patch(&target_dir, &options)?;
         }
     }
 
     Ok(())
 }
 
+/// Directory a patch is applied to: the given target, or the current directory
+fn resolve_target_dir(target: Option<PathBuf>) -> Result<PathBuf> {
+    match target {
+        Some(dir) => {
+            check_path_exists(&dir, "Target directory").context("Target directory check failed")?;
+            check_is_directory(&dir).context("Target directory check failed")?;
+            Ok(dir)
+        }
+        None => env::current_dir().context("Failed to get current directory"),
+    }
+}
+
+/// Glob excludes from the command line and an ignore file, and the include allowlist
+fn build_glob_filters(
+    mut exclude_patterns: Vec<String>,
+    exclude_from: Option<&Path>,
+    include: &[String],
+) -> Result<(Option<GlobSet>, Option<GlobSet>)> {
+    if let Some(path) = exclude_from {
+        exclude_patterns.extend(utils::read_pattern_list(path)?);
+    }
+    let exclude_globs = if exclude_patterns.is_empty() {
+        None
+    } else {
+        info!("Excluding glob patterns:");
+        for pattern in &exclude_patterns {
+            info!("  - {}", pattern);
+        }
+        Some(utils::build_exclude_set(&exclude_patterns)?)
+    };
+
+    let include_globs = if include.is_empty() {
+        None
+    } else {
+        info!("Only including paths matching:");
+        for pattern in include {
+            info!("  - {}", pattern);
+        }
+        Some(utils::build_glob_set(include)?)
+    };
+    Ok((exclude_globs, include_globs))
+}
+
+/// Print a unified diff for each modified file
+fn print_modified_diffs(
+    source: &Path,
+    target: &Path,
+    diffs: &[diff::DiffType],
+    context: usize,
+) -> Result<()> {
+    for d in diffs {
+        let relative_path = match d {
+            diff::DiffType::Modified(info) => &info.relative_path,
+            diff::DiffType::ModifiedDiff(file_diff) => &file_diff.relative_path,
+            diff::DiffType::BinaryDelta(delta) => &delta.relative_path,
+            _ => continue,
+        };
+        let old = std::fs::read(source.join(relative_path))
+            .with_context(|| format!("Failed to read source file: {}", relative_path.display()))?;
+        let new = std::fs::read(target.join(relative_path))
+            .with_context(|| format!("Failed to read target file: {}", relative_path.display()))?;
+        print!(
+            "{}",
+            diff::render_unified_diff(relative_path, &old, &new, context)
+        );
+    }
+    Ok(())
+}
+
+/// Print every change a patch would contain with its size, sorted by path
+fn print_create_dry_run(diffs: &[diff::DiffType]) {
+    println!("Dry run: no patch file will be written");
+    let mut lines: Vec<(&Path, String)> = diffs
+        .iter()
+        .map(|d| match d {
+            diff::DiffType::Added(info) => (
+                info.relative_path.as_path(),
+                format!(
+                    "add       {} ({})",
+                    info.relative_path.display(),
+                    HumanBytes(info.size)
+                ),
+            ),
+            diff::DiffType::Modified(info) => (
+                info.relative_path.as_path(),
+                format!(
+                    "replace   {} ({})",
+                    info.relative_path.display(),
+                    HumanBytes(info.size)
+                ),
+            ),
+            diff::DiffType::ModifiedDiff(file_diff) => {
+                let stats = diff::change_stats(std::slice::from_ref(d));
+                (
+                    file_diff.relative_path.as_path(),
+                    format!(
+                        "patch     {} (+{} -{} lines)",
+                        file_diff.relative_path.display(),
+                        stats.inserted_lines,
+                        stats.deleted_lines
+                    ),
+                )
+            }
+            diff::DiffType::BinaryDelta(delta) => (
+                delta.relative_path.as_path(),
+                format!(
+                    "delta     {} ({} delta for {})",
+                    delta.relative_path.display(),
+                    HumanBytes(delta.delta.len() as u64),
+                    HumanBytes(delta.size)
+                ),
+            ),
+            diff::DiffType::Symlink(link) => (
+                link.relative_path.as_path(),
+                format!(
+                    "link      {} -> {}",
+                    link.relative_path.display(),
+                    link.target.display()
+                ),
+            ),
+            diff::DiffType::Renamed(rename) => (
+                rename.to.as_path(),
+                format!(
+                    "rename    {} -> {}",
+                    rename.from.display(),
+                    rename.to.display()
+                ),
+            ),
+            diff::DiffType::Removed(path) => {
+                (path.as_path(), format!("remove    {}", path.display()))
+            }
+            diff::DiffType::TypeChanged(change) => (
+                change.relative_path.as_path(),
+                format!("retype    {}", change.relative_path.display()),
+            ),
+        })
+        .collect();
+    lines.sort();
+    for (_, line) in lines {
+        println!("{}", line);
+    }
+
+    let stats = diff::change_stats(diffs);
+    println!(
+        "{} changes, {} in full files, patch about {}",
+        diffs.len(),
+        HumanBytes(stats.full_file_bytes),
+        HumanBytes(stats.estimated_patch_bytes)
+    );
+}
+
+/// Print the checks and changes recorded in a patch
+fn print_patch_summary(patch_data: &patch::PatchData) {
+    print!("{}", patch_data.metadata);
+    if let Some(version) = &patch_data.requires_version {
+        println!("Requires version: {}", version);
+    }
+    if patch_data.check_files.is_empty() {
+        println!("Verification files: none");
+    } else {
+        println!("Verification files:");
+        for file in &patch_data.check_files {
+            match patch_data.check_hashes.get(file) {
+                Some(hash) => println!("  {} (SHA256 {})", file, hash),
+                None => println!("  {}", file),
+            }
+        }
+    }
+    if patch_data.eula.is_some() {
+        println!("License: embedded");
+    }
+
+    let sections: [(&str, Vec<&Path>); 7] = [
+        (
+            "Added",
+            patch_data
+                .added_files
+                .iter()
+                .map(|f| f.relative_path.as_path())
+                .collect(),
+        ),
+        (
+            "Modified (full files)",
+            patch_data
+                .modified_files
+                .iter()
+                .map(|f| f.relative_path.as_path())
+                .collect(),
+        ),
+        (
+            "Modified (diff patches)",
+            patch_data
+                .modified_diffs
+                .iter()
+                .map(|d| d.relative_path.as_path())
+                .collect(),
+        ),
+        (
+            "Modified (binary deltas)",
+            patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| d.relative_path.as_path())
+                .collect(),
+        ),
+        (
+            "Symlinks",
+            patch_data
+                .symlinks
+                .iter()
+                .map(|l| l.relative_path.as_path())
+                .collect(),
+        ),
+        (
+            "Removed",
+            patch_data
+                .removed_files
+                .iter()
+                .map(PathBuf::as_path)
+                .collect(),
+        ),
+        (
+            "Type changed",
+            patch_data
+                .type_changes
+                .iter()
+                .map(|c| c.relative_path.as_path())
+                .collect(),
+        ),
+    ];
+    for (category, paths) in sections {
+        println!("{}: {}", category, paths.len());
+        for path in paths {
+            println!("  {}", path.display());
+        }
+    }
+
+    if !patch_data.renamed_files.is_empty() {
+        println!("Renamed: {}", patch_data.renamed_files.len());
+        for rename in &patch_data.renamed_files {
+            println!("  {} -> {}", rename.from.display(), rename.to.display());
+        }
+    }
+
+    if !patch_data.removed_globs.is_empty() {
+        println!("Removed globs: {}", patch_data.removed_globs.len());
+        for pattern in &patch_data.removed_globs {
+            println!("  {}", pattern);
+        }
+    }
+}
+
+/// Log progress and status messages, more with -v/-vv or only errors with --quiet
+///
+/// `RUST_LOG` still takes precedence when it is set.
+fn init_logger(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
+
 // Check if running as a patch executable
 fn is_patch_executable() -> bool {
     let Ok(exe_path) = std::env::current_exe() else {
@@ -177,5 +1052,5 @@ fn is_patch_executable() -> bool {
         return false;
     }
 
-    &buffer == b"PATCH_END"
+    &buffer == patch::END_MARKER || &buffer == patch::LEGACY_END_MARKER
 }
@@ -0,0 +1,149 @@
+use crate::diff::{
+    FileInfo, HashAlgorithm, calculate_file_hash, is_diffpatch_entry, scan_directory,
+};
+use crate::options::CreateOptions;
+use crate::utils::{build_io_pool, get_io_thread_count, native_path, portable_path};
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Snapshot of the files in a directory
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DirectoryManifest {
+    /// Algorithm of the file hashes, unknown for manifests written by other tools
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// When the directory was scanned, in UTC as RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    pub files: BTreeMap<PathBuf, FileInfo>,
+}
+
+impl DirectoryManifest {
+    /// Hash the files a patch created with `options` would consider
+    pub fn scan(dir: &Path, options: &CreateOptions) -> Result<Self> {
+        let pool = build_io_pool(get_io_thread_count(dir, options.threads));
+        let files = scan_directory(&pool, dir, options)?;
+        Ok(Self {
+            hash_algorithm: Some(options.hash_algorithm),
+            created: Some(
+                jiff::Timestamp::now()
+                    .strftime("%Y-%m-%dT%H:%M:%SZ")
+                    .to_string(),
+            ),
+            files: files.into_iter().collect(),
+        })
+    }
+
+    /// Write the manifest as JSON, with `/` separators on every platform
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut portable = self.clone();
+        portable.map_paths(portable_path);
+        let content =
+            serde_json::to_vec_pretty(&portable).context("Failed to serialize manifest")?;
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write manifest: {}", path.display()))
+    }
+
+    /// Fail if the manifest's hashes can't be compared with hashes made with `algorithm`
+    pub fn check_algorithm(&self, algorithm: HashAlgorithm) -> Result<()> {
+        match self.hash_algorithm {
+            Some(recorded) if recorded != algorithm => Err(anyhow!(
+                "Manifest hashes were made with {:?}, not {:?}; pass the same --hash",
+                recorded,
+                algorithm
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Load a manifest from a JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read(path)
+            .with_context(|| format!("Failed to read manifest: {}", path.display()))?;
+        let mut manifest: Self = serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse manifest: {}", path.display()))?;
+        manifest.map_paths(native_path);
+        Ok(manifest)
+    }
+
+    fn map_paths(&mut self, map: fn(&Path) -> PathBuf) {
+        self.files = std::mem::take(&mut self.files)
+            .into_iter()
+            .map(|(path, mut info)| {
+                info.map_paths(map);
+                (map(&path), info)
+            })
+            .collect();
+    }
+
+    /// Describe every difference between this manifest and scanned files
+    pub fn mismatches(&self, files: &HashMap<PathBuf, FileInfo>) -> Vec<String> {
+        let mut mismatches = Vec::new();
+
+        for (path, expected) in &self.files {
+            match files.get(path) {
+                None => mismatches.push(format!("missing: {}", path.display())),
+                Some(actual) if actual.hash != expected.hash => {
+                    mismatches.push(format!("content differs: {}", path.display()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut unexpected: Vec<_> = files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .collect();
+        unexpected.sort();
+        for path in unexpected {
+            mismatches.push(format!("unexpected: {}", path.display()));
+        }
+
+        mismatches
+    }
+}
+
+/// Compute a single fingerprint of every file under a directory
+///
+/// The hash covers the sorted relative paths (with `/` separators) and the
+/// SHA256 of each file, so two directories with the same contents produce the
+/// same hash regardless of platform or walk order. diffpatch's own bookkeeping
+/// (backups, rollback and resume state, the installed version) is left out, as
+/// is `skip`, such as the running patch executable.
+pub fn tree_hash(dir: &Path, skip: Option<&Path>) -> Result<String> {
+    let mut entries = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry =
+            entry.with_context(|| format!("Failed to walk directory: {}", dir.display()))?;
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        if !entry.file_type().is_file()
+            || skip == Some(entry.path())
+            || is_diffpatch_entry(relative)
+        {
+            continue;
+        }
+        let key = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        entries.insert(
+            key,
+            calculate_file_hash(entry.path(), HashAlgorithm::Sha256)?,
+        );
+    }
+
+    let mut hasher = Sha256::new();
+    for (path, hash) in &entries {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
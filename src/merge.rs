@@ -0,0 +1,175 @@
+use crate::diff::{DiffChange, DiffChangeTag};
+use crate::transform::LineStyle;
+
+/// A run of base lines replaced by new lines, positioned in base line numbers
+struct Hunk<'a> {
+    old_start: usize,
+    old_len: usize,
+    old_lines: Option<Vec<&'a str>>, // None when the patch did not record the replaced lines
+    new_lines: Vec<&'a str>,
+    context: Vec<&'a str>, // unchanged base lines right before the hunk, if recorded
+}
+
+/// Result of merging a diff into a locally modified file
+pub struct MergeResult {
+    pub content: String,
+    pub conflicts: usize,
+}
+
+/// Split change content that joins `count` lines with `\n`
+fn split_lines(content: &str, count: usize) -> Vec<&str> {
+    if count == 0 {
+        Vec::new()
+    } else {
+        content.split('\n').collect()
+    }
+}
+
+/// Turn line-based changes into hunks positioned in the original file
+///
+/// Inserts only record their position in the new file, so it is translated
+/// back using the line count difference of the preceding hunks.
+fn line_hunks(changes: &[DiffChange]) -> Vec<Hunk<'_>> {
+    let mut delta: isize = 0;
+    let mut hunks = Vec::new();
+    for change in changes {
+        let context = change
+            .context
+            .as_deref()
+            .map(|content| content.split('\n').collect())
+            .unwrap_or_default();
+        let hunk = match (&change.tag, change.old_range, change.new_range) {
+            (DiffChangeTag::Delete, Some((start, len)), _) => Hunk {
+                old_start: start,
+                old_len: len,
+                old_lines: Some(split_lines(&change.content, len)),
+                new_lines: Vec::new(),
+                context,
+            },
+            (DiffChangeTag::Insert, _, Some((start, len))) => Hunk {
+                old_start: (start as isize - delta).max(0) as usize,
+                old_len: 0,
+                old_lines: Some(Vec::new()),
+                new_lines: split_lines(&change.content, len),
+                context,
+            },
+            (DiffChangeTag::Replace, Some((start, len)), Some((_, new_len))) => Hunk {
+                old_start: start,
+                old_len: len,
+                old_lines: change
+                    .old_content
+                    .as_deref()
+                    .map(|content| split_lines(content, len)),
+                new_lines: split_lines(&change.content, new_len),
+                context,
+            },
+            _ => continue,
+        };
+        delta += hunk.new_lines.len() as isize - hunk.old_len as isize;
+        hunks.push(hunk);
+    }
+    hunks
+}
+
+/// Apply line-based changes to the content they were computed against,
+/// writing the result with the given line endings
+pub fn apply_line_changes(content: &str, changes: &[DiffChange], style: LineStyle) -> String {
+    let mut lines: Vec<&str> = content.lines().collect();
+
+    // Apply from back to front, so earlier line numbers aren't affected
+    for hunk in line_hunks(changes).into_iter().rev() {
+        let start = hunk.old_start.min(lines.len());
+        let end = (start + hunk.old_len).min(lines.len());
+        lines.splice(start..end, hunk.new_lines);
+    }
+
+    style.join(&lines)
+}
+
+/// Find where a hunk's base lines occur in the local file, preferring the spot nearest `expected`
+fn find_hunk(mine: &[&str], old_lines: &[&str], expected: usize, from: usize) -> Option<usize> {
+    if old_lines.is_empty() {
+        return Some(expected.clamp(from, mine.len()));
+    }
+    if mine.len() < old_lines.len() {
+        return None;
+    }
+    (from..=mine.len() - old_lines.len())
+        .filter(|&at| mine[at..at + old_lines.len()] == *old_lines)
+        .min_by_key(|&at| at.abs_diff(expected))
+}
+
+/// Find a hunk by its recorded context followed by its base lines
+///
+/// This places hunks whose base lines occur more than once, and inserts, which
+/// have no base lines at all, after the right context rather than by line number.
+/// `from` bounds the hunk itself; its context may overlap lines already merged.
+fn find_with_context(
+    mine: &[&str],
+    context: &[&str],
+    old_lines: &[&str],
+    expected: usize,
+    from: usize,
+) -> Option<usize> {
+    let needle_len = context.len() + old_lines.len();
+    if context.is_empty() || mine.len() < needle_len {
+        return None;
+    }
+    (from.saturating_sub(context.len())..=mine.len() - needle_len)
+        .filter(|&at| {
+            mine[at..at + context.len()] == *context
+                && mine[at + context.len()..at + needle_len] == *old_lines
+        })
+        .map(|at| at + context.len())
+        .filter(|&at| at >= from)
+        .min_by_key(|&at| at.abs_diff(expected))
+}
+
+/// Merge line-based changes into a file that was edited locally since the patch was made
+///
+/// Each hunk is applied where its original lines are found in the local file.
+/// Hunks whose original lines were edited locally become diff3-style conflict
+/// blocks. Returns `None` if the patch does not record the replaced lines.
+pub fn three_way_merge(mine: &str, changes: &[DiffChange]) -> Option<MergeResult> {
+    let mine_lines: Vec<&str> = mine.lines().collect();
+    let hunks = line_hunks(changes);
+
+    let mut out: Vec<&str> = Vec::new();
+    let mut conflicts = 0;
+    let mut pos = 0; // next local line not yet copied to the output
+    let mut offset: isize = 0; // local line number minus base line number of the last match
+    for hunk in &hunks {
+        let old_lines = hunk.old_lines.as_ref()?;
+        let expected = ((hunk.old_start as isize + offset).max(0) as usize).max(pos);
+
+        let found = find_with_context(&mine_lines, &hunk.context, old_lines, expected, pos)
+            .or_else(|| find_hunk(&mine_lines, old_lines, expected, pos));
+        match found {
+            Some(at) => {
+                out.extend_from_slice(&mine_lines[pos..at]);
+                out.extend_from_slice(&hunk.new_lines);
+                pos = at + old_lines.len();
+                offset = at as isize - hunk.old_start as isize;
+            }
+            None => {
+                let start = expected.min(mine_lines.len());
+                let end = (start + hunk.old_len).min(mine_lines.len());
+                out.extend_from_slice(&mine_lines[pos..start]);
+                out.push("<<<<<<< local");
+                out.extend_from_slice(&mine_lines[start..end]);
+                out.push("||||||| base");
+                out.extend_from_slice(old_lines);
+                out.push("=======");
+                out.extend_from_slice(&hunk.new_lines);
+                out.push(">>>>>>> patch");
+                pos = end;
+                conflicts += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&mine_lines[pos..]);
+
+    // Keep the local file's line endings
+    let content = LineStyle::detect(mine).join(&out);
+    Some(MergeResult { content, conflicts })
+}
@@ -0,0 +1,508 @@
+use crate::diff::{DiffAlgorithm, HashAlgorithm};
+use crate::manifest::DirectoryManifest;
+use crate::patch::{Compression, OnConflict, PatchFormat};
+use crate::transform::EolMode;
+use globset::GlobSet;
+use regex::bytes::Regex;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+/// Options controlling how a patch is created
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// File extensions to exclude (with or without the leading dot)
+    pub exclude_extensions: Vec<String>,
+    /// Directory names to exclude
+    pub exclude_dirs: Vec<String>,
+    /// Relative paths to exclude, built with `utils::build_exclude_set`
+    pub exclude_globs: Option<GlobSet>,
+    /// When set, only relative paths matching these globs are considered
+    pub include_globs: Option<GlobSet>,
+    /// Store text diffs instead of full files for modified files
+    pub use_diff_patches: bool,
+    /// Files that must exist in the directory the patch is applied to
+    pub check_files: Vec<String>,
+    /// Expected SHA256 of check files whose content must match, by path
+    pub check_hashes: BTreeMap<String, String>,
+    /// License text that must be accepted before applying
+    pub eula: Option<String>,
+    /// Attempts at copying and opening the output executable
+    pub copy_retries: u32,
+    /// Leave out files that cannot be read or copied, or whose names aren't valid UTF-8,
+    /// with a warning instead of failing
+    pub continue_on_error: bool,
+    /// Compute content-defined chunk fingerprints to detect near-duplicate files
+    pub cdc: bool,
+    /// Only consider these relative paths instead of walking the directories
+    pub manifest: Option<Vec<PathBuf>>,
+    /// Relative paths to remove, used together with `manifest`
+    pub manifest_removals: Vec<PathBuf>,
+    /// Scan source and target directories concurrently
+    pub parallel_scan: bool,
+    /// Treat files with the same size and modification time on both sides as unchanged
+    pub fast: bool,
+    /// File to keep whole-file hashes in between runs, `None` to hash every file
+    pub hash_cache: Option<PathBuf>,
+    /// Manifest the scanned source directory must match before a patch is built
+    pub expected_source: Option<DirectoryManifest>,
+    /// Files of the source directory, compared against instead of scanning it
+    pub source_manifest: Option<DirectoryManifest>,
+    /// Relative paths or glob patterns to remove regardless of the source contents
+    pub force_remove: Vec<PathBuf>,
+    /// Diff recognized source files by brace/indent-aware tokens instead of lines
+    pub syntax_diff: bool,
+    /// Unchanged lines recorded before each line change, used to relocate it in merges
+    pub context_lines: usize,
+    /// Algorithm used to diff modified text files
+    pub diff_algorithm: DiffAlgorithm,
+    /// Files larger than this many bytes are stored whole instead of being diffed
+    pub max_diff_size: Option<u64>,
+    /// Store each diff as a zip entry instead of inline in the patch data
+    pub diffs_in_zip: bool,
+    /// Only patch modified files whose source content matches this pattern
+    pub only_if_contains: Option<Regex>,
+    /// Write a self-applying executable or a plain archive
+    pub format: PatchFormat,
+    /// Executable the patch is appended to, instead of the running executable
+    pub stub: Option<PathBuf>,
+    /// Algorithm for the file hashes recorded in the patch
+    pub hash_algorithm: HashAlgorithm,
+    /// Treat symbolic links as the entries they point to instead of storing links
+    pub follow_symlinks: bool,
+    /// Compression method of the patch archive
+    pub compression: Compression,
+    /// Compression level, or the method's default
+    pub compression_level: Option<i64>,
+    /// Encrypt the patch data and payload with a key derived from this passphrase
+    pub passphrase: Option<String>,
+    /// Version of the patch itself, e.g. 1.4.2
+    pub patch_version: Option<String>,
+    /// Version a directory must have been patched to before the patch applies
+    pub requires_version: Option<String>,
+    pub author: Option<String>,
+    /// Free-text description of what the patch changes
+    pub description: Option<String>,
+    /// Directory for temporary files instead of the system temporary directory
+    pub temp_dir: Option<PathBuf>,
+    /// Name temporary files after this seed instead of at random; not allowed with a passphrase
+    pub seed: Option<u64>,
+    /// Include files and directories whose name starts with a dot
+    pub include_hidden: bool,
+    /// Threads for hashing, compressing and copying files, 0 for one per logical core;
+    /// `None` picks a count from the storage type or `DIFFPATCH_IO_THREADS`
+    pub threads: Option<usize>,
+    /// Buffer size in bytes for file reads and writes; `None` uses `DEFAULT_IO_BUFFER_SIZE`
+    pub io_buffer_size: Option<NonZeroUsize>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            exclude_extensions: Vec::new(),
+            exclude_dirs: Vec::new(),
+            exclude_globs: None,
+            include_globs: None,
+            use_diff_patches: false,
+            check_files: Vec::new(),
+            check_hashes: BTreeMap::new(),
+            eula: None,
+            copy_retries: 5,
+            continue_on_error: false,
+            cdc: false,
+            manifest: None,
+            manifest_removals: Vec::new(),
+            parallel_scan: false,
+            fast: false,
+            hash_cache: None,
+            expected_source: None,
+            source_manifest: None,
+            force_remove: Vec::new(),
+            syntax_diff: false,
+            context_lines: 3,
+            diff_algorithm: DiffAlgorithm::default(),
+            max_diff_size: None,
+            diffs_in_zip: false,
+            only_if_contains: None,
+            format: PatchFormat::default(),
+            stub: None,
+            hash_algorithm: HashAlgorithm::default(),
+            follow_symlinks: false,
+            compression: Compression::default(),
+            compression_level: None,
+            passphrase: None,
+            patch_version: None,
+            requires_version: None,
+            author: None,
+            description: None,
+            temp_dir: None,
+            seed: None,
+            include_hidden: false,
+            threads: None,
+            io_buffer_size: None,
+        }
+    }
+}
+
+impl CreateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn exclude_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.exclude_extensions = extensions;
+        self
+    }
+
+    pub fn exclude_dirs(mut self, dirs: Vec<String>) -> Self {
+        self.exclude_dirs = dirs;
+        self
+    }
+
+    pub fn exclude_globs(mut self, globs: Option<GlobSet>) -> Self {
+        self.exclude_globs = globs;
+        self
+    }
+
+    pub fn include_globs(mut self, globs: Option<GlobSet>) -> Self {
+        self.include_globs = globs;
+        self
+    }
+
+    pub fn use_diff_patches(mut self, enabled: bool) -> Self {
+        self.use_diff_patches = enabled;
+        self
+    }
+
+    pub fn check_files(mut self, files: Vec<String>) -> Self {
+        self.check_files = files;
+        self
+    }
+
+    pub fn check_hashes(mut self, hashes: BTreeMap<String, String>) -> Self {
+        self.check_hashes = hashes;
+        self
+    }
+
+    pub fn eula(mut self, eula: Option<String>) -> Self {
+        self.eula = eula;
+        self
+    }
+
+    pub fn copy_retries(mut self, attempts: u32) -> Self {
+        self.copy_retries = attempts.max(1);
+        self
+    }
+
+    pub fn continue_on_error(mut self, enabled: bool) -> Self {
+        self.continue_on_error = enabled;
+        self
+    }
+
+    pub fn cdc(mut self, enabled: bool) -> Self {
+        self.cdc = enabled;
+        self
+    }
+
+    pub fn manifest(mut self, paths: Option<Vec<PathBuf>>) -> Self {
+        self.manifest = paths;
+        self
+    }
+
+    pub fn manifest_removals(mut self, paths: Vec<PathBuf>) -> Self {
+        self.manifest_removals = paths;
+        self
+    }
+
+    pub fn parallel_scan(mut self, enabled: bool) -> Self {
+        self.parallel_scan = enabled;
+        self
+    }
+
+    pub fn fast(mut self, enabled: bool) -> Self {
+        self.fast = enabled;
+        self
+    }
+
+    pub fn hash_cache(mut self, path: Option<PathBuf>) -> Self {
+        self.hash_cache = path;
+        self
+    }
+
+    pub fn expected_source(mut self, manifest: Option<DirectoryManifest>) -> Self {
+        self.expected_source = manifest;
+        self
+    }
+
+    pub fn source_manifest(mut self, manifest: Option<DirectoryManifest>) -> Self {
+        self.source_manifest = manifest;
+        self
+    }
+
+    pub fn force_remove(mut self, paths: Vec<PathBuf>) -> Self {
+        self.force_remove = paths;
+        self
+    }
+
+    pub fn syntax_diff(mut self, enabled: bool) -> Self {
+        self.syntax_diff = enabled;
+        self
+    }
+
+    pub fn context_lines(mut self, lines: usize) -> Self {
+        self.context_lines = lines;
+        self
+    }
+
+    pub fn diff_algorithm(mut self, algorithm: DiffAlgorithm) -> Self {
+        self.diff_algorithm = algorithm;
+        self
+    }
+
+    pub fn max_diff_size(mut self, bytes: Option<u64>) -> Self {
+        self.max_diff_size = bytes;
+        self
+    }
+
+    pub fn diffs_in_zip(mut self, enabled: bool) -> Self {
+        self.diffs_in_zip = enabled;
+        self
+    }
+
+    pub fn only_if_contains(mut self, pattern: Option<Regex>) -> Self {
+        self.only_if_contains = pattern;
+        self
+    }
+
+    pub fn format(mut self, format: PatchFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn stub(mut self, path: Option<PathBuf>) -> Self {
+        self.stub = path;
+        self
+    }
+
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.follow_symlinks = enabled;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn compression_level(mut self, level: Option<i64>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    pub fn passphrase(mut self, passphrase: Option<String>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    pub fn requires_version(mut self, version: Option<String>) -> Self {
+        self.requires_version = version;
+        self
+    }
+
+    pub fn patch_version(mut self, version: Option<String>) -> Self {
+        self.patch_version = version;
+        self
+    }
+
+    pub fn author(mut self, author: Option<String>) -> Self {
+        self.author = author;
+        self
+    }
+
+    pub fn description(mut self, description: Option<String>) -> Self {
+        self.description = description;
+        self
+    }
+
+    pub fn temp_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn include_hidden(mut self, enabled: bool) -> Self {
+        self.include_hidden = enabled;
+        self
+    }
+
+    pub fn threads(mut self, threads: Option<usize>) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn io_buffer_size(mut self, size: Option<NonZeroUsize>) -> Self {
+        self.io_buffer_size = size;
+        self
+    }
+}
+
+/// Options controlling how a patch is applied
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Accept the embedded license without prompting
+    pub accept_eula: bool,
+    /// Convert text line endings on write
+    pub eol: Option<EolMode>,
+    /// Published SHA256 the whole patch file must match before anything runs
+    pub expect_sha256: Option<String>,
+    /// Directory copied into `out` before patching
+    pub base: Option<PathBuf>,
+    /// New directory the patched copy is written to
+    pub out: Option<PathBuf>,
+    /// Merge diffs into locally modified files instead of overwriting them
+    pub three_way: bool,
+    /// Print a tree hash of the directory before and after applying
+    pub merkle: bool,
+    /// Report what would change without writing anything
+    pub dry_run: bool,
+    /// Save overwritten and removed files so the apply can be undone
+    pub backup: bool,
+    /// Unpack the whole archive to a temporary directory before writing anything
+    pub safe: bool,
+    /// Write a JSON report of what happened to each file here
+    pub report: Option<PathBuf>,
+    /// Warn about written files whose hash differs from the patch instead of failing
+    pub allow_hash_mismatch: bool,
+    /// Finish an interrupted apply, skipping files that already have their new content
+    pub resume: bool,
+    /// Directory for temporary files instead of the system temporary directory
+    pub temp_dir: Option<PathBuf>,
+    /// Leave directories emptied by removed files in place
+    pub keep_empty_dirs: bool,
+    /// What to do with fully replaced files that were changed locally
+    pub on_conflict: OnConflict,
+    /// Ask before each file is added, changed or removed
+    pub interactive: bool,
+    /// Clear the read-only flag of files that can't be written and retry
+    pub force: bool,
+    /// Threads for copying and writing files, 0 for one per logical core;
+    /// `None` picks a count from the storage type or `DIFFPATCH_IO_THREADS`
+    pub threads: Option<usize>,
+    /// Buffer size in bytes for file reads and writes; `None` uses `DEFAULT_IO_BUFFER_SIZE`
+    pub io_buffer_size: Option<NonZeroUsize>,
+}
+
+impl ApplyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn accept_eula(mut self, accept: bool) -> Self {
+        self.accept_eula = accept;
+        self
+    }
+
+    pub fn eol(mut self, eol: Option<EolMode>) -> Self {
+        self.eol = eol;
+        self
+    }
+
+    pub fn expect_sha256(mut self, hash: Option<String>) -> Self {
+        self.expect_sha256 = hash;
+        self
+    }
+
+    pub fn base(mut self, dir: Option<PathBuf>) -> Self {
+        self.base = dir;
+        self
+    }
+
+    pub fn out(mut self, dir: Option<PathBuf>) -> Self {
+        self.out = dir;
+        self
+    }
+
+    pub fn three_way(mut self, enabled: bool) -> Self {
+        self.three_way = enabled;
+        self
+    }
+
+    pub fn merkle(mut self, enabled: bool) -> Self {
+        self.merkle = enabled;
+        self
+    }
+
+    pub fn dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    pub fn backup(mut self, enabled: bool) -> Self {
+        self.backup = enabled;
+        self
+    }
+
+    pub fn safe(mut self, enabled: bool) -> Self {
+        self.safe = enabled;
+        self
+    }
+
+    pub fn report(mut self, path: Option<PathBuf>) -> Self {
+        self.report = path;
+        self
+    }
+
+    pub fn allow_hash_mismatch(mut self, enabled: bool) -> Self {
+        self.allow_hash_mismatch = enabled;
+        self
+    }
+
+    pub fn resume(mut self, enabled: bool) -> Self {
+        self.resume = enabled;
+        self
+    }
+
+    pub fn temp_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    pub fn keep_empty_dirs(mut self, enabled: bool) -> Self {
+        self.keep_empty_dirs = enabled;
+        self
+    }
+
+    pub fn on_conflict(mut self, policy: OnConflict) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+
+    pub fn interactive(mut self, enabled: bool) -> Self {
+        self.interactive = enabled;
+        self
+    }
+
+    pub fn force(mut self, enabled: bool) -> Self {
+        self.force = enabled;
+        self
+    }
+
+    pub fn threads(mut self, threads: Option<usize>) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn io_buffer_size(mut self, size: Option<NonZeroUsize>) -> Self {
+        self.io_buffer_size = size;
+        self
+    }
+}
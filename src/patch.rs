@@ -1,64 +1,673 @@
-use crate::diff::{DiffChangeTag, DiffType, FileDiff, FileInfo};
-use crate::utils::get_io_thread_count;
-use anyhow::{anyhow, Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{info, warn};
+use crate::backup::{
+    BACKUP_DIR, RESUME_FILE, ROLLBACK_DIR, ResumeState, Stage, VERSION_FILE, backup_before_apply,
+    restore_from,
+};
+use crate::crypto;
+use crate::delta::apply_delta;
+use crate::diff::{
+    BinaryDelta, DiffType, EntryKind, FileDiff, FileInfo, HashAlgorithm, RESUMABLE_COPY_THRESHOLD,
+    Rename, Symlink, TypeChange, calculate_block_hashes, calculate_file_hash,
+    calculate_file_hash_buffered, calculate_reader_hash,
+};
+use crate::manifest::tree_hash;
+use crate::merge::{apply_line_changes, three_way_merge};
+use crate::options::{ApplyOptions, CreateOptions};
+use crate::report::{Action, ApplyReport, Outcomes};
+use crate::review::review_changes;
+use crate::target::{LocalTarget, PatchTarget};
+use crate::tokenize::apply_token_changes;
+use crate::transform::{convert_eol, looks_binary};
+use crate::utils::{
+    DEFAULT_IO_BUFFER_SIZE, StorageKind, build_glob_set, check_contained_path, check_free_space,
+    check_is_directory, collected_failures, confirm_action, confirm_prompt, copy_dir_all,
+    create_seeded_temp_dir, create_temp_dir, file_mode, get_io_thread_count, io_buffer_size,
+    io_thread_count, is_glob_pattern, native_path, portable_path, progress_bar, read_passphrase,
+    retry_with_backoff, same_volume,
+};
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use log::{debug, info, warn};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, hash_map::Entry};
+use std::env;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tempfile::tempdir;
-use zip::{write::FileOptions, ZipWriter};
+use std::time::Instant;
+use walkdir::WalkDir;
+use zip::{ZipWriter, write::FileOptions};
+
+type FileContents = Arc<Mutex<Vec<(String, Option<u32>, Vec<u8>)>>>;
+
+/// Zip directory holding diffs stored outside the patch data
+const DIFFS_DIR: &str = ".diffpatch-diffs";
+
+/// Zip directory holding binary deltas
+const DELTAS_DIR: &str = ".diffpatch-deltas";
+
+/// How files are compressed in the patch archive
+///
+/// Every zip entry records its own method, so patches open whichever was used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// No compression, fastest for already compressed assets
+    Stored,
+    #[default]
+    Deflate,
+    /// Smaller output for text-heavy patches
+    Zstd,
+}
+
+impl Compression {
+    fn method(self) -> zip::CompressionMethod {
+        match self {
+            Compression::Stored => zip::CompressionMethod::Stored,
+            Compression::Deflate => zip::CompressionMethod::Deflated,
+            Compression::Zstd => zip::CompressionMethod::Zstd,
+        }
+    }
+
+    /// Check that a compression level is valid for this method
+    pub fn check_level(self, level: i64) -> Result<()> {
+        let range = match self {
+            Compression::Stored => {
+                return Err(anyhow!(
+                    "Stored files are not compressed, a level does not apply"
+                ));
+            }
+            Compression::Deflate => 0..=9,
+            Compression::Zstd => 1..=22,
+        };
+        if !range.contains(&level) {
+            return Err(anyhow!(
+                "Invalid {:?} compression level {}: expected {} to {}",
+                self,
+                level,
+                range.start(),
+                range.end()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// File a patch is written as, set with `--format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchFormat {
+    /// Self-applying executable: the patch is appended to a copy of the stub
+    #[default]
+    Exe,
+    /// Plain `.dpatch` file with the same layout minus the stub, applied with
+    /// `diffpatch apply-archive` on any OS
+    Archive,
+}
+
+/// Whether an executable is a Windows (PE) binary, which starts with `MZ`
+fn is_windows_executable(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open stub executable: {}", path.display()))?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == *b"MZ")
+}
 
-type FileContents = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+/// What to do with a fully replaced file that was changed locally, set with `--on-conflict`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Replace it, warning about the lost changes
+    #[default]
+    Overwrite,
+    /// Keep the local file and leave it out of the update
+    Skip,
+    /// Ask for each file
+    Prompt,
+}
+
+/// Shown when the output executable stays locked after all retries
+const ANTIVIRUS_HINT: &str = "The file may be locked by antivirus software scanning the new \
+executable; consider adding an exclusion for the output directory or retrying with a larger \
+--copy-retries";
 
+/// Version of the `PatchData` layout written by this build
+///
+/// Bump it when a change would make older builds misread new patches.
+/// Patches from before it was recorded read as 0.
+pub const PATCH_FORMAT_VERSION: u32 = 1;
 
 /// Patch data structure
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PatchData {
+    #[serde(default)]
+    pub format_version: u32,
     pub check_files: Vec<String>,
+    /// Expected SHA256 of check files whose content is pinned, by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub check_hashes: BTreeMap<String, String>,
     pub added_files: Vec<FileInfo>,
     pub modified_files: Vec<FileInfo>,
     pub modified_diffs: Vec<FileDiff>,
+    #[serde(default)]
+    pub binary_deltas: Vec<BinaryDelta>,
+    #[serde(default)]
+    pub symlinks: Vec<Symlink>,
+    #[serde(default)]
+    pub renamed_files: Vec<Rename>,
     pub removed_files: Vec<PathBuf>,
+    #[serde(default)]
+    pub type_changes: Vec<TypeChange>,
+    #[serde(default)]
+    pub eula: Option<String>,
+    #[serde(default)]
+    pub removed_globs: Vec<String>,
+    /// Diff changes are stored as zip entries, `modified_diffs` only indexes them
+    #[serde(default)]
+    pub diffs_in_zip: bool,
+    /// Algorithm of the file hashes; patches without it used SHA256
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    pub metadata: PatchMetadata,
+    /// Version the directory must have been patched to before this patch applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_version: Option<String>,
+}
+
+/// What a patch is and when it was made, shown before it is applied
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PatchMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Creation time in UTC, as RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl fmt::Display for PatchMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("Patch version", &self.patch_version),
+            ("Author", &self.author),
+            ("Created", &self.timestamp),
+            ("Description", &self.description),
+        ];
+        for (label, value) in fields {
+            if let Some(value) = value {
+                writeln!(f, "{}: {}", label, value)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl PatchData {
+    /// Parse patch data, refusing layouts newer than this build understands
+    ///
+    /// The version is read on its own first, so a newer patch gets a clear
+    /// error instead of failing (or succeeding wrongly) on changed fields.
+    pub fn from_json(bytes: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Version {
+            #[serde(default)]
+            format_version: u32,
+        }
+
+        let Version { format_version } =
+            serde_json::from_slice(bytes).context("Failed to deserialize patch data")?;
+        if format_version > PATCH_FORMAT_VERSION {
+            return Err(anyhow!(
+                "This patch was made by a newer version of diffpatch (patch data format {}, \
+                 supported up to {}). Update diffpatch to apply it.",
+                format_version,
+                PATCH_FORMAT_VERSION
+            ));
+        }
+        let mut patch_data: Self =
+            serde_json::from_slice(bytes).context("Failed to deserialize patch data")?;
+        patch_data.map_paths(native_path);
+        patch_data.check_paths()?;
+        Ok(patch_data)
+    }
+
+    /// Fail if a path the patch writes or removes could reach outside the directory
+    pub fn check_paths(&self) -> Result<()> {
+        let removed = self
+            .removed_files
+            .iter()
+            .chain(self.renamed_files.iter().map(|r| &r.from))
+            .chain(self.type_changes.iter().map(|c| &c.relative_path))
+            .chain(self.type_changes.iter().flat_map(|c| &c.removed))
+            .map(PathBuf::as_path)
+            .chain(self.removed_globs.iter().map(Path::new));
+        let copied_from = self
+            .added_files
+            .iter()
+            .chain(self.modified_files.iter())
+            .filter_map(|f| f.same_as.as_deref());
+        for path in self.written_paths().chain(removed).chain(copied_from) {
+            check_contained_path(path).context("Patch is invalid")?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite every stored path, e.g. between `/` and the native separator
+    ///
+    /// Patches store paths with `/` so they apply on any platform.
+    pub fn map_paths(&mut self, map: fn(&Path) -> PathBuf) {
+        for file_info in self
+            .added_files
+            .iter_mut()
+            .chain(self.modified_files.iter_mut())
+        {
+            file_info.map_paths(map);
+        }
+        for file_diff in &mut self.modified_diffs {
+            file_diff.relative_path = map(&file_diff.relative_path);
+        }
+        for delta in &mut self.binary_deltas {
+            delta.relative_path = map(&delta.relative_path);
+        }
+        for link in &mut self.symlinks {
+            link.relative_path = map(&link.relative_path);
+            link.target = map(&link.target);
+        }
+        for rename in &mut self.renamed_files {
+            rename.from = map(&rename.from);
+            rename.to = map(&rename.to);
+        }
+        for path in &mut self.removed_files {
+            *path = map(path);
+        }
+        for change in &mut self.type_changes {
+            change.relative_path = map(&change.relative_path);
+            for path in &mut change.removed {
+                *path = map(path);
+            }
+        }
+        // Check files are looked up in `check_hashes` by the same string
+        let map_str = |path: &str| map(Path::new(path)).to_string_lossy().into_owned();
+        for file in &mut self.check_files {
+            *file = map_str(file);
+        }
+        self.check_hashes = std::mem::take(&mut self.check_hashes)
+            .into_iter()
+            .map(|(path, hash)| (map_str(&path), hash))
+            .collect();
+    }
+
     pub fn from_diffs(diffs: Vec<DiffType>, check_files: Vec<String>) -> Self {
         let mut added_files = Vec::new();
         let mut modified_files = Vec::new();
         let mut modified_diffs = Vec::new();
+        let mut binary_deltas = Vec::new();
+        let mut symlinks = Vec::new();
+        let mut renamed_files = Vec::new();
         let mut removed_files = Vec::new();
+        let mut type_changes = Vec::new();
 
         for diff in diffs {
             match diff {
                 DiffType::Added(file_info) => added_files.push(file_info),
                 DiffType::Modified(file_info) => modified_files.push(file_info),
                 DiffType::ModifiedDiff(file_diff) => modified_diffs.push(file_diff),
+                DiffType::BinaryDelta(delta) => binary_deltas.push(delta),
+                DiffType::Symlink(link) => symlinks.push(link),
+                DiffType::Renamed(rename) => renamed_files.push(rename),
                 DiffType::Removed(path) => removed_files.push(path),
+                DiffType::TypeChanged(change) => type_changes.push(change),
             }
         }
 
+        // Directories are scanned in no particular order; sorting makes the same
+        // inputs give byte-identical patches
+        added_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        modified_files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        modified_diffs.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        binary_deltas.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        symlinks.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        renamed_files.sort_by(|a, b| a.to.cmp(&b.to));
+        removed_files.sort();
+        type_changes.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
         PatchData {
+            format_version: PATCH_FORMAT_VERSION,
             check_files,
+            check_hashes: BTreeMap::new(),
             added_files,
             modified_files,
             modified_diffs,
+            binary_deltas,
+            symlinks,
+            renamed_files,
             removed_files,
+            type_changes,
+            eula: None,
+            removed_globs: Vec::new(),
+            diffs_in_zip: false,
+            hash_algorithm: HashAlgorithm::default(),
+            metadata: PatchMetadata::default(),
+            requires_version: None,
+        }
+    }
+
+    /// Point files with identical content at a single stored copy
+    ///
+    /// The first added or modified file with a given hash and size keeps its
+    /// zip entry; the others record it in `same_as` and are copied from it when
+    /// the patch is applied. Returns how many files no longer need an entry.
+    pub fn deduplicate(&mut self) -> usize {
+        let mut stored: HashMap<(String, u64), PathBuf> = HashMap::new();
+        let mut duplicates = 0;
+        for file_info in self
+            .added_files
+            .iter_mut()
+            .chain(self.modified_files.iter_mut())
+            .filter(|f| f.link_target.is_none())
+        {
+            match stored.entry((file_info.hash.clone(), file_info.size)) {
+                Entry::Occupied(entry) => {
+                    file_info.same_as = Some(entry.get().clone());
+                    duplicates += 1;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(file_info.relative_path.clone());
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// Paths of every file and link the patch writes
+    pub fn written_paths(&self) -> impl Iterator<Item = &Path> {
+        self.added_files
+            .iter()
+            .chain(self.modified_files.iter())
+            .map(|f| f.relative_path.as_path())
+            .chain(
+                self.modified_diffs
+                    .iter()
+                    .map(|d| d.relative_path.as_path()),
+            )
+            .chain(self.binary_deltas.iter().map(|d| d.relative_path.as_path()))
+            .chain(self.symlinks.iter().map(|l| l.relative_path.as_path()))
+            .chain(self.renamed_files.iter().map(|r| r.to.as_path()))
+    }
+
+    /// Paths listed in more than one of the added, modified, diffed and removed sets
+    ///
+    /// Applying such a patch would process the path twice (e.g. extract a full
+    /// file and then apply a diff on top), so these patches are rejected.
+    pub fn conflicting_paths(&self) -> Vec<PathBuf> {
+        let mut seen: HashMap<&Path, usize> = HashMap::new();
+        for path in self
+            .written_paths()
+            .chain(self.removed_files.iter().map(PathBuf::as_path))
+            .chain(self.renamed_files.iter().map(|r| r.from.as_path()))
+        {
+            *seen.entry(path).or_default() += 1;
+        }
+
+        let mut conflicts: Vec<PathBuf> = seen
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(path, _)| path.to_path_buf())
+            .collect();
+        conflicts.sort();
+        conflicts
+    }
+}
+
+/// Zip entry path of a diff stored outside the patch data
+fn diff_entry_name(relative_path: &Path) -> PathBuf {
+    let mut name = relative_path.as_os_str().to_owned();
+    name.push(".json");
+    Path::new(DIFFS_DIR).join(name)
+}
+
+/// Zip entry path of a binary delta
+fn delta_entry_name(relative_path: &Path) -> PathBuf {
+    let mut name = relative_path.as_os_str().to_owned();
+    name.push(".delta");
+    Path::new(DELTAS_DIR).join(name)
+}
+
+/// Rebuild a file shipped as a binary delta into `output`
+///
+/// The delta only applies to the exact original file, so the file in the
+/// target is checked first and the rebuilt file is checked afterwards.
+pub fn rebuild_from_delta<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    target: &dyn PatchTarget,
+    delta: &BinaryDelta,
+    output: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> Result<()> {
+    if !has_content(
+        target,
+        &delta.relative_path,
+        &delta.original_hash,
+        algorithm,
+    ) {
+        return Err(anyhow!(
+            "Cannot apply binary delta: {} is not the version the patch was made against",
+            delta.relative_path.display()
+        ));
+    }
+
+    let name = delta_entry_name(&delta.relative_path);
+    let mut entry = archive
+        .by_name(&portable_path(&name).to_string_lossy())
+        .with_context(|| format!("Binary delta missing from patch: {}", name.display()))?;
+    let mut data = Vec::new();
+    entry
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read binary delta: {}", name.display()))?;
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let mut original = target
+        .open(&delta.relative_path)
+        .with_context(|| format!("Failed to open file: {}", delta.relative_path.display()))?;
+    apply_delta(&mut original, &data, output, buffer_size)?;
+    if calculate_file_hash_buffered(output, algorithm, buffer_size)? != delta.hash {
+        return Err(anyhow!(
+            "Binary delta produced the wrong content for {}",
+            delta.relative_path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Read a diff stored as a zip entry
+pub fn read_stored_diff<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    relative_path: &Path,
+) -> Result<FileDiff> {
+    let name = diff_entry_name(relative_path);
+    let entry = archive
+        .by_name(&portable_path(&name).to_string_lossy())
+        .with_context(|| format!("Patch is missing the diff for {}", relative_path.display()))?;
+    serde_json::from_reader(BufReader::new(entry))
+        .with_context(|| format!("Failed to parse diff for {}", relative_path.display()))
+}
+
+/// Every diff of a patch with its changes, read from the payload if stored there
+pub fn load_file_diffs(
+    patch_file: &Path,
+    patch_data: &PatchData,
+    payload: PayloadRegion,
+) -> Result<Vec<FileDiff>> {
+    if !patch_data.diffs_in_zip {
+        return Ok(patch_data.modified_diffs.clone());
+    }
+    let temp_dir = create_temp_dir(None)?;
+    let zip_path = temp_dir.path().join("content.zip");
+    copy_payload_region(patch_file, payload, &zip_path, DEFAULT_IO_BUFFER_SIZE)?;
+    let file = File::open(&zip_path).context("Failed to open zip file")?;
+    let mut archive = zip::ZipArchive::new(file).context("Failed to read zip archive")?;
+    patch_data
+        .modified_diffs
+        .iter()
+        .map(|d| read_stored_diff(&mut archive, &d.relative_path))
+        .collect()
+}
+
+/// Check that a patch file is well-formed, e.g. before it is distributed
+///
+/// The trailer, payload checksum and patch data are read as for an apply, and
+/// a failure there is returned as an error. Every zip entry is then
+/// decompressed and the entries are compared with the patch data; the
+/// problems found are returned, so an empty list means the patch is sound.
+pub fn verify_patch_file(patch_file: &Path) -> Result<Vec<String>> {
+    let (patch_data, payload) = extract_patch_data(patch_file)?;
+    let temp_dir = create_temp_dir(None)?;
+    let zip_path = temp_dir.path().join("content.zip");
+    copy_payload_region(patch_file, payload, &zip_path, DEFAULT_IO_BUFFER_SIZE)?;
+    let file = File::open(&zip_path).context("Failed to open zip file")?;
+    let mut archive = zip::ZipArchive::new(file)
+        .context("Invalid patch file: the payload is not a zip archive")?;
+
+    let mut problems = Vec::new();
+    let mut entries = HashSet::new();
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(entry) => entry,
+            Err(e) => {
+                problems.push(format!("unreadable zip entry #{}: {}", i, e));
+                continue;
+            }
+        };
+        let name = entry.name().to_string();
+        // Reading to the end also checks the entry's CRC
+        if let Err(e) = std::io::copy(&mut entry, &mut std::io::sink()) {
+            problems.push(format!("does not decompress: {} ({})", name, e));
+        }
+        if !entry.is_dir() {
+            entries.insert(name);
         }
     }
+
+    let entry_name = |path: &Path| portable_path(path).to_string_lossy().into_owned();
+    let full_files = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter());
+    let mut expected: HashSet<String> = full_files
+        .clone()
+        .filter(|f| f.same_as.is_none())
+        .map(|f| entry_name(&f.relative_path))
+        .chain(
+            patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| entry_name(&delta_entry_name(&d.relative_path))),
+        )
+        .collect();
+    if patch_data.diffs_in_zip {
+        expected.extend(
+            patch_data
+                .modified_diffs
+                .iter()
+                .map(|d| entry_name(&diff_entry_name(&d.relative_path))),
+        );
+    }
+
+    let mut missing: Vec<&String> = expected.difference(&entries).collect();
+    missing.sort();
+    problems.extend(
+        missing
+            .into_iter()
+            .map(|name| format!("missing from the payload: {}", name)),
+    );
+    for file_info in full_files {
+        if let Some(same_as) = &file_info.same_as
+            && !expected.contains(&entry_name(same_as))
+        {
+            problems.push(format!(
+                "duplicate of a file the patch does not store: {} (same as {})",
+                file_info.relative_path.display(),
+                same_as.display()
+            ));
+        }
+    }
+    let mut unreferenced: Vec<&String> = entries.difference(&expected).collect();
+    unreferenced.sort();
+    problems.extend(
+        unreferenced
+            .into_iter()
+            .map(|name| format!("not referenced by the patch data: {}", name)),
+    );
+    problems.extend(
+        patch_data
+            .conflicting_paths()
+            .iter()
+            .map(|path| format!("listed more than once: {}", path.display())),
+    );
+    Ok(problems)
+}
+
+/// Find the files under a directory that match any of the given glob patterns
+pub fn find_glob_matches(dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let files = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.path().strip_prefix(dir).ok().map(Path::to_path_buf));
+    filter_glob_matches(files, patterns)
+}
+
+/// Relative paths matching any of the glob patterns, leaving out diffpatch's own files
+fn filter_glob_matches(
+    paths: impl IntoIterator<Item = PathBuf>,
+    patterns: &[String],
+) -> Result<Vec<PathBuf>> {
+    let glob_set = build_glob_set(patterns)?;
+    Ok(paths
+        .into_iter()
+        .filter(|relative_path| {
+            !relative_path.starts_with(BACKUP_DIR)
+                && !relative_path.starts_with(ROLLBACK_DIR)
+                && relative_path != Path::new(RESUME_FILE)
+        })
+        .filter(|relative_path| {
+            let normalized = relative_path.to_string_lossy().replace('\\', "/");
+            glob_set.is_match(normalized)
+        })
+        .collect())
 }
 
-/// Create a patch file
+/// Create a patch file, returning the path it was written to
 pub fn create_patch(
     source_dir: &Path,
     target_dir: &Path,
     output_file: &Path,
     diffs: Vec<DiffType>,
-    check_files: Vec<String>,
-) -> Result<()> {
+    options: &CreateOptions,
+) -> Result<PathBuf> {
+    // Encryption salts and nonces must stay random
+    if options.seed.is_some() && options.passphrase.is_some() {
+        return Err(anyhow!("A seed cannot be used for an encrypted patch"));
+    }
+
     // Determine the final output path.
     // If output_file is just a filename, it will be placed in the source directory.
     // Otherwise, it will be created at the specified path.
@@ -68,91 +677,212 @@ pub fn create_patch(
         output_file.to_path_buf()
     };
 
-    // Ensure the output file has a .exe extension
-    if target_output_file.extension().and_then(|s| s.to_str()) != Some("exe") {
-        target_output_file.set_extension("exe");
+    // The patch runs the stub it is appended to, by default this executable
+    let stub = match (options.format, &options.stub) {
+        (PatchFormat::Archive, _) => None,
+        (PatchFormat::Exe, Some(stub)) => Some(stub.clone()),
+        (PatchFormat::Exe, None) => {
+            Some(std::env::current_exe().context("Failed to get current executable path")?)
+        }
+    };
+
+    // Windows patches need the .exe extension to run, Linux and macOS stubs keep the name given
+    let extension = match &stub {
+        None => Some("dpatch"),
+        Some(stub) if is_windows_executable(stub)? => Some("exe"),
+        Some(_) => None,
+    };
+    if let Some(extension) = extension
+        && target_output_file.extension().and_then(|s| s.to_str()) != Some(extension)
+    {
+        target_output_file.set_extension(extension);
     }
 
     info!("Creating patch file: {}", target_output_file.display());
 
     // Create temporary directory to store patch data
-    let temp_dir = tempdir().context("Failed to create temporary directory")?;
+    let temp_dir = match options.seed {
+        Some(seed) => create_seeded_temp_dir(options.temp_dir.as_deref(), seed)?,
+        None => create_temp_dir(options.temp_dir.as_deref())?,
+    };
     let patch_data_path = temp_dir.path().join("patch_data.json");
     let content_dir = temp_dir.path().join("content");
     fs::create_dir(&content_dir).context("Failed to create content directory")?;
 
     // Save patch data
-    let patch_data = PatchData::from_diffs(diffs, check_files);
+    let mut patch_data = PatchData::from_diffs(diffs, options.check_files.clone());
+    patch_data.check_hashes = options.check_hashes.clone();
+    patch_data.eula = options.eula.clone();
+    patch_data.hash_algorithm = options.hash_algorithm;
+    patch_data.metadata = PatchMetadata {
+        patch_version: options.patch_version.clone(),
+        author: options.author.clone(),
+        timestamp: Some(creation_time()?.strftime("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        description: options.description.clone(),
+    };
+    patch_data.requires_version = options.requires_version.clone();
+    // Large files get block hashes so they can be copied resumably and verified
+    for file_info in patch_data
+        .added_files
+        .iter_mut()
+        .chain(patch_data.modified_files.iter_mut())
+        .filter(|f| f.size >= RESUMABLE_COPY_THRESHOLD)
+    {
+        file_info.block_hashes = calculate_block_hashes(
+            &target_dir.join(&file_info.relative_path),
+            io_buffer_size(options.io_buffer_size),
+        )?;
+    }
+    let duplicates = patch_data.deduplicate();
+    if duplicates > 0 {
+        info!("Storing {} duplicate files once", duplicates);
+    }
+
+    // The files are copied in, then compressed into an archive of at most about their size
+    let content_size: u64 = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .filter(|f| f.same_as.is_none())
+        .map(|f| f.size)
+        .sum();
+    check_free_space(temp_dir.path(), content_size.saturating_mul(2))
+        .context("Use --temp-dir to put temporary files on a volume with more space")?;
+    patch_data.removed_globs = options
+        .force_remove
+        .iter()
+        .filter(|p| is_glob_pattern(p))
+        .map(|p| p.to_string_lossy().replace('\\', "/"))
+        .collect();
+    patch_data.check_paths()?;
+
+    // Move diff changes into the zip, leaving only an index in the patch data
+    if options.diffs_in_zip {
+        for file_diff in &mut patch_data.modified_diffs {
+            let entry_path = content_dir.join(diff_entry_name(&file_diff.relative_path));
+            if let Some(parent) = entry_path.parent() {
+                fs::create_dir_all(parent).context("Failed to create diff directory")?;
+            }
+            let diff_json = serde_json::to_vec(file_diff).context("Failed to serialize diff")?;
+            fs::write(&entry_path, diff_json)
+                .with_context(|| format!("Failed to write diff: {}", entry_path.display()))?;
+            file_diff.changes.clear();
+        }
+        patch_data.diffs_in_zip = true;
+    }
+
+    // Binary deltas always live in the zip
+    for delta in &patch_data.binary_deltas {
+        let entry_path = content_dir.join(delta_entry_name(&delta.relative_path));
+        if let Some(parent) = entry_path.parent() {
+            fs::create_dir_all(parent).context("Failed to create delta directory")?;
+        }
+        fs::write(&entry_path, &delta.delta)
+            .with_context(|| format!("Failed to write delta: {}", entry_path.display()))?;
+    }
+
+    patch_data.map_paths(portable_path);
     let patch_json =
         serde_json::to_string_pretty(&patch_data).context("Failed to serialize patch data")?;
     fs::write(&patch_data_path, patch_json).context("Failed to write patch data")?;
 
-    // Copy added and modified files
-    let pb =
-        ProgressBar::new((patch_data.added_files.len() + patch_data.modified_files.len()) as u64);
-    pb.set_message("Copying files...");
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-            )
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-
-    // Create a list of all files to copy
+    // Create a list of all files to copy, duplicates are copied from their original
     let files_to_copy: Vec<&FileInfo> = patch_data
         .added_files
         .iter()
         .chain(patch_data.modified_files.iter())
+        .filter(|f| f.same_as.is_none())
         .collect();
 
+    // Copy added and modified files
+    let file_count = patch_data.added_files.len() + patch_data.modified_files.len();
+    let pb = progress_bar(file_count as u64, "Copying files...");
+
     // Use atomic counter for progress
     let progress_counter = Arc::new(Mutex::new(0));
+    let errors = Mutex::new(Vec::new());
 
-    // Perform copying in parallel
-    files_to_copy.par_iter().for_each(|file_info| {
-        let source_file = target_dir.join(&file_info.relative_path);
-        let dest_file = content_dir.join(&file_info.relative_path);
-
-        // Create target directory
-        if let Some(parent) = dest_file.parent()
-            && fs::create_dir_all(parent).is_err() {
-                return; // Skip this file on error
+    // Perform copying in parallel, on a pool limited like the other I/O steps
+    let pool = io_pool(get_io_thread_count(target_dir, options.threads));
+    pool.install(|| {
+        files_to_copy.par_iter().for_each(|file_info| {
+            let source_file = target_dir.join(&file_info.relative_path);
+            let dest_file = content_dir.join(&file_info.relative_path);
+
+            // Create target directory
+            if let Some(parent) = dest_file.parent()
+                && let Err(e) = fs::create_dir_all(parent)
+            {
+                errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", parent.display(), e));
+                return;
             }
 
-        // Copy file
-        if fs::copy(&source_file, &dest_file).is_err() {
-            return; // Skip this file on error
-        }
+            // Copy file
+            if let Err(e) = fs::copy(&source_file, &dest_file) {
+                errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", source_file.display(), e));
+                return;
+            }
 
-        // Update progress
-        let mut counter = progress_counter.lock().unwrap();
-        *counter += 1;
-        pb.set_position(*counter);
+            // Update progress
+            let mut counter = progress_counter.lock().unwrap();
+            *counter += 1;
+            pb.set_position(*counter);
+        });
     });
 
     pb.finish();
+    collected_failures(
+        "copy",
+        errors.into_inner().unwrap(),
+        options.continue_on_error,
+    )?;
     info!("File copying complete");
 
     // Create ZIP archive
     let zip_path = temp_dir.path().join("patch_content.zip");
-    create_zip_archive(&content_dir, &zip_path)?;
-
-    // Get current executable path
-    let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
-
-    // Copy current executable to target directory
-    fs::copy(&current_exe, &target_output_file).with_context(|| {
-        format!(
-            "Failed to copy executable from {} to {}",
-            current_exe.display(),
-            target_output_file.display()
-        )
-    })?;
+    create_zip_archive(&content_dir, &zip_path, options)?;
+
+    match &stub {
+        Some(stub) => {
+            // Copy the stub executable to target directory
+            retry_with_backoff("Copying executable", options.copy_retries, || {
+                fs::copy(stub, &target_output_file)
+            })
+            .with_context(|| {
+                format!(
+                    "Failed to copy executable from {} to {}. {}",
+                    stub.display(),
+                    target_output_file.display(),
+                    ANTIVIRUS_HINT
+                )
+            })?;
+        }
+        // The trailer is found from the end of the file, so an archive is the same without a stub
+        None => {
+            File::create(&target_output_file).with_context(|| {
+                format!(
+                    "Failed to create patch file: {}",
+                    target_output_file.display()
+                )
+            })?;
+        }
+    }
 
     // Append patch data and content to the end of executable
-    append_data_to_exe(&target_output_file, &patch_data_path, &zip_path)?;
+    let stub_size = file_size(&target_output_file)?;
+    append_data_to_exe(
+        &target_output_file,
+        &patch_data_path,
+        &zip_path,
+        options.copy_retries,
+        options.passphrase.as_deref(),
+    )?;
 
     info!("Patch file created successfully:");
     info!("  Location: {}", target_output_file.display());
@@ -160,18 +890,65 @@ pub fn create_patch(
     info!("  Added: {} files", patch_data.added_files.len());
     info!("  Modified: {} files", patch_data.modified_files.len());
     info!("  Deleted: {} files", patch_data.removed_files.len());
+    if !patch_data.type_changes.is_empty() {
+        info!("  Type changed: {} paths", patch_data.type_changes.len());
+    }
 
-    Ok(())
+    // Stored content includes the diffs and deltas written to the zip, not just whole files
+    let stored_size: u64 = WalkDir::new(&content_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let compressed_size = file_size(&zip_path)?;
+    info!("Size:");
+    info!(
+        "  Patch file: {}",
+        HumanBytes(file_size(&target_output_file)?)
+    );
+    info!("  Content: {} uncompressed", HumanBytes(stored_size));
+    info!(
+        "  Compressed: {} ({})",
+        HumanBytes(compressed_size),
+        compression_ratio(compressed_size, stored_size)
+    );
+    info!("  Patch data: {}", HumanBytes(file_size(&patch_data_path)?));
+    if stub.is_some() {
+        info!("  Stub executable: {}", HumanBytes(stub_size));
+    }
+
+    Ok(target_output_file)
+}
+
+fn file_size(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("Failed to read size of {}", path.display()))?
+        .len())
+}
+
+/// Compressed size as a percentage of the original
+fn compression_ratio(compressed: u64, original: u64) -> String {
+    if original == 0 {
+        return "nothing to compress".to_string();
+    }
+    format!(
+        "{:.1}% of original",
+        compressed as f64 * 100.0 / original as f64
+    )
 }
 
 /// Create ZIP archive
-fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
+fn create_zip_archive(source_dir: &Path, zip_path: &Path, create: &CreateOptions) -> Result<()> {
     let file = File::create(zip_path).context("Failed to create zip file")?;
     let writer = BufWriter::new(file);
     let mut zip = ZipWriter::new(writer);
+    // A fixed time keeps entries identical between runs; apply doesn't use it
     let options = FileOptions::<()>::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .unix_permissions(0o755);
+        .compression_method(create.compression.method())
+        .compression_level(create.compression_level)
+        .last_modified_time(zip::DateTime::default());
 
     // Collect all files from the directory in parallel
     let files: Vec<_> = walkdir::WalkDir::new(source_dir)
@@ -182,83 +959,85 @@ fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
 
     if !files.is_empty() {
         info!("Compressing {} files...", files.len());
-        let pb = ProgressBar::new(files.len() as u64);
-        pb.set_message("Compressing files...");
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-                )
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+        let pb = progress_bar(files.len() as u64, "Compressing files...");
 
         // Create a thread pool with limited threads to avoid I/O contention
         let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(get_io_thread_count())
+            .num_threads(get_io_thread_count(source_dir, create.threads))
             .build()
             .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
 
         // Process files in parallel to prepare content
-        let file_contents: FileContents =
-            Arc::new(Mutex::new(Vec::with_capacity(files.len())));
+        let file_contents: FileContents = Arc::new(Mutex::new(Vec::with_capacity(files.len())));
         let progress_counter = Arc::new(Mutex::new(0));
+        let errors = Mutex::new(Vec::new());
 
         pool.install(|| {
             files.par_iter().for_each(|entry| {
                 let path = entry.path();
-                let relative_path = match path.strip_prefix(source_dir) {
-                    Ok(rel_path) => match rel_path.to_str() {
-                        Some(path_str) => path_str.to_string(),
-                        None => return, // Skip files with invalid UTF-8 paths
-                    },
-                    Err(_) => return, // Skip if we can't get relative path
+                let failed = |error: &dyn fmt::Display| {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {}", path.display(), error));
+                };
+                let Some(relative_path) = path
+                    .strip_prefix(source_dir)
+                    .ok()
+                    .map(portable_path)
+                    .and_then(|p| p.to_str().map(str::to_string))
+                else {
+                    failed(&"path is not valid UTF-8");
+                    return;
                 };
 
                 // Read file content with buffered IO
                 let mut buffer = Vec::new();
                 let result = (|| -> Result<(), std::io::Error> {
                     let file = File::open(path)?;
-                    let mut reader = BufReader::with_capacity(65536, file);
+                    let mut reader =
+                        BufReader::with_capacity(io_buffer_size(create.io_buffer_size), file);
                     reader.read_to_end(&mut buffer)?;
                     Ok(())
                 })();
 
-                if result.is_ok() {
-                    let mut contents = file_contents.lock().unwrap();
-                    contents.push((relative_path, buffer));
+                match result {
+                    Ok(()) => {
+                        let mode = entry.metadata().ok().and_then(|m| file_mode(&m));
+                        let mut contents = file_contents.lock().unwrap();
+                        contents.push((relative_path, mode, buffer));
 
-                    // Update progress
-                    let mut counter = progress_counter.lock().unwrap();
-                    *counter += 1;
-                    pb.set_position(*counter);
+                        // Update progress
+                        let mut counter = progress_counter.lock().unwrap();
+                        *counter += 1;
+                        pb.set_position(*counter);
+                    }
+                    Err(e) => failed(&e),
                 }
             });
         });
-
-        // Extract contents from the mutex
-        let contents = Arc::try_unwrap(file_contents)
+        collected_failures(
+            "read",
+            errors.into_inner().unwrap(),
+            create.continue_on_error,
+        )?;
+
+        // Extract contents from the mutex, in path order rather than the order they were read in
+        let mut contents = Arc::try_unwrap(file_contents)
             .unwrap()
             .into_inner()
             .unwrap();
+        contents.sort_by(|a, b| a.0.cmp(&b.0));
 
         pb.finish();
         info!("File reading complete");
 
         // Add files to the zip sequentially (ZipWriter is not thread-safe)
         info!("Creating archive...");
-        let zip_pb = ProgressBar::new(contents.len() as u64);
-        zip_pb.set_message("Creating archive...");
-        zip_pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-                )
-                .unwrap()
-                .progress_chars("#>-"),
-        );
+        let zip_pb = progress_bar(contents.len() as u64, "Creating archive...");
 
-        for (i, (relative_path, buffer)) in contents.into_iter().enumerate() {
+        for (i, (relative_path, mode, buffer)) in contents.into_iter().enumerate() {
+            let options = options.unix_permissions(mode.unwrap_or(0o644));
             zip.start_file(&relative_path, options)
                 .with_context(|| format!("Failed to start zip file: {}", relative_path))?;
 
@@ -276,12 +1055,200 @@ fn create_zip_archive(source_dir: &Path, zip_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Time recorded as the patch's creation time
+///
+/// `SOURCE_DATE_EPOCH` (seconds since the Unix epoch) overrides the current
+/// time, so reproducible builds can produce byte-identical patches.
+fn creation_time() -> Result<jiff::Timestamp> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|seconds| jiff::Timestamp::from_second(seconds).ok())
+            .ok_or_else(|| anyhow!("Invalid SOURCE_DATE_EPOCH: {}", value)),
+        Err(_) => Ok(jiff::Timestamp::now()),
+    }
+}
+
+/// Marker ending the data appended to a patch executable
+pub const END_MARKER: &[u8; 9] = b"PATCH_FMT";
+
+/// Marker of patches written before the trailer had a format version
+pub const LEGACY_END_MARKER: &[u8; 9] = b"PATCH_END";
+
+/// Version of the trailer written in front of `END_MARKER`
+///
+/// 1 is the legacy trailer (sizes only), 2 adds flags and a payload checksum,
+/// 3 the length and hash of the stub executable.
+pub const TRAILER_VERSION: u8 = 3;
+
+/// Trailer flag: the patch data and payload are encrypted
+const FLAG_ENCRYPTED: u8 = 1;
+
+/// Fixed-size fields at the end of a patch file
+///
+/// Version 3 is laid out as `[salt][stub hash][stub size][checksum][patch size]
+/// [zip size][flags][version][marker]`, the salt only being there for encrypted
+/// patches. Version 2 has no stub hash and size.
+struct Trailer {
+    version: u8,
+    salt: Option<[u8; crypto::SALT_LEN]>,
+    /// Size and SHA256 of the stub executable in front of the patch data
+    stub: Option<(u64, [u8; 32])>,
+    checksum: Option<[u8; 32]>,
+    patch_data_size: u64,
+    zip_data_size: u64,
+    len: u64,
+}
+
+impl Trailer {
+    /// Read the trailer backwards from the end of the file
+    fn read(file: &mut File, file_size: u64) -> Result<Self> {
+        // Check if file is large enough to contain patch data
+        if file_size < 25 {
+            // 8 (patch_size) + 8 (zip_size) + 9 (marker)
+            return Err(anyhow!("Invalid patch file: too small"));
+        }
+
+        // Read file end
+        let mut end_marker = [0u8; 9];
+        file.seek(std::io::SeekFrom::End(-9))
+            .context("Failed to seek to end marker")?;
+        file.read_exact(&mut end_marker)
+            .context("Failed to read end marker")?;
+
+        if &end_marker == LEGACY_END_MARKER {
+            let (patch_data_size, zip_data_size) = read_sizes(file, 25)?;
+            return Ok(Self {
+                version: 1,
+                salt: None,
+                stub: None,
+                checksum: None,
+                patch_data_size,
+                zip_data_size,
+                len: 25,
+            });
+        }
+        if &end_marker != END_MARKER {
+            return Err(anyhow!("Invalid patch file: missing end marker"));
+        }
+
+        let mut version_and_flags = [0u8; 2];
+        file.seek(std::io::SeekFrom::End(-11))
+            .context("Failed to seek to trailer version")?;
+        file.read_exact(&mut version_and_flags)
+            .context("Failed to read trailer version")?;
+        let [flags, version] = version_and_flags;
+        if version > TRAILER_VERSION {
+            return Err(anyhow!(
+                "This patch was made by a newer version of diffpatch (patch file format {}, \
+                 supported up to {}). Update diffpatch to apply it.",
+                version,
+                TRAILER_VERSION
+            ));
+        }
+
+        // 32 (checksum) + 8 (patch_size) + 8 (zip_size) + 1 (flags) + 1 (version) + 9 (marker)
+        let mut len = 59;
+        if version >= 3 {
+            // 32 (stub hash) + 8 (stub size)
+            len += 40;
+        }
+        if flags & FLAG_ENCRYPTED != 0 {
+            len += crypto::SALT_LEN as u64;
+        }
+        if file_size < len {
+            return Err(anyhow!("Invalid patch file: too small"));
+        }
+        let (patch_data_size, zip_data_size) = read_sizes(file, 27)?;
+
+        let mut checksum = [0u8; 32];
+        file.seek(std::io::SeekFrom::End(-59))
+            .context("Failed to seek to payload checksum")?;
+        file.read_exact(&mut checksum)
+            .context("Failed to read payload checksum")?;
+
+        let stub = if version >= 3 {
+            let mut stub_data = [0u8; 40];
+            file.seek(std::io::SeekFrom::End(-99))
+                .context("Failed to seek to stub hash")?;
+            file.read_exact(&mut stub_data)
+                .context("Failed to read stub hash")?;
+            let size = u64::from_le_bytes(stub_data[32..40].try_into().unwrap());
+            Some((size, stub_data[..32].try_into().unwrap()))
+        } else {
+            None
+        };
+
+        let salt = if flags & FLAG_ENCRYPTED != 0 {
+            let mut salt = [0u8; crypto::SALT_LEN];
+            file.seek(std::io::SeekFrom::End(-(len as i64)))
+                .context("Failed to seek to encryption salt")?;
+            file.read_exact(&mut salt)
+                .context("Failed to read encryption salt")?;
+            Some(salt)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version,
+            salt,
+            stub,
+            checksum: Some(checksum),
+            patch_data_size,
+            zip_data_size,
+            len,
+        })
+    }
+}
+
+/// Read the patch data and zip sizes stored `from_end` bytes before the end
+fn read_sizes(file: &mut File, from_end: i64) -> Result<(u64, u64)> {
+    let mut size_data = [0u8; 16];
+    file.seek(std::io::SeekFrom::End(-from_end))
+        .context("Failed to seek to size data")?;
+    file.read_exact(&mut size_data)
+        .context("Failed to read size data")?;
+
+    let patch_data_size = u64::from_le_bytes(size_data[0..8].try_into().unwrap());
+    let zip_data_size = u64::from_le_bytes(size_data[8..16].try_into().unwrap());
+    Ok((patch_data_size, zip_data_size))
+}
+
 /// Append data to the end of executable file
-fn append_data_to_exe(exe_path: &Path, patch_data_path: &Path, zip_path: &Path) -> Result<()> {
-    let mut exe_file = fs::OpenOptions::new()
-        .append(true)
-        .open(exe_path)
+///
+/// With a passphrase the patch data and zip are encrypted separately, so the
+/// payload can still be located without decrypting the patch data first. The
+/// checksum covers the bytes as written, so corruption is caught before any
+/// passphrase is asked for. The stub already in the file is hashed too, so a
+/// patch whose executable part was corrupted refuses to run.
+fn append_data_to_exe(
+    exe_path: &Path,
+    patch_data_path: &Path,
+    zip_path: &Path,
+    open_retries: u32,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let mut exe_file = retry_with_backoff("Opening executable", open_retries, || {
+        fs::OpenOptions::new().append(true).open(exe_path)
+    })
+    .with_context(|| {
+        format!(
+            "Failed to open executable file: {}. {}",
+            exe_path.display(),
+            ANTIVIRUS_HINT
+        )
+    })?;
+
+    let mut stub_file = File::open(exe_path)
         .with_context(|| format!("Failed to open executable file: {}", exe_path.display()))?;
+    let stub_size = stub_file
+        .metadata()
+        .context("Failed to get executable metadata")?
+        .len();
+    let stub_hash = hash_file_region(&mut stub_file, 0, stub_size)?;
 
     // Write patch data
     let mut patch_data = Vec::new();
@@ -297,6 +1264,18 @@ fn append_data_to_exe(exe_path: &Path, patch_data_path: &Path, zip_path: &Path)
         .read_to_end(&mut zip_data)
         .context("Failed to read zip data")?;
 
+    let salt = match passphrase {
+        Some(passphrase) => {
+            info!("Encrypting patch data...");
+            let salt = crypto::new_salt();
+            let key = crypto::derive_key(passphrase, &salt)?;
+            patch_data = crypto::encrypt(&key, &patch_data)?;
+            zip_data = crypto::encrypt(&key, &zip_data)?;
+            Some(salt)
+        }
+        None => None,
+    };
+
     // Write end markers and offsets
     let patch_data_size = patch_data.len() as u64;
     let zip_data_size = zip_data.len() as u64;
@@ -308,74 +1287,200 @@ fn append_data_to_exe(exe_path: &Path, patch_data_path: &Path, zip_path: &Path)
         .write_all(&zip_data)
         .context("Failed to write zip data to executable")?;
 
+    let mut hasher = Sha256::new();
+    hasher.update(&patch_data);
+    hasher.update(&zip_data);
+    let checksum = hasher.finalize();
+
+    let mut flags = 0;
+    if let Some(salt) = &salt {
+        flags |= FLAG_ENCRYPTED;
+        exe_file
+            .write_all(salt)
+            .context("Failed to write encryption salt")?;
+    }
+    exe_file
+        .write_all(&stub_hash)
+        .context("Failed to write stub hash")?;
+    exe_file
+        .write_all(&stub_size.to_le_bytes())
+        .context("Failed to write stub size")?;
+    exe_file
+        .write_all(&checksum)
+        .context("Failed to write payload checksum")?;
     exe_file
         .write_all(&patch_data_size.to_le_bytes())
         .context("Failed to write patch data size")?;
     exe_file
         .write_all(&zip_data_size.to_le_bytes())
         .context("Failed to write zip data size")?;
+    exe_file
+        .write_all(&[flags, TRAILER_VERSION])
+        .context("Failed to write trailer version")?;
 
     // Write magic marker
     exe_file
-        .write_all(b"PATCH_END")
+        .write_all(END_MARKER)
         .context("Failed to write end marker")?;
 
     Ok(())
 }
 
-/// Verify if patch should be applied to the current directory
-pub fn verify_directory(check_files: &[String], current_dir: &Path) -> Result<bool> {
-    for file in check_files {
-        let file_path = current_dir.join(file);
-        if !file_path.exists() {
-            warn!("Verification file not found: {}", file_path.display());
-            return Ok(false);
+/// Split a `--check-files` entry into its path and optional expected SHA256
+///
+/// `bin/game.dll:<sha256>` pins the file's content; anything after the last
+/// `:` that is not a SHA256 stays part of the path.
+pub fn parse_check_file(entry: &str) -> (String, Option<String>) {
+    match entry.rsplit_once(':') {
+        Some((path, hash)) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            (path.to_string(), Some(hash.to_ascii_lowercase()))
         }
+        _ => (entry.to_string(), None),
     }
-    Ok(true)
 }
 
-/// Extract patch data from executable
-pub fn extract_patch_data_from_exe() -> Result<(PatchData, Vec<u8>)> {
-    let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
-
-    let mut file = File::open(&current_exe)
-        .with_context(|| format!("Failed to open executable file: {}", current_exe.display()))?;
+/// Version recorded by the last versioned patch applied to a directory
+pub fn installed_version(target: &dyn PatchTarget) -> Result<Option<String>> {
+    match target.get(Path::new(VERSION_FILE)) {
+        Ok(text) => Ok(Some(String::from_utf8_lossy(&text).trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to read installed version: {}", VERSION_FILE))
+        }
+    }
+}
 
-    let file_size = file
+/// Fail unless the directory was last patched to the required version
+fn check_installed_version(target: &dyn PatchTarget, required: &str) -> Result<()> {
+    match installed_version(target)? {
+        Some(installed) if installed == required => {
+            info!(
+                "Installed version {} matches the patch prerequisite.",
+                installed
+            );
+            Ok(())
+        }
+        Some(installed) => Err(anyhow!(
+            "This patch updates version {}, but version {} is installed. Apply the patch to \
+             version {} first.",
+            required,
+            installed,
+            required
+        )),
+        None => Err(anyhow!(
+            "This patch updates version {}, but no installed version is recorded \
+             ({} is missing). Apply the patch to version {} first.",
+            required,
+            VERSION_FILE,
+            required
+        )),
+    }
+}
+
+/// Verify if patch should be applied to the current directory
+pub fn verify_directory(
+    check_files: &[String],
+    check_hashes: &BTreeMap<String, String>,
+    target: &dyn PatchTarget,
+) -> Result<bool> {
+    let failures = verification_failures(check_files, check_hashes, target)?;
+    for failure in &failures {
+        warn!("{}", failure);
+    }
+    Ok(failures.is_empty())
+}
+
+/// Check files that are missing or whose content is not the expected version
+fn verification_failures(
+    check_files: &[String],
+    check_hashes: &BTreeMap<String, String>,
+    target: &dyn PatchTarget,
+) -> Result<Vec<String>> {
+    let mut failures = Vec::new();
+    for file in check_files {
+        let file_path = Path::new(file);
+        if !target.exists(file_path) {
+            failures.push(format!("Verification file not found: {}", file));
+        } else if let Some(expected) = check_hashes.get(file)
+            && target
+                .hash(file_path, HashAlgorithm::Sha256)
+                .with_context(|| format!("Failed to hash verification file: {}", file))?
+                != *expected
+        {
+            failures.push(format!(
+                "Verification file is not the version the patch was made for: {}",
+                file
+            ));
+        }
+    }
+    Ok(failures)
+}
+
+/// Location of the zip payload appended to a patch executable
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadRegion {
+    pub offset: u64,
+    pub len: u64,
+    /// Key the payload is encrypted with, for encrypted patches
+    pub key: Option<[u8; 32]>,
+}
+
+/// Extract patch data from a patch file
+///
+/// Only the (small) patch-data JSON is read into memory; the zip payload is
+/// returned as a region of the file so callers can stream it. Encrypted
+/// patches ask for their passphrase here.
+pub fn extract_patch_data(patch_file: &Path) -> Result<(PatchData, PayloadRegion)> {
+    let mut file = File::open(patch_file)
+        .with_context(|| format!("Failed to open patch file: {}", patch_file.display()))?;
+
+    let file_size = file
         .metadata()
         .context("Failed to get file metadata")?
         .len();
 
-    // Check if file is large enough to contain patch data
-    if file_size < 24 {
-        // 8 (patch_size) + 8 (zip_size) + 9 (PATCH_END)
-        return Err(anyhow!("Invalid patch file: too small"));
-    }
+    let trailer = Trailer::read(&mut file, file_size)?;
+    let patch_data_size = trailer.patch_data_size;
+    let zip_data_size = trailer.zip_data_size;
 
-    // Read file end
-    let mut end_marker = [0u8; 9];
-    file.seek(std::io::SeekFrom::End(-9))
-        .context("Failed to seek to end marker")?;
-    file.read_exact(&mut end_marker)
-        .context("Failed to read end marker")?;
+    // Locate patch data and content
+    let offset = (file_size - trailer.len)
+        .checked_sub(patch_data_size)
+        .and_then(|o| o.checked_sub(zip_data_size))
+        .ok_or_else(|| anyhow!("Invalid patch file: size fields exceed file length"))?;
 
-    if &end_marker != b"PATCH_END" {
-        return Err(anyhow!("Invalid patch file: missing end marker"));
+    match &trailer.checksum {
+        Some(expected) => {
+            verify_payload_checksum(&mut file, offset, file_size - trailer.len, expected)?
+        }
+        None => debug!(
+            "Patch file format {} has no payload checksum, skipping check",
+            trailer.version
+        ),
+    }
+    match &trailer.stub {
+        Some((size, expected)) => {
+            if *size != offset || hash_file_region(&mut file, 0, offset)? != *expected {
+                return Err(anyhow!(
+                    "Patch file is corrupted: its executable part is not the one it was \
+                     created with. Do not run it; download the patch again from a trusted \
+                     source."
+                ));
+            }
+        }
+        None => debug!(
+            "Patch file format {} has no stub hash, skipping check",
+            trailer.version
+        ),
     }
 
-    // Read patch data and content size
-    let mut size_data = [0u8; 16];
-    file.seek(std::io::SeekFrom::End(-25))
-        .context("Failed to seek to size data")?;
-    file.read_exact(&mut size_data)
-        .context("Failed to read size data")?;
-
-    let patch_data_size = u64::from_le_bytes(size_data[0..8].try_into().unwrap());
-    let zip_data_size = u64::from_le_bytes(size_data[8..16].try_into().unwrap());
-
-    // Read patch data and content
-    let offset = file_size - 25 - patch_data_size - zip_data_size;
+    let key = match &trailer.salt {
+        Some(salt) => {
+            let passphrase = read_passphrase("Patch passphrase", false)?;
+            Some(crypto::derive_key(&passphrase, salt)?)
+        }
+        None => None,
+    };
 
     file.seek(std::io::SeekFrom::Start(offset))
         .context("Failed to seek to patch data")?;
@@ -383,73 +1488,1094 @@ pub fn extract_patch_data_from_exe() -> Result<(PatchData, Vec<u8>)> {
     let mut patch_data_bytes = vec![0u8; patch_data_size as usize];
     file.read_exact(&mut patch_data_bytes)
         .context("Failed to read patch data")?;
-
-    let mut content_bytes = vec![0u8; zip_data_size as usize];
-    file.read_exact(&mut content_bytes)
-        .context("Failed to read content data")?;
+    if let Some(key) = &key {
+        patch_data_bytes = crypto::decrypt(key, &patch_data_bytes)?;
+    }
 
     // Deserialize patch data
-    let patch_data: PatchData =
-        serde_json::from_slice(&patch_data_bytes).context("Failed to deserialize patch data")?;
+    let patch_data = PatchData::from_json(&patch_data_bytes)?;
+
+    let region = PayloadRegion {
+        offset: offset + patch_data_size,
+        len: zip_data_size,
+        key,
+    };
+
+    Ok((patch_data, region))
+}
+
+/// Hash the appended patch data and payload and compare with the trailer
+fn verify_payload_checksum(
+    file: &mut File,
+    start: u64,
+    end: u64,
+    expected: &[u8; 32],
+) -> Result<()> {
+    if hash_file_region(file, start, end)? != *expected {
+        return Err(anyhow!(
+            "Patch file is corrupted: payload checksum does not match. Download the patch again."
+        ));
+    }
+    Ok(())
+}
+
+/// SHA256 of the bytes of a file between two offsets
+fn hash_file_region(file: &mut File, start: u64, end: u64) -> Result<[u8; 32]> {
+    file.seek(std::io::SeekFrom::Start(start))
+        .context("Failed to seek in patch file")?;
+    let mut reader = BufReader::with_capacity(DEFAULT_IO_BUFFER_SIZE, &mut *file).take(end - start);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; DEFAULT_IO_BUFFER_SIZE];
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .context("Failed to read patch file")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Stream a region of a patch file to another file using a bounded buffer
+///
+/// An encrypted payload is read and decrypted in memory instead.
+pub fn copy_payload_region(
+    patch_file: &Path,
+    region: PayloadRegion,
+    dest: &Path,
+    buffer_size: usize,
+) -> Result<()> {
+    let mut file = File::open(patch_file)
+        .with_context(|| format!("Failed to open patch file: {}", patch_file.display()))?;
+    file.seek(std::io::SeekFrom::Start(region.offset))
+        .context("Failed to seek to content data")?;
+
+    if let Some(key) = &region.key {
+        let mut encrypted = vec![0u8; region.len as usize];
+        file.read_exact(&mut encrypted)
+            .context("Invalid patch file: content data is truncated")?;
+        let content = crypto::decrypt(key, &encrypted)?;
+        return fs::write(dest, content)
+            .with_context(|| format!("Failed to create file: {}", dest.display()));
+    }
+
+    let mut reader = BufReader::with_capacity(buffer_size, file).take(region.len);
+    let mut writer = BufWriter::with_capacity(
+        buffer_size,
+        File::create(dest).with_context(|| format!("Failed to create file: {}", dest.display()))?,
+    );
+    let copied = std::io::copy(&mut reader, &mut writer).context("Failed to read content data")?;
+    writer
+        .flush()
+        .context("Failed to write content to temp file")?;
+
+    if copied != region.len {
+        return Err(anyhow!("Invalid patch file: content data is truncated"));
+    }
+    Ok(())
+}
+
+/// Remove the old entry at a type-changed path, leaving room for the new type
+///
+/// A directory is only removed if it holds nothing but the files the patch
+/// removes with it, so files added locally are never deleted.
+pub fn remove_for_type_change(target: &dyn PatchTarget, change: &TypeChange) -> Result<()> {
+    // With nothing there, the new entry can be created directly
+    let path = &change.relative_path;
+    match change.new_kind {
+        EntryKind::Directory if target.exists(path) && !target.is_dir(path) => target
+            .delete(path)
+            .with_context(|| format!("Failed to remove file: {}", path.display())),
+        EntryKind::File if target.is_dir(path) => {
+            let unknown = unknown_entries(target, change)?;
+            if !unknown.is_empty() {
+                return Err(anyhow!(
+                    "Cannot replace directory {} with a file: it holds {} entries the patch \
+                     does not remove: {}. Move them elsewhere and apply again.",
+                    change.relative_path.display(),
+                    unknown.len(),
+                    unknown
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            target
+                .delete_dir(path)
+                .with_context(|| format!("Failed to remove directory: {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Files and links below a type-changed directory that the patch doesn't remove
+fn unknown_entries(target: &dyn PatchTarget, change: &TypeChange) -> Result<Vec<PathBuf>> {
+    let removed: HashSet<&Path> = change.removed.iter().map(PathBuf::as_path).collect();
+    let mut unknown: Vec<PathBuf> = target
+        .list(&change.relative_path)
+        .with_context(|| {
+            format!(
+                "Failed to read directory: {}",
+                change.relative_path.display()
+            )
+        })?
+        .into_iter()
+        .filter(|path| !removed.contains(path.as_path()))
+        .collect();
+    unknown.sort();
+    Ok(unknown)
+}
+
+/// Reason recorded for files an interrupted apply already wrote
+const ALREADY_APPLIED: &str = "already up to date";
+
+/// Reason recorded for modified files left alone by `--on-conflict`
+const KEPT_LOCAL: &str = "kept local changes";
+
+/// Reason recorded for changes turned down with `--interactive`
+const DECLINED: &str = "declined";
+
+/// Whether a fully replaced file is neither the version the patch was made
+/// against nor already the new one
+///
+/// Patches without the original hash of their modified files never report changes.
+fn has_local_changes(
+    target: &dyn PatchTarget,
+    file_info: &FileInfo,
+    algorithm: HashAlgorithm,
+) -> Result<bool> {
+    let Some(original) = &file_info.original_hash else {
+        return Ok(false);
+    };
+    let path = &file_info.relative_path;
+    if !target.exists(path) || target.is_dir(path) {
+        return Ok(false);
+    }
+    let hash = target
+        .hash(path, algorithm)
+        .with_context(|| format!("Failed to hash file: {}", path.display()))?;
+    Ok(hash != *original && hash != file_info.hash)
+}
+
+/// Decide which locally changed files are kept instead of overwritten
+fn keep_local_changes(
+    target: &dyn PatchTarget,
+    patch_data: &PatchData,
+    policy: OnConflict,
+) -> Result<HashSet<PathBuf>> {
+    let mut kept = HashSet::new();
+    for file_info in &patch_data.modified_files {
+        if !has_local_changes(target, file_info, patch_data.hash_algorithm)? {
+            continue;
+        }
+        let path = &file_info.relative_path;
+        let keep = match policy {
+            OnConflict::Overwrite => {
+                warn!("Overwriting local changes to {}", path.display());
+                false
+            }
+            OnConflict::Skip => {
+                warn!(
+                    "Keeping local changes to {}, it is not updated",
+                    path.display()
+                );
+                true
+            }
+            OnConflict::Prompt => !confirm_action(
+                &format!("{} has local changes. Overwrite it?", path.display()),
+                false,
+            )?,
+        };
+        if keep {
+            kept.insert(path.clone());
+        }
+    }
+    Ok(kept)
+}
+
+/// Whether a file exists with the given content hash
+///
+/// Used by `--resume` to leave alone files an interrupted apply already wrote.
+fn has_content(
+    target: &dyn PatchTarget,
+    path: &Path,
+    hash: &str,
+    algorithm: HashAlgorithm,
+) -> bool {
+    target.hash(path, algorithm).is_ok_and(|h| h == hash)
+}
+
+/// Move a renamed file to its new path
+///
+/// The file is checked first, since moving a locally edited copy would leave
+/// the wrong content at the new path.
+pub fn apply_rename(
+    target: &dyn PatchTarget,
+    rename: &Rename,
+    algorithm: HashAlgorithm,
+) -> Result<()> {
+    if !has_content(target, &rename.from, &rename.hash, algorithm) {
+        return Err(anyhow!(
+            "Cannot rename {}: it is missing or not the version the patch was made against",
+            rename.from.display()
+        ));
+    }
+
+    target
+        .rename(&rename.from, &rename.to)
+        .and_then(|_| target.set_mode(&rename.to, rename.mode))
+        .with_context(|| {
+            format!(
+                "Failed to rename {} to {}",
+                rename.from.display(),
+                rename.to.display()
+            )
+        })
+}
+
+/// Create a symbolic link, replacing the file or link at its path
+pub fn replace_with_symlink(target: &dyn PatchTarget, link: &Symlink) -> Result<()> {
+    target
+        .symlink(&link.relative_path, &link.target)
+        .with_context(|| format!("Failed to create link: {}", link.relative_path.display()))
+}
+
+/// Check that a patch file matches an expected SHA256
+pub fn verify_patch_file_hash(patch_file: &Path, expected: &str) -> Result<()> {
+    info!("Verifying patch file hash...");
+    let actual = calculate_file_hash(patch_file, HashAlgorithm::Sha256)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(anyhow!(
+            "Patch file hash mismatch: expected {}, found {}. The patch file may be corrupted.",
+            expected.trim(),
+            actual
+        ));
+    }
+    info!("Patch file hash verified.");
+    Ok(())
+}
+
+/// Copy the base directory into a new, empty output directory
+fn prepare_out_dir(base_dir: &Path, out_dir: &Path) -> Result<()> {
+    check_is_directory(base_dir).context("Base directory check failed")?;
+    // Copying a directory into itself would never finish
+    let base = fs::canonicalize(base_dir)
+        .with_context(|| format!("Failed to resolve path: {}", base_dir.display()))?;
+    if resolve_missing_path(out_dir)?.starts_with(&base) {
+        return Err(anyhow!(
+            "Output directory {} is inside the base directory {}",
+            out_dir.display(),
+            base_dir.display()
+        ));
+    }
+    if out_dir.exists()
+        && fs::read_dir(out_dir)
+            .with_context(|| format!("Failed to read directory: {}", out_dir.display()))?
+            .next()
+            .is_some()
+    {
+        return Err(anyhow!(
+            "Output directory is not empty: {}",
+            out_dir.display()
+        ));
+    }
+
+    info!(
+        "Copying {} to {} before patching...",
+        base_dir.display(),
+        out_dir.display()
+    );
+    copy_dir_all(base_dir, out_dir)
+}
+
+/// Absolute path of a file or directory that may not exist yet, with links resolved
+///
+/// The deepest existing ancestor is canonicalized and the rest appended to it.
+fn resolve_missing_path(path: &Path) -> Result<PathBuf> {
+    let absolute = std::path::absolute(path)
+        .with_context(|| format!("Failed to resolve path: {}", path.display()))?;
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = fs::canonicalize(existing)
+        .with_context(|| format!("Failed to resolve path: {}", existing.display()))?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Display the embedded license and ask the user to accept it
+fn prompt_eula(eula: &str, accept_eula: bool) -> Result<bool> {
+    println!("{}", eula);
+    if accept_eula {
+        info!("License accepted via --accept-eula.");
+        return Ok(true);
+    }
+    // Accepting a license must be an explicit choice, so Enter always declines
+    confirm_prompt("Do you accept the license terms above?", false)
+        .context("Pass --accept-eula to accept the license without prompting")
+}
+
+/// Files a patch removes: the listed removals plus force-removal glob matches
+///
+/// Globs never match files the patch writes or the patch file itself, given
+/// by its path in the target if it is there.
+fn planned_removals(
+    patch_data: &PatchData,
+    target: &dyn PatchTarget,
+    patch_file: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut removals = patch_data.removed_files.clone();
+    if patch_data.removed_globs.is_empty() {
+        return Ok(removals);
+    }
+    let written: HashSet<&Path> = patch_data.written_paths().collect();
+    let files = target
+        .list(Path::new(""))
+        .context("Failed to list files to match removal globs")?;
+    removals.extend(
+        filter_glob_matches(files, &patch_data.removed_globs)?
+            .into_iter()
+            .filter(|path| !written.contains(path.as_path()))
+            .filter(|path| Some(path.as_path()) != patch_file),
+    );
+    Ok(removals)
+}
+
+/// Print what applying the patch would change without writing anything
+fn print_dry_run(patch_data: &PatchData, patch_file: &Path, current_dir: &Path) -> Result<()> {
+    println!(
+        "Dry run: no files will be changed in {}",
+        current_dir.display()
+    );
+    let target = LocalTarget::new(current_dir);
+
+    if patch_data.check_files.is_empty() {
+        println!("No verification files specified, the patch applies to any directory.");
+    } else {
+        let failures =
+            verification_failures(&patch_data.check_files, &patch_data.check_hashes, &target)?;
+        if failures.is_empty() {
+            println!("Directory verification would pass.");
+        } else {
+            println!("Directory verification would fail:");
+            for failure in failures {
+                println!("  {}", failure);
+            }
+        }
+    }
+    if let Some(required) = &patch_data.requires_version {
+        match check_installed_version(&target, required) {
+            Ok(()) => println!("Installed version {} matches the prerequisite.", required),
+            Err(e) => println!("Version prerequisite would fail: {}", e),
+        }
+    }
+
+    for change in &patch_data.type_changes {
+        println!("replace   {}", change.relative_path.display());
+    }
+    for info in &patch_data.added_files {
+        let action = if current_dir.join(&info.relative_path).exists() {
+            "overwrite"
+        } else {
+            "add"
+        };
+        println!("{:<9} {}", action, info.relative_path.display());
+    }
+    for info in &patch_data.modified_files {
+        if has_local_changes(&target, info, patch_data.hash_algorithm)? {
+            println!(
+                "conflict  {} (changed locally)",
+                info.relative_path.display()
+            );
+        } else {
+            println!("overwrite {}", info.relative_path.display());
+        }
+    }
+    for file_diff in &patch_data.modified_diffs {
+        println!("patch     {}", file_diff.relative_path.display());
+    }
+    for delta in &patch_data.binary_deltas {
+        println!("patch     {}", delta.relative_path.display());
+    }
+    for rename in &patch_data.renamed_files {
+        println!(
+            "rename    {} -> {}",
+            rename.from.display(),
+            rename.to.display()
+        );
+    }
+    for link in &patch_data.symlinks {
+        println!(
+            "link      {} -> {}",
+            link.relative_path.display(),
+            link.target.display()
+        );
+    }
+    let patch_file = patch_file.strip_prefix(current_dir).ok();
+    for path in planned_removals(patch_data, &target, patch_file)? {
+        if current_dir.join(&path).exists() {
+            println!("remove    {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Whether an apply that didn't fail changed anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyStatus {
+    Applied,
+    /// Nothing was written: a dry run, or the license or a confirmation was declined
+    NotApplied,
+}
+
+/// Apply the patch embedded in the running executable to a directory
+pub fn apply_patch(current_dir: &Path, options: &ApplyOptions) -> Result<ApplyStatus> {
+    let current_exe = std::env::current_exe().context("Failed to get current executable path")?;
+    apply_patch_file(&current_exe, current_dir, options)
+}
+
+/// Apply several patch files in order, each to the directory the previous one left
+///
+/// Each patch checks its verification files against the result of the one
+/// before it, and the chain stops at the first patch that fails or is not
+/// applied. With `out`, the first patch writes the patched copy and the rest
+/// update it in place.
+pub fn apply_patch_chain(
+    patch_files: &[PathBuf],
+    current_dir: &Path,
+    options: &ApplyOptions,
+) -> Result<ApplyStatus> {
+    if options.dry_run && patch_files.len() > 1 {
+        return Err(anyhow!(
+            "--dry-run previews one patch at a time, since each patch in a chain expects \
+             the changes of the previous one"
+        ));
+    }
+
+    let mut options = options.clone();
+    let mut dir = current_dir.to_path_buf();
+    for (i, patch_file) in patch_files.iter().enumerate() {
+        info!(
+            "Applying patch {} of {}: {}",
+            i + 1,
+            patch_files.len(),
+            patch_file.display()
+        );
+        let status = apply_patch_file(patch_file, &dir, &options)
+            .with_context(|| chain_stop(patch_files, i, "failed"))?;
+        if status == ApplyStatus::NotApplied {
+            warn!("{}", chain_stop(patch_files, i, "was not applied"));
+            return Ok(status);
+        }
+        if let Some(out_dir) = options.out.take() {
+            dir = out_dir;
+            options.base = None;
+        }
+    }
+    Ok(ApplyStatus::Applied)
+}
+
+/// Apply several patch files in order to a target other than a local directory
+pub fn apply_patch_chain_to_target(
+    patch_files: &[PathBuf],
+    target: &dyn PatchTarget,
+    options: &ApplyOptions,
+) -> Result<ApplyStatus> {
+    for (i, patch_file) in patch_files.iter().enumerate() {
+        info!(
+            "Applying patch {} of {}: {}",
+            i + 1,
+            patch_files.len(),
+            patch_file.display()
+        );
+        let status = apply_patch_to_target(patch_file, target, options)
+            .with_context(|| chain_stop(patch_files, i, "failed"))?;
+        if status == ApplyStatus::NotApplied {
+            warn!("{}", chain_stop(patch_files, i, "was not applied"));
+            return Ok(status);
+        }
+    }
+    Ok(ApplyStatus::Applied)
+}
+
+/// Which patch of a chain stopped it, why, and how many were applied before it
+fn chain_stop(patch_files: &[PathBuf], index: usize, reason: &str) -> String {
+    let applied = match index {
+        0 => String::new(),
+        1 => "; the patch before it was applied".to_string(),
+        _ => format!("; the {} patches before it were applied", index),
+    };
+    format!(
+        "Patch {} of {} {}: {}{}",
+        index + 1,
+        patch_files.len(),
+        reason,
+        patch_files[index].display(),
+        applied
+    )
+}
 
-    Ok((patch_data, content_bytes))
+/// Apply a patch file to a directory
+pub fn apply_patch_file(
+    patch_file: &Path,
+    current_dir: &Path,
+    options: &ApplyOptions,
+) -> Result<ApplyStatus> {
+    // Patch a fresh copy and leave the base directory untouched
+    if let Some(out_dir) = &options.out {
+        let base_dir = options.base.as_deref().unwrap_or(current_dir);
+        let options = options.clone().base(None).out(None);
+        if options.dry_run {
+            // Nothing is copied, so report what would happen to the base directory
+            return apply_patch_file(patch_file, base_dir, &options);
+        }
+        // An interrupted apply already copied the base directory
+        if !(options.resume && out_dir.join(RESUME_FILE).exists()) {
+            prepare_out_dir(base_dir, out_dir)?;
+        }
+        return apply_patch_file(patch_file, out_dir, &options);
+    }
+
+    let Some(report_path) = &options.report else {
+        return apply_to_dir(patch_file, current_dir, options, &Outcomes::new());
+    };
+    let started_at = jiff::Timestamp::now();
+    let start = Instant::now();
+    let outcomes = Outcomes::new();
+    let result = apply_to_dir(patch_file, current_dir, options, &outcomes);
+    let (tree_hash_before, tree_hash_after) = outcomes.tree_hashes();
+    let report = ApplyReport {
+        patch: patch_file.to_path_buf(),
+        target: current_dir.to_path_buf(),
+        succeeded: result.is_ok(),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        rolled_back: outcomes.rolled_back(),
+        started_at: started_at.strftime("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        duration_ms: start.elapsed().as_millis() as u64,
+        tree_hash_before,
+        tree_hash_after,
+        files: outcomes.into_sorted(),
+    };
+    report.write(report_path)?;
+    info!("Report written to {}", report_path.display());
+    result
 }
 
-/// Apply patch to current directory
-pub fn apply_patch(current_dir: &Path) -> Result<()> {
+/// Apply a patch file to a directory in place, recording what happens to each path
+fn apply_to_dir(
+    patch_file: &Path,
+    current_dir: &Path,
+    options: &ApplyOptions,
+    outcomes: &Outcomes,
+) -> Result<ApplyStatus> {
     info!("Applying patch to directory: {}", current_dir.display());
+    let target = LocalTarget::new(current_dir)
+        .force(options.force)
+        .io_buffer_size(io_buffer_size(options.io_buffer_size));
+    let (patch_data, payload) = open_checked_patch(patch_file, options)?;
+    print!("{}", patch_data.metadata);
+
+    if options.dry_run {
+        print_dry_run(&patch_data, patch_file, current_dir)?;
+        return Ok(ApplyStatus::NotApplied);
+    }
 
-    // Extract patch data and content
-    let (patch_data, content_bytes) = extract_patch_data_from_exe()?;
+    // Require license acceptance before touching anything
+    if let Some(eula) = &patch_data.eula
+        && !prompt_eula(eula, options.accept_eula)?
+    {
+        info!("License not accepted. Patch was not applied.");
+        return Ok(ApplyStatus::NotApplied);
+    }
 
-    // Verify if patch should be applied to this directory
-    if !patch_data.check_files.is_empty() {
-        info!("Verifying directory...");
-        if !verify_directory(&patch_data.check_files, current_dir)? {
+    // An apply that did not finish is either resumed or has to be undone first
+    let patch_id = format!(
+        "{:x}",
+        Sha256::digest(serde_json::to_vec(&patch_data).context("Failed to serialize patch data")?)
+    );
+    let resumed = match ResumeState::load(current_dir)? {
+        Some(state) if options.resume && state.patch == patch_id => {
+            info!("Resuming the interrupted apply...");
+            Some(state.stage)
+        }
+        Some(_) if options.resume => {
             return Err(anyhow!(
-                "Directory verification failed. This patch cannot be applied here."
+                "An interrupted apply of a different patch was found in {}. \
+                 Run `diffpatch restore` to undo it first.",
+                current_dir.display()
             ));
         }
-        info!("Directory verification successful.");
-    } else {
-        warn!("No verification files specified. Applying patch without verification.");
-        if !dialoguer::Confirm::new()
-            .with_prompt("Continue with patch application?")
-            .default(false)
-            .interact()
-            .context("Failed to get user confirmation")?
-        {
-            return Ok(());
+        Some(_) => {
+            return Err(anyhow!(
+                "An earlier apply was interrupted in {}. Run again with --resume to finish it, \
+                 or run `diffpatch restore` to undo it.",
+                current_dir.display()
+            ));
+        }
+        None => {
+            if options.resume {
+                warn!("No interrupted apply found, applying the whole patch.");
+            }
+            None
         }
+    };
+
+    // Incremental patches only apply on top of the version they were made from
+    if let Some(required) = &patch_data.requires_version
+        && resumed != Some(Stage::Write)
+    {
+        check_installed_version(&target, required)?;
+    }
+
+    // Verify if patch should be applied to this directory
+    if resumed == Some(Stage::Write) {
+        // Some checked files may already be patched
+        info!("Directory was verified when the interrupted apply started.");
+    } else if !verify_target(&target, &patch_data)? {
+        return Ok(ApplyStatus::NotApplied);
+    }
+
+    // Locally edited files are handled before anything is written, prompts included
+    let mut left_alone: HashMap<PathBuf, &str> =
+        keep_local_changes(&target, &patch_data, options.on_conflict)?
+            .into_iter()
+            .map(|path| (path, KEPT_LOCAL))
+            .collect();
+
+    if options.merkle {
+        let before = tree_hash(current_dir, Some(patch_file))?;
+        println!("Tree hash before: {}", before);
+        outcomes.set_tree_hash_before(before);
     }
 
     // Create temporary directory to extract content
-    let temp_dir = tempdir().context("Failed to create temporary directory")?;
+    let temp_dir = create_temp_dir(options.temp_dir.as_deref())?;
     let zip_path = temp_dir.path().join("content.zip");
 
-    // Write content to temporary file
-    fs::write(&zip_path, &content_bytes).context("Failed to write content to temp file")?;
+    // The payload is copied out, and with --safe also unpacked, before anything is written
+    let mut needed = payload.len + patch_data.binary_deltas.iter().map(|d| d.size).sum::<u64>();
+    if options.safe {
+        needed += patch_data
+            .added_files
+            .iter()
+            .chain(patch_data.modified_files.iter())
+            .filter(|f| f.same_as.is_none())
+            .map(|f| f.size)
+            .sum::<u64>();
+    }
+    let mut removals = planned_removals(
+        &patch_data,
+        &target,
+        patch_file.strip_prefix(current_dir).ok(),
+    )?;
+
+    // An interrupted apply already made its rollback copies
+    let target_needed = if resumed == Some(Stage::Write) {
+        0
+    } else {
+        target_space_needed(current_dir, &patch_data, &removals)
+    };
+    if same_volume(temp_dir.path(), current_dir) {
+        check_free_space(current_dir, needed + target_needed).context(
+            "The target volume also holds the temporary files; use --temp-dir to put them on \
+             another volume",
+        )?;
+    } else {
+        check_free_space(temp_dir.path(), needed)
+            .context("Use --temp-dir to put temporary files on a volume with more space")?;
+        check_free_space(current_dir, target_needed).context(
+            "The target volume needs room for the new files and for copies of the files they \
+             replace or remove, kept until the apply succeeds",
+        )?;
+    }
+
+    // Stream content to temporary file
+    copy_payload_region(
+        patch_file,
+        payload,
+        &zip_path,
+        io_buffer_size(options.io_buffer_size),
+    )?;
 
     // Unzip content
     let file = File::open(&zip_path).context("Failed to open zip file")?;
     let mut archive = zip::ZipArchive::new(file).context("Failed to read zip archive")?;
 
-    // Process files
-    info!("Processing {} files...", archive.len());
-    let pb = ProgressBar::new(archive.len() as u64);
-    pb.set_message("Extracting files...");
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-            )
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+    // Every change is confirmed before anything is unpacked or written
+    if options.interactive {
+        let settled = left_alone.keys().map(PathBuf::as_path).collect();
+        let Some(declined) = review_changes(&patch_data, &mut archive, &removals, &settled)? else {
+            info!("Operation cancelled. Patch was not applied.");
+            return Ok(ApplyStatus::NotApplied);
+        };
+        removals.retain(|path| {
+            let keep = !declined.contains(path);
+            if !keep {
+                outcomes.skipped(path, Action::Removed, DECLINED);
+            }
+            keep
+        });
+        left_alone.extend(declined.into_iter().map(|path| (path, DECLINED)));
+    }
 
-    // Safely unpack the archive to a temporary location first
+    // Safely unpack the archive to a temporary location first; by default
+    // files are written straight to their destination instead
     let extract_dir = temp_dir.path().join("extracted");
     fs::create_dir_all(&extract_dir).context("Failed to create extraction directory")?;
+    if options.safe {
+        extract_archive(
+            &mut archive,
+            &extract_dir,
+            io_buffer_size(options.io_buffer_size),
+        )?;
+    }
+
+    let mut payload = Payload {
+        zip_path: &zip_path,
+        archive: &mut archive,
+        extract_dir: &extract_dir,
+    };
+    rebuild_deltas(
+        &target,
+        &patch_data,
+        &mut payload,
+        &left_alone,
+        options,
+        outcomes,
+    )?;
+
+    // Save everything about to be overwritten or removed, so a failed apply
+    // can be rolled back; with --backup the copy is kept for `restore`
+    let backup_dir = current_dir.join(if options.backup {
+        BACKUP_DIR
+    } else {
+        ROLLBACK_DIR
+    });
+    // Originals saved by the interrupted apply are kept, the files may be patched by now
+    if resumed != Some(Stage::Write) {
+        if resumed == Some(Stage::Backup) && backup_dir.exists() {
+            fs::remove_dir_all(&backup_dir).with_context(|| {
+                format!("Failed to delete partial backup: {}", backup_dir.display())
+            })?;
+        }
+        let mut state = ResumeState {
+            patch: patch_id,
+            stage: Stage::Backup,
+        };
+        state.save(current_dir)?;
+        if let Err(err) = backup_before_apply(&backup_dir, current_dir, &patch_data, &removals) {
+            ResumeState::clear(current_dir)?;
+            return Err(err);
+        }
+        state.stage = Stage::Write;
+        state.save(current_dir)?;
+    }
+
+    let plan = Plan {
+        removals: &removals,
+        left_alone: &left_alone,
+    };
+    let pool = io_pool(get_io_thread_count(current_dir, options.threads));
+    if let Err(err) = write_changes(
+        &target,
+        &patch_data,
+        payload,
+        plan,
+        &pool,
+        options,
+        outcomes,
+    ) {
+        warn!("Patch application failed, rolling back changes...");
+        restore_from(&backup_dir, current_dir)
+            .with_context(|| format!("Failed to roll back after error: {:#}", err))?;
+        outcomes.mark_rolled_back();
+        ResumeState::clear(current_dir)?;
+        return Err(err.context("Patch was not applied, the directory was left unchanged"));
+    }
+    if !options.backup {
+        fs::remove_dir_all(&backup_dir)
+            .with_context(|| format!("Failed to delete rollback data: {}", backup_dir.display()))?;
+    }
+    record_version(&target, &patch_data)?;
+    ResumeState::clear(current_dir)?;
+
+    info!("Patch applied successfully!");
+    log_outcome_summary(outcomes);
+
+    if options.merkle {
+        let after = tree_hash(current_dir, Some(patch_file))?;
+        println!("Tree hash after: {}", after);
+        outcomes.set_tree_hash_after(after);
+    }
+
+    Ok(ApplyStatus::Applied)
+}
+
+/// Apply a patch file to a target other than a local directory, such as an object store
+///
+/// Files are checked and written as in a directory, but nothing is saved
+/// aside first, so a failed apply can leave the target partly patched.
+/// Options that rely on a local directory are refused.
+pub fn apply_patch_to_target(
+    patch_file: &Path,
+    target: &dyn PatchTarget,
+    options: &ApplyOptions,
+) -> Result<ApplyStatus> {
+    let unsupported = [
+        ("--out", options.out.is_some()),
+        ("--backup", options.backup),
+        ("--resume", options.resume),
+        ("--dry-run", options.dry_run),
+        ("--interactive", options.interactive),
+        ("--merkle", options.merkle),
+        ("--report", options.report.is_some()),
+    ];
+    if let Some((flag, _)) = unsupported.iter().find(|(_, given)| *given) {
+        return Err(anyhow!(
+            "{} can only be used when patching a local directory",
+            flag
+        ));
+    }
+
+    let (patch_data, payload) = open_checked_patch(patch_file, options)?;
+    print!("{}", patch_data.metadata);
+
+    if let Some(eula) = &patch_data.eula
+        && !prompt_eula(eula, options.accept_eula)?
+    {
+        info!("License not accepted. Patch was not applied.");
+        return Ok(ApplyStatus::NotApplied);
+    }
+    if let Some(required) = &patch_data.requires_version {
+        check_installed_version(target, required)?;
+    }
+    if !verify_target(target, &patch_data)? {
+        return Ok(ApplyStatus::NotApplied);
+    }
+    let left_alone: HashMap<PathBuf, &str> =
+        keep_local_changes(target, &patch_data, options.on_conflict)?
+            .into_iter()
+            .map(|path| (path, KEPT_LOCAL))
+            .collect();
+    let removals = planned_removals(&patch_data, target, None)?;
+
+    let temp_dir = create_temp_dir(options.temp_dir.as_deref())?;
+    let zip_path = temp_dir.path().join("content.zip");
+    copy_payload_region(
+        patch_file,
+        payload,
+        &zip_path,
+        io_buffer_size(options.io_buffer_size),
+    )?;
+    let file = File::open(&zip_path).context("Failed to open zip file")?;
+    let mut archive = zip::ZipArchive::new(file).context("Failed to read zip archive")?;
+    let extract_dir = temp_dir.path().join("extracted");
+    fs::create_dir_all(&extract_dir).context("Failed to create extraction directory")?;
+    if options.safe {
+        extract_archive(
+            &mut archive,
+            &extract_dir,
+            io_buffer_size(options.io_buffer_size),
+        )?;
+    }
+
+    let outcomes = Outcomes::new();
+    let mut payload = Payload {
+        zip_path: &zip_path,
+        archive: &mut archive,
+        extract_dir: &extract_dir,
+    };
+    rebuild_deltas(
+        target,
+        &patch_data,
+        &mut payload,
+        &left_alone,
+        options,
+        &outcomes,
+    )?;
+    let plan = Plan {
+        removals: &removals,
+        left_alone: &left_alone,
+    };
+    // Object stores are reached over the network
+    let pool = io_pool(io_thread_count(StorageKind::Network, options.threads));
+    write_changes(
+        target,
+        &patch_data,
+        payload,
+        plan,
+        &pool,
+        options,
+        &outcomes,
+    )
+    .context("Patch application failed, the target may be partly patched")?;
+    record_version(target, &patch_data)?;
+
+    info!("Patch applied successfully!");
+    log_outcome_summary(&outcomes);
+    Ok(ApplyStatus::Applied)
+}
+
+/// Check a patch file against its published hash and read its patch data
+fn open_checked_patch(
+    patch_file: &Path,
+    options: &ApplyOptions,
+) -> Result<(PatchData, PayloadRegion)> {
+    // Check the whole patch file against the published hash before trusting any of it
+    if let Some(expected) = &options.expect_sha256 {
+        verify_patch_file_hash(patch_file, expected)?;
+    }
+
+    // Extract patch data and content
+    let (patch_data, payload) = extract_patch_data(patch_file)?;
+
+    let conflicts = patch_data.conflicting_paths();
+    if !conflicts.is_empty() {
+        let list: Vec<String> = conflicts.iter().map(|p| p.display().to_string()).collect();
+        return Err(anyhow!(
+            "Patch is invalid: {} paths appear in more than one change category: {}",
+            conflicts.len(),
+            list.join(", ")
+        ));
+    }
+    Ok((patch_data, payload))
+}
+
+/// Check the verification files, or without any ask whether to continue
+///
+/// Returns false if the user chose not to continue.
+fn verify_target(target: &dyn PatchTarget, patch_data: &PatchData) -> Result<bool> {
+    if patch_data.check_files.is_empty() {
+        warn!("No verification files specified. Applying patch without verification.");
+        return confirm_action("Continue with patch application?", false);
+    }
+    info!("Verifying directory...");
+    if !verify_directory(&patch_data.check_files, &patch_data.check_hashes, target)? {
+        return Err(anyhow!(
+            "Directory verification failed. This patch cannot be applied here."
+        ));
+    }
+    info!("Directory verification successful.");
+    Ok(true)
+}
+
+/// Rebuild the files shipped as binary deltas next to the extracted files
+///
+/// They are then copied into place with the extracted files.
+fn rebuild_deltas<R: Read + Seek>(
+    target: &dyn PatchTarget,
+    patch_data: &PatchData,
+    payload: &mut Payload<'_, R>,
+    left_alone: &HashMap<PathBuf, &str>,
+    options: &ApplyOptions,
+    outcomes: &Outcomes,
+) -> Result<()> {
+    if patch_data.binary_deltas.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Rebuilding {} files from binary deltas...",
+        patch_data.binary_deltas.len()
+    );
+    for delta in &patch_data.binary_deltas {
+        if options.resume
+            && has_content(
+                target,
+                &delta.relative_path,
+                &delta.hash,
+                patch_data.hash_algorithm,
+            )
+        {
+            outcomes.skipped(&delta.relative_path, Action::DeltaPatched, ALREADY_APPLIED);
+            continue;
+        }
+        if let Some(reason) = left_alone.get(&delta.relative_path) {
+            outcomes.skipped(&delta.relative_path, Action::DeltaPatched, *reason);
+            continue;
+        }
+        let output = payload.extract_dir.join(&delta.relative_path);
+        rebuild_from_delta(
+            payload.archive,
+            target,
+            delta,
+            &output,
+            patch_data.hash_algorithm,
+            io_buffer_size(options.io_buffer_size),
+        )?;
+    }
+    Ok(())
+}
+
+/// Record the version a versioned patch installed, for the next incremental patch
+fn record_version(target: &dyn PatchTarget, patch_data: &PatchData) -> Result<()> {
+    if let Some(version) = &patch_data.metadata.patch_version {
+        target
+            .put(Path::new(VERSION_FILE), format!("{}\n", version).as_bytes())
+            .with_context(|| format!("Failed to write installed version: {}", VERSION_FILE))?;
+    }
+    Ok(())
+}
+
+/// Thread pool for file I/O, limited to avoid contention
+fn io_pool(threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap())
+}
+
+/// Bytes the target volume needs while a patch is written
+///
+/// New content is staged next to the file it replaces, and every file that is
+/// overwritten or removed is first copied aside so a failure can be rolled back.
+fn target_space_needed(current_dir: &Path, patch_data: &PatchData, removals: &[PathBuf]) -> u64 {
+    let current_size = |path: &Path| {
+        fs::symlink_metadata(current_dir.join(path))
+            .map(|m| if m.is_file() { m.len() } else { 0 })
+            .unwrap_or(0)
+    };
+    let new_content: u64 = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .map(|f| f.size)
+        .chain(patch_data.binary_deltas.iter().map(|d| d.size))
+        // A diffed file is rewritten at about its current size
+        .chain(
+            patch_data
+                .modified_diffs
+                .iter()
+                .map(|d| current_size(&d.relative_path)),
+        )
+        .sum();
+    let copied_aside: u64 = patch_data
+        .written_paths()
+        .chain(removals.iter().map(PathBuf::as_path))
+        .map(current_size)
+        .sum();
+    new_content + copied_aside
+}
+
+/// Unpack every full file in the archive into `extract_dir`
+fn extract_archive<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    extract_dir: &Path,
+    buffer_size: usize,
+) -> Result<()> {
+    // Process files
+    info!("Processing {} files...", archive.len());
+    let pb = progress_bar(archive.len() as u64, "Extracting files...");
 
     // Extract files to the temporary directory first
     for i in 0..archive.len() {
@@ -457,6 +2583,11 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
             .by_index(i)
             .context("Failed to access zip file entry")?;
         let outpath = match file.enclosed_name() {
+            // Stored diffs and deltas are read from the archive when their file is patched
+            Some(path) if path.starts_with(DIFFS_DIR) || path.starts_with(DELTAS_DIR) => {
+                pb.inc(1);
+                continue;
+            }
             Some(path) => extract_dir.join(path),
             None => {
                 pb.inc(1);
@@ -471,14 +2602,14 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
         } else {
             // Create parent directory if needed
             if let Some(parent) = outpath.parent()
-                && !parent.exists() {
-                    fs::create_dir_all(parent).with_context(|| {
-                        format!("Failed to create directory: {}", parent.display())
-                    })?;
-                }
+                && !parent.exists()
+            {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+            }
             // Extract file with buffered IO
             let mut outfile = BufWriter::with_capacity(
-                65536,
+                buffer_size,
                 File::create(&outpath)
                     .with_context(|| format!("Failed to create file: {}", outpath.display()))?,
             );
@@ -491,123 +2622,398 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
 
     pb.finish();
     info!("Files extracted successfully");
+    Ok(())
+}
 
-    // Process diff patch files
-    if !patch_data.modified_diffs.is_empty() {
-        info!("Applying {} file diffs...", patch_data.modified_diffs.len());
-        let diff_pb = ProgressBar::new(patch_data.modified_diffs.len() as u64);
-        diff_pb.set_message("Applying diffs...");
-        diff_pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+/// What is recorded about the full files a patch writes, by relative path
+struct WrittenFiles<'a> {
+    modes: &'a HashMap<&'a Path, u32>,
+    block_hashes: &'a HashMap<&'a Path, &'a [String]>,
+    actions: &'a HashMap<&'a Path, Action>,
+    /// Files left as they are, with the reason
+    skipped: &'a HashMap<&'a Path, &'a str>,
+}
+
+/// Write every full file in the archive straight to its destination
+///
+/// Used instead of `extract_archive` unless `--safe` is given, halving the
+/// disk I/O and temporary space of large patches. Entries are streamed with
+/// the buffered writer and still staged next to their destination.
+fn extract_in_place<R: Read + Seek>(
+    target: &dyn PatchTarget,
+    archive: &mut zip::ZipArchive<R>,
+    written: &WrittenFiles,
+    options: &ApplyOptions,
+    outcomes: &Outcomes,
+) -> Result<()> {
+    let eol = options.eol;
+    info!("Extracting {} files to target directory...", archive.len());
+    let pb = progress_bar(archive.len() as u64, "Extracting files...");
+
+    // Staging retries the write, which has to open the entry again
+    let archive = RefCell::new(archive);
+    let len = archive.borrow().len();
+    for i in 0..len {
+        let rel_path = {
+            let mut archive = archive.borrow_mut();
+            let file = archive
+                .by_index(i)
+                .context("Failed to access zip file entry")?;
+            match file.enclosed_name() {
+                Some(path)
+                    if !file.is_dir()
+                        && !path.starts_with(DIFFS_DIR)
+                        && !path.starts_with(DELTAS_DIR) =>
+                {
+                    path
+                }
+                // Stored diffs and deltas are read from the archive when their file is patched
+                _ => {
+                    pb.inc(1);
+                    continue;
+                }
+            }
+        };
+        let action = written
+            .actions
+            .get(rel_path.as_path())
+            .copied()
+            .unwrap_or(Action::Added);
+        if let Some(reason) = written.skipped.get(rel_path.as_path()) {
+            outcomes.skipped(&rel_path, action, *reason);
+            pb.inc(1);
+            continue;
+        }
+        // Patches without permissions keep those of the file being replaced
+        let mode = written
+            .modes
+            .get(rel_path.as_path())
+            .copied()
+            .or_else(|| target.mode(&rel_path));
+
+        let result = match written.block_hashes.get(rel_path.as_path()) {
+            // Verified, resumable write for large files written verbatim
+            Some(hashes) if eol.is_none() => {
+                let mut archive = archive.borrow_mut();
+                let archive = &mut **archive;
+                target.put_blocks(
+                    &rel_path,
+                    hashes,
+                    Box::new(move |offset| {
+                        let mut entry = archive.by_index(i).map_err(std::io::Error::other)?;
+                        std::io::copy(&mut (&mut entry).take(offset), &mut std::io::sink())?;
+                        Ok(Box::new(entry))
+                    }),
                 )
-                .unwrap()
-                .progress_chars("#>-"),
+            }
+            _ => target.put_with(&rel_path, &|writer| {
+                let mut archive = archive.borrow_mut();
+                let mut entry = archive.by_index(i).map_err(std::io::Error::other)?;
+                if let Some(mode) = eol {
+                    // Text files are rewritten with the requested line endings
+                    let mut content = Vec::new();
+                    entry.read_to_end(&mut content)?;
+                    if !looks_binary(&content) {
+                        content = convert_eol(&content, mode);
+                    }
+                    return writer.write_all(&content);
+                }
+                std::io::copy(&mut entry, writer).map(|_| ())
+            }),
+        };
+        if let Err(e) = result.and_then(|_| target.set_mode(&rel_path, mode)) {
+            outcomes.failed(&rel_path, action, &e);
+            return Err(e).with_context(|| format!("Failed to write file: {}", rel_path.display()));
+        }
+        debug!("Extracted {}", rel_path.display());
+        outcomes.succeeded(&rel_path, action);
+        pb.inc(1);
+    }
+
+    pb.finish();
+    info!("Files extracted successfully");
+    Ok(())
+}
+
+/// What became of a single file diff
+enum DiffResult {
+    Patched { conflicts: usize },
+    Skipped(&'static str),
+}
+
+/// Apply one diff to its file, merging local edits with `--three-way`
+fn apply_file_diff(
+    target: &dyn PatchTarget,
+    file_diff: &FileDiff,
+    algorithm: HashAlgorithm,
+    options: &ApplyOptions,
+) -> Result<DiffResult> {
+    let file_path = &file_diff.relative_path;
+
+    // Check if file exists
+    if !target.exists(file_path) {
+        return Ok(DiffResult::Skipped("file does not exist"));
+    }
+    if options.resume && has_content(target, file_path, &file_diff.hash, algorithm) {
+        return Ok(DiffResult::Skipped(ALREADY_APPLIED));
+    }
+
+    // Read current file content
+    let Ok(Ok(content)) = target.get(file_path).map(String::from_utf8) else {
+        // Skip if unable to read file (e.g., binary file)
+        return Ok(DiffResult::Skipped("file is not readable as text"));
+    };
+
+    // Local edits since the patch was made are merged instead of overwritten
+    let locally_modified = options.three_way
+        && calculate_reader_hash(&mut content.as_bytes(), algorithm)? != file_diff.original_hash;
+
+    // Recombine file content
+    let mut conflicts = 0;
+    let mut new_content = if locally_modified && file_diff.tokenized {
+        warn!(
+            "Cannot merge {}: token diffs are not merged with local edits; leaving it unchanged",
+            file_path.display()
         );
+        return Ok(DiffResult::Skipped("locally modified and the patch cannot be merged"));
+    } else if file_diff.tokenized {
+        apply_token_changes(&content, &file_diff.changes)
+            .with_context(|| format!("Failed to patch {}", file_path.display()))?
+    } else if locally_modified {
+        let Some(merge) = three_way_merge(&content, &file_diff.changes) else {
+            warn!(
+                "Cannot merge {}: the patch does not record the replaced lines; leaving it unchanged",
+                file_path.display()
+            );
+            return Ok(DiffResult::Skipped("locally modified and the patch cannot be merged"));
+        };
+        conflicts = merge.conflicts;
+        merge.content
+    } else {
+        apply_line_changes(&content, &file_diff.changes, file_diff.line_style_for(&content))
+    }
+    .into_bytes();
+    if let Some(mode) = options.eol {
+        new_content = convert_eol(&new_content, mode);
+    }
 
-        // Apply diff patches one by one (no need for parallelization as each file patch operation is already fast)
-        for file_diff in patch_data.modified_diffs.iter() {
-            let file_path = current_dir.join(&file_diff.relative_path);
+    // Write back to file, keeping its permissions if the patch has none
+    let mode = file_diff.mode.or_else(|| target.mode(file_path));
+    target
+        .put(file_path, &new_content)
+        .and_then(|_| target.set_mode(file_path, mode))
+        .with_context(|| format!("Failed to write file: {}", file_path.display()))?;
+    Ok(DiffResult::Patched { conflicts })
+}
 
-            // Check if file exists
-            if !file_path.exists() {
-                diff_pb.inc(1);
-                continue;
-            }
+/// Hash every full file the patch wrote and compare it with the hash it was made with
+///
+/// Catches payload corruption and partial writes. Mismatches fail the apply,
+/// so it is rolled back, unless `--allow-hash-mismatch` turns them into warnings.
+/// Files rewritten with `--eol` are expected to differ and are not checked.
+fn verify_written_files(
+    target: &dyn PatchTarget,
+    patch_data: &PatchData,
+    written: &WrittenFiles,
+    pool: &rayon::ThreadPool,
+    options: &ApplyOptions,
+    outcomes: &Outcomes,
+) -> Result<()> {
+    if options.eol.is_some() {
+        debug!("Line endings were converted, skipping hash verification of written files");
+        return Ok(());
+    }
+    let expected: Vec<(&Path, &str)> = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .map(|f| (f.relative_path.as_path(), f.hash.as_str()))
+        .chain(
+            patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| (d.relative_path.as_path(), d.hash.as_str())),
+        )
+        .filter(|(path, _)| !written.skipped.contains_key(path))
+        .collect();
+    if expected.is_empty() {
+        return Ok(());
+    }
 
-            // Read current file content
-            let mut content = String::new();
-            if let Ok(mut file) = File::open(&file_path) {
-                if file.read_to_string(&mut content).is_err() {
-                    // Skip if unable to read file (e.g., binary file)
-                    diff_pb.inc(1);
-                    continue;
-                }
-            } else {
-                diff_pb.inc(1);
+    info!("Verifying {} written files...", expected.len());
+    let errors = Mutex::new(Vec::new());
+    pool.install(|| {
+        expected.par_iter().for_each(|(path, hash)| {
+            let error = match target.hash(path, patch_data.hash_algorithm) {
+                Ok(actual) if actual == *hash => return,
+                Ok(actual) => format!("hash mismatch, expected {}, found {}", hash, actual),
+                Err(e) => format!("{:#}", e),
+            };
+            let action = written.actions.get(path).copied().unwrap_or(Action::Added);
+            outcomes.failed(path, action, &error);
+            errors
+                .lock()
+                .unwrap()
+                .push(format!("{}: {}", path.display(), error));
+        });
+    });
+
+    if options.allow_hash_mismatch {
+        for error in errors.into_inner().unwrap() {
+            warn!("Written file does not match the patch: {}", error);
+        }
+        return Ok(());
+    }
+    collected_failures("verify", errors.into_inner().unwrap(), false)?;
+    info!("Written files verified");
+    Ok(())
+}
+
+/// Zip archive of a patch and the directory its files were unpacked or rebuilt in
+struct Payload<'a, R> {
+    /// Where the archive is, for threads that need their own handle to it
+    zip_path: &'a Path,
+    archive: &'a mut zip::ZipArchive<R>,
+    extract_dir: &'a Path,
+}
+
+/// What was decided about the directory before anything is written
+struct Plan<'a> {
+    removals: &'a [PathBuf],
+    /// Files left as they are by the user's choice, with the reason
+    left_alone: &'a HashMap<PathBuf, &'a str>,
+}
+
+/// Write every change of an extracted patch to the directory
+///
+/// Stops at the first failing step. Copies and removals run in parallel, so
+/// their failures are collected and reported together once the step is done.
+fn write_changes<R: Read + Seek>(
+    target: &dyn PatchTarget,
+    patch_data: &PatchData,
+    payload: Payload<'_, R>,
+    plan: Plan,
+    pool: &rayon::ThreadPool,
+    options: &ApplyOptions,
+    outcomes: &Outcomes,
+) -> Result<()> {
+    let Payload {
+        zip_path,
+        archive,
+        extract_dir,
+    } = payload;
+    let Plan {
+        removals,
+        left_alone,
+    } = plan;
+
+    // Clear out entries whose type changed so the new file or directory can be created
+    if !patch_data.type_changes.is_empty() {
+        info!(
+            "Applying {} file/directory type changes...",
+            patch_data.type_changes.len()
+        );
+        for change in &patch_data.type_changes {
+            remove_for_type_change(target, change)?;
+            outcomes.succeeded(&change.relative_path, Action::TypeChanged);
+        }
+    }
+
+    // Renamed files are moved before anything is written to their new paths
+    if !patch_data.renamed_files.is_empty() {
+        info!("Renaming {} files...", patch_data.renamed_files.len());
+        for rename in &patch_data.renamed_files {
+            if options.resume
+                && !target.exists(&rename.from)
+                && has_content(target, &rename.to, &rename.hash, patch_data.hash_algorithm)
+            {
+                outcomes.skipped(&rename.to, Action::Renamed, ALREADY_APPLIED);
                 continue;
             }
+            apply_rename(target, rename, patch_data.hash_algorithm)?;
+            outcomes.succeeded(&rename.to, Action::Renamed);
+        }
+    }
 
-            // Split file content into lines
-            let mut lines: Vec<String> = content.lines().map(|s| s.to_owned()).collect();
-
-            // Apply changes
-            // Apply changes from back to front, so line numbers aren't affected by insertions/deletions
-            let mut sorted_changes = file_diff.changes.clone();
-            sorted_changes.sort_by(|a, b| {
-                let a_line = a.old_range.map(|(start, _)| start).unwrap_or(usize::MAX);
-                let b_line = b.old_range.map(|(start, _)| start).unwrap_or(usize::MAX);
-                b_line.cmp(&a_line)
-            });
+    // Process diff patch files
+    if !patch_data.modified_diffs.is_empty() {
+        info!("Applying {} file diffs...", patch_data.modified_diffs.len());
+        let diff_count = patch_data.modified_diffs.len() as u64;
+        let diff_pb = progress_bar(diff_count, "Applying diffs...");
+
+        // Diffs stored in the zip are read as they are applied, each thread
+        // through its own handle to the archive
+        let open_archive = || -> Result<Option<zip::ZipArchive<File>>> {
+            if !patch_data.diffs_in_zip {
+                return Ok(None);
+            }
+            let file = File::open(zip_path).context("Failed to open zip file")?;
+            Ok(Some(
+                zip::ZipArchive::new(file).context("Failed to read zip archive")?,
+            ))
+        };
 
-            for change in sorted_changes {
-                match change.tag {
-                    DiffChangeTag::Delete => {
-                        if let Some((start, len)) = change.old_range {
-                            // Ensure within range
-                            if start < lines.len() {
-                                let end = std::cmp::min(start + len, lines.len());
-                                lines.drain(start..end);
-                            }
-                        }
-                    }
-                    DiffChangeTag::Insert => {
-                        if let Some((start, _)) = change.new_range {
-                            // Ensure within range
-                            if start <= lines.len() {
-                                let new_lines: Vec<String> =
-                                    change.content.lines().map(|s| s.to_owned()).collect();
-                                for (i, line) in new_lines.into_iter().enumerate() {
-                                    lines.insert(start + i, line);
-                                }
-                            }
-                        }
+        let conflicted_files = AtomicUsize::new(0);
+
+        // Each diff touches its own file, so they are applied in parallel
+        let errors: Vec<String> = pool.install(|| {
+            patch_data
+                .modified_diffs
+                .par_iter()
+                .map_init(open_archive, |archive, file_diff| {
+                    let file_path = &file_diff.relative_path;
+                    if let Some(reason) = left_alone.get(file_path) {
+                        outcomes.skipped(file_path, Action::Diffed, *reason);
+                        diff_pb.inc(1);
+                        return None;
                     }
-                    DiffChangeTag::Equal => {
-                        // No changes needed for equal parts
+                    let result = match archive {
+                        Ok(Some(archive)) => read_stored_diff(archive, file_path).map(Cow::Owned),
+                        Ok(None) => Ok(Cow::Borrowed(file_diff)),
+                        Err(e) => Err(anyhow!("{:#}", e)),
                     }
-                    DiffChangeTag::Replace => {
-                        // Replace operation: delete first, then insert
-                        if let Some((start, len)) = change.old_range
-                            && start < lines.len() {
-                                let end = std::cmp::min(start + len, lines.len());
-                                lines.drain(start..end);
-                            }
-                        if let Some((start, _)) = change.new_range
-                            && start <= lines.len() {
-                                let new_lines: Vec<String> =
-                                    change.content.lines().map(|s| s.to_owned()).collect();
-                                for (i, line) in new_lines.into_iter().enumerate() {
-                                    if start + i <= lines.len() {
-                                        lines.insert(start + i, line);
-                                    }
-                                }
+                    .and_then(|file_diff| {
+                        apply_file_diff(target, &file_diff, patch_data.hash_algorithm, options)
+                    });
+                    diff_pb.inc(1);
+                    match result {
+                        Ok(DiffResult::Patched { conflicts }) => {
+                            if conflicts > 0 {
+                                warn!("{} merge conflicts in {}", conflicts, file_path.display());
+                                conflicted_files.fetch_add(1, Ordering::Relaxed);
                             }
+                            debug!("Patched {}", file_path.display());
+                            outcomes.succeeded(file_path, Action::Diffed);
+                            None
+                        }
+                        Ok(DiffResult::Skipped(reason)) => {
+                            outcomes.skipped(file_path, Action::Diffed, reason);
+                            None
+                        }
+                        Err(e) => {
+                            outcomes.failed(file_path, Action::Diffed, format!("{:#}", e));
+                            Some(format!("{}: {:#}", file_path.display(), e))
+                        }
                     }
-                }
-            }
-
-            // Recombine file content
-            let new_content = lines.join("\n");
-
-            // Write back to file
-            if let Ok(mut file) = File::create(&file_path)
-                && file.write_all(new_content.as_bytes()).is_err() {
-                    // Skip on write error
-                    diff_pb.inc(1);
-                    continue;
-                }
-
-            diff_pb.inc(1);
-        }
+                })
+                .flatten()
+                .collect()
+        });
 
         diff_pb.finish();
+        collected_failures("patch", errors, false)?;
         info!("File diffs applied successfully");
+        let conflicted_files = conflicted_files.into_inner();
+        if conflicted_files > 0 {
+            warn!(
+                "{} files have merge conflicts; search them for '<<<<<<< local'",
+                conflicted_files
+            );
+        }
     }
 
     // Now copy files in parallel from the temporary directory to the target directory
-    let extracted_files: Vec<_> = walkdir::WalkDir::new(&extract_dir)
+    let extracted_files: Vec<_> = walkdir::WalkDir::new(extract_dir)
         .into_iter()
         .filter_map(Result::ok)
         .filter(|e| e.file_type().is_file())
@@ -617,57 +3023,139 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
         "Copying {} files to target directory...",
         extracted_files.len()
     );
-    let copy_pb = ProgressBar::new(extracted_files.len() as u64);
-    copy_pb.set_message("Copying files...");
-    copy_pb.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
-            )
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+    let copy_pb = progress_bar(extracted_files.len() as u64, "Copying files...");
 
     // Use atomic counter for progress
     let copy_counter = Arc::new(Mutex::new(0));
 
-    // Create a thread pool with limited threads to avoid I/O contention
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(get_io_thread_count())
-        .build()
-        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap());
+    // Large files recorded with block hashes are copied block by block
+    let block_hashes: HashMap<&Path, &[String]> = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .filter(|f| !f.block_hashes.is_empty())
+        .map(|f| (f.relative_path.as_path(), f.block_hashes.as_slice()))
+        .collect();
+
+    // Permissions recorded for each written file
+    let modes: HashMap<&Path, u32> = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .filter_map(|f| Some((f.relative_path.as_path(), f.mode?)))
+        .chain(
+            patch_data
+                .binary_deltas
+                .iter()
+                .filter_map(|d| Some((d.relative_path.as_path(), d.mode?))),
+        )
+        .collect();
+
+    // How each written file is reported
+    let actions: HashMap<&Path, Action> = patch_data
+        .added_files
+        .iter()
+        .map(|f| (f.relative_path.as_path(), Action::Added))
+        .chain(
+            patch_data
+                .modified_files
+                .iter()
+                .map(|f| (f.relative_path.as_path(), Action::Modified)),
+        )
+        .chain(
+            patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| (d.relative_path.as_path(), Action::DeltaPatched)),
+        )
+        .collect();
+
+    // Files an interrupted apply already wrote are left alone when resuming
+    let mut skipped: HashMap<&Path, &str> = if options.resume {
+        pool.install(|| {
+            patch_data
+                .added_files
+                .par_iter()
+                .chain(patch_data.modified_files.par_iter())
+                .filter(|f| f.link_target.is_none())
+                .filter(|f| {
+                    has_content(target, &f.relative_path, &f.hash, patch_data.hash_algorithm)
+                })
+                .map(|f| (f.relative_path.as_path(), ALREADY_APPLIED))
+                .collect()
+        })
+    } else {
+        HashMap::new()
+    };
+    skipped.extend(
+        left_alone
+            .iter()
+            .map(|(path, reason)| (path.as_path(), *reason)),
+    );
+
+    // Failures are collected so every copy finishes before the apply is rolled back
+    let errors = Mutex::new(Vec::new());
 
     // Parallel copy to target directory
     pool.install(|| {
         extracted_files.par_iter().for_each(|entry| {
             let src_path = entry.path();
-            let rel_path = src_path.strip_prefix(&extract_dir).unwrap_or(src_path);
-            let dest_path = current_dir.join(rel_path);
-
-            // Ensure parent directory exists
-            if let Some(parent) = dest_path.parent()
-                && !parent.exists()
-                    && fs::create_dir_all(parent).is_err() {
-                        return; // Skip on error
+            let rel_path = src_path.strip_prefix(extract_dir).unwrap_or(src_path);
+            let action = actions.get(rel_path).copied().unwrap_or(Action::Added);
+            if let Some(reason) = skipped.get(rel_path) {
+                outcomes.skipped(rel_path, action, *reason);
+                let mut counter = copy_counter.lock().unwrap();
+                *counter += 1;
+                copy_pb.set_position(*counter);
+                return;
+            }
+            // Patches without permissions keep those of the file being replaced
+            let mode = modes
+                .get(rel_path)
+                .copied()
+                .or_else(|| target.mode(rel_path));
+
+            let result = match block_hashes.get(rel_path) {
+                // Verified, resumable copy for large files written verbatim
+                Some(hashes) if options.eol.is_none() => target.put_blocks(
+                    rel_path,
+                    hashes,
+                    Box::new(|offset| {
+                        let mut src_file = File::open(src_path)?;
+                        src_file.seek(SeekFrom::Start(offset))?;
+                        Ok(Box::new(src_file))
+                    }),
+                ),
+                // Buffered copy of everything else
+                _ => target.put_with(rel_path, &|writer| {
+                    if let Some(mode) = options.eol {
+                        // Text files are rewritten with the requested line endings
+                        let content = fs::read(src_path)?;
+                        if !looks_binary(&content) {
+                            return writer.write_all(&convert_eol(&content, mode));
+                        }
                     }
 
-            // Optimized copy with buffered IO
-            let result = (|| {
-                let src_file = File::open(src_path)?;
-                let mut reader = BufReader::with_capacity(65536, src_file);
-
-                let dst_file = File::create(&dest_path)?;
-                let mut writer = BufWriter::with_capacity(65536, dst_file);
-
-                std::io::copy(&mut reader, &mut writer)?;
-                writer.flush()?;
-                Ok::<_, std::io::Error>(())
-            })();
-
-            if result.is_err() {
-                return; // Skip on error
+                    let src_file = File::open(src_path)?;
+                    let mut reader =
+                        BufReader::with_capacity(io_buffer_size(options.io_buffer_size), src_file);
+                    std::io::copy(&mut reader, writer).map(|_| ())
+                }),
+            }
+            .and_then(|_| target.set_mode(rel_path, mode));
+
+            if let Err(e) = result {
+                outcomes.failed(rel_path, action, &e);
+                errors
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", rel_path.display(), e));
+                return;
             }
 
+            debug!("Copied {}", rel_path.display());
+            outcomes.succeeded(rel_path, action);
+
             // Update progress
             let mut counter = copy_counter.lock().unwrap();
             *counter += 1;
@@ -676,37 +3164,176 @@ pub fn apply_patch(current_dir: &Path) -> Result<()> {
     });
 
     copy_pb.finish();
+    collected_failures("copy", errors.into_inner().unwrap(), false)?;
     info!("Files copied successfully");
 
+    let written = WrittenFiles {
+        modes: &modes,
+        block_hashes: &block_hashes,
+        actions: &actions,
+        skipped: &skipped,
+    };
+    if !options.safe {
+        extract_in_place(target, archive, &written, options, outcomes)?;
+    }
+
+    // Deduplicated files are copied from the written file with the same content
+    let duplicates: Vec<&FileInfo> = patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+        .filter(|f| f.same_as.is_some())
+        .collect();
+    if !duplicates.is_empty() {
+        info!("Copying {} duplicate files...", duplicates.len());
+        for file_info in duplicates {
+            let action = actions
+                .get(file_info.relative_path.as_path())
+                .copied()
+                .unwrap_or(Action::Added);
+            if let Some(reason) = skipped.get(file_info.relative_path.as_path()) {
+                outcomes.skipped(&file_info.relative_path, action, *reason);
+                continue;
+            }
+            let same_as = file_info.same_as.as_ref().unwrap();
+            let dest_path = &file_info.relative_path;
+            let mode = file_info.mode.or_else(|| target.mode(dest_path));
+            let result = if left_alone.contains_key(same_as) {
+                // The file holding this content was left alone, so it comes from the patch
+                let name = portable_path(same_as).to_string_lossy().into_owned();
+                let mut content = Vec::new();
+                archive
+                    .by_name(&name)
+                    .map_err(std::io::Error::other)
+                    .and_then(|mut entry| entry.read_to_end(&mut content))
+                    .and_then(|_| target.put(dest_path, &content))
+            } else {
+                target.copy(same_as, dest_path)
+            };
+            result
+                .and_then(|_| target.set_mode(dest_path, mode))
+                .with_context(|| format!("Failed to write file: {}", dest_path.display()))?;
+            debug!("Copied {}", file_info.relative_path.display());
+            outcomes.succeeded(&file_info.relative_path, action);
+        }
+    }
+
+    verify_written_files(target, patch_data, &written, pool, options, outcomes)?;
+
+    // Links go in once the files they may point to are in place
+    if !patch_data.symlinks.is_empty() {
+        info!("Creating {} symlinks...", patch_data.symlinks.len());
+        for link in &patch_data.symlinks {
+            replace_with_symlink(target, link)?;
+            outcomes.succeeded(&link.relative_path, Action::Symlinked);
+        }
+    }
+
     // Remove files to be deleted in parallel
-    if !patch_data.removed_files.is_empty() {
-        info!("Removing {} files...", patch_data.removed_files.len());
+    if !removals.is_empty() {
+        info!("Removing {} files...", removals.len());
 
         // Use same thread pool for deletion
+        let errors = Mutex::new(Vec::new());
         pool.install(|| {
-            patch_data.removed_files.par_iter().for_each(|path| {
-                let full_path = current_dir.join(path);
-                if full_path.exists() {
-                    let _ = fs::remove_file(&full_path);
+            removals.par_iter().for_each(|path| {
+                if !target.exists(path) {
+                    outcomes.skipped(path, Action::Removed, "file does not exist");
+                    return;
+                }
+                match target.delete(path) {
+                    Ok(()) => outcomes.succeeded(path, Action::Removed),
+                    Err(e) => {
+                        outcomes.failed(path, Action::Removed, &e);
+                        errors
+                            .lock()
+                            .unwrap()
+                            .push(format!("{}: {}", path.display(), e));
+                    }
                 }
             });
         });
+        collected_failures("remove", errors.into_inner().unwrap(), false)?;
 
         info!("Files removed successfully");
+
+        if !options.keep_empty_dirs {
+            let removed = remove_empty_dirs(target, patch_data, removals);
+            if removed > 0 {
+                info!("Removed {} empty directories", removed);
+            }
+        }
     }
 
-    info!("Patch applied successfully!");
+    Ok(())
+}
+
+/// Log how many paths of each kind were actually changed, skipped or failed
+///
+/// The counts come from what the apply did, not from the patch, so files that
+/// were already up to date or left alone are not counted as written.
+fn log_outcome_summary(outcomes: &Outcomes) {
+    let (counts, skip_reasons) = outcomes.counts();
     info!("Summary:");
-    info!("  Added files: {}", patch_data.added_files.len());
-    info!(
-        "  Modified files (full): {}",
-        patch_data.modified_files.len()
-    );
-    info!(
-        "  Modified files (diff): {}",
-        patch_data.modified_diffs.len()
-    );
-    info!("  Removed files: {}", patch_data.removed_files.len());
+    // The common kinds are always listed, the others only when the patch has them
+    for (action, label, always) in [
+        (Action::Added, "Added files", true),
+        (Action::Modified, "Modified files (full)", true),
+        (Action::Diffed, "Modified files (diff)", true),
+        (Action::DeltaPatched, "Modified files (binary delta)", false),
+        (Action::Renamed, "Renamed files", false),
+        (Action::Removed, "Removed files", true),
+        (Action::TypeChanged, "Type changed paths", false),
+        (Action::Symlinked, "Symlinks", false),
+    ] {
+        let count = counts.get(&action).copied().unwrap_or_default();
+        if !always && count.succeeded + count.skipped + count.failed == 0 {
+            continue;
+        }
+        let mut line = format!("  {}: {}", label, count.succeeded);
+        if count.skipped > 0 {
+            line.push_str(&format!(", {} skipped", count.skipped));
+        }
+        if count.failed > 0 {
+            line.push_str(&format!(", {} failed", count.failed));
+        }
+        info!("{}", line);
+    }
+    for (reason, count) in skip_reasons {
+        info!("  Skipped ({}): {}", reason, count);
+    }
+}
 
-    Ok(())
+/// Remove directories left empty by the removed files, deepest first
+///
+/// Directories the patch writes into are kept. A directory that still holds
+/// anything is left alone, so this never fails the apply.
+fn remove_empty_dirs(
+    target: &dyn PatchTarget,
+    patch_data: &PatchData,
+    removals: &[PathBuf],
+) -> usize {
+    let written: HashSet<&Path> = patch_data
+        .written_paths()
+        .flat_map(Path::ancestors)
+        .collect();
+    let mut dirs: Vec<&Path> = removals
+        .iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty() && !written.contains(dir))
+        .collect();
+    dirs.sort_by(|a, b| {
+        let depth = |dir: &Path| dir.components().count();
+        depth(b).cmp(&depth(a)).then_with(|| a.cmp(b))
+    });
+    dirs.dedup();
+
+    let mut removed = 0;
+    for dir in dirs {
+        if target.remove_empty_dir(dir) {
+            debug!("Removed empty directory {}", dir.display());
+            removed += 1;
+        }
+    }
+    removed
 }
@@ -0,0 +1,144 @@
+use crate::patch::PatchData;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Differences between two patches within one category of entries
+#[derive(Debug)]
+pub struct CategoryDelta {
+    pub category: &'static str,
+    pub only_in_new: Vec<PathBuf>, // entries the new patch introduced
+    pub only_in_old: Vec<PathBuf>, // entries the new patch no longer contains
+    pub changed: Vec<PathBuf>,     // entries present in both with different content
+}
+
+impl CategoryDelta {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_new.is_empty() && self.only_in_old.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Map each entry of a category to a fingerprint of its content
+type Entries = BTreeMap<PathBuf, String>;
+
+fn categories(patch: &PatchData) -> Vec<(&'static str, Entries)> {
+    vec![
+        (
+            "Added files",
+            patch
+                .added_files
+                .iter()
+                .map(|f| (f.relative_path.clone(), f.hash.clone()))
+                .collect(),
+        ),
+        (
+            "Modified files (full)",
+            patch
+                .modified_files
+                .iter()
+                .map(|f| (f.relative_path.clone(), f.hash.clone()))
+                .collect(),
+        ),
+        (
+            "Modified files (diff)",
+            patch
+                .modified_diffs
+                .iter()
+                .map(|d| {
+                    (
+                        d.relative_path.clone(),
+                        format!("{}:{}", d.original_hash, d.hash),
+                    )
+                })
+                .collect(),
+        ),
+        (
+            "Modified files (delta)",
+            patch
+                .binary_deltas
+                .iter()
+                .map(|d| {
+                    (
+                        d.relative_path.clone(),
+                        format!("{}:{}", d.original_hash, d.hash),
+                    )
+                })
+                .collect(),
+        ),
+        (
+            "Renamed files",
+            patch
+                .renamed_files
+                .iter()
+                .map(|r| (r.to.clone(), format!("{}:{}", r.from.display(), r.hash)))
+                .collect(),
+        ),
+        (
+            "Symlinks",
+            patch
+                .symlinks
+                .iter()
+                .map(|l| (l.relative_path.clone(), l.target.display().to_string()))
+                .collect(),
+        ),
+        (
+            "Removed files",
+            patch
+                .removed_files
+                .iter()
+                .map(|p| (p.clone(), String::new()))
+                .collect(),
+        ),
+        (
+            "Removed globs",
+            patch
+                .removed_globs
+                .iter()
+                .map(|g| (PathBuf::from(g), String::new()))
+                .collect(),
+        ),
+        (
+            "Type changes",
+            patch
+                .type_changes
+                .iter()
+                .map(|t| (t.relative_path.clone(), format!("{:?}", t.new_kind)))
+                .collect(),
+        ),
+    ]
+}
+
+/// Compare the metadata of two patches category by category
+pub fn diff_patch_data(old: &PatchData, new: &PatchData) -> Vec<CategoryDelta> {
+    categories(old)
+        .into_iter()
+        .zip(categories(new))
+        .map(|((category, old_entries), (_, new_entries))| {
+            let only_in_new = new_entries
+                .keys()
+                .filter(|path| !old_entries.contains_key(*path))
+                .cloned()
+                .collect();
+            let only_in_old = old_entries
+                .keys()
+                .filter(|path| !new_entries.contains_key(*path))
+                .cloned()
+                .collect();
+            let changed = new_entries
+                .iter()
+                .filter(|(path, fingerprint)| {
+                    old_entries
+                        .get(*path)
+                        .is_some_and(|old_fingerprint| old_fingerprint != *fingerprint)
+                })
+                .map(|(path, _)| path.clone())
+                .collect();
+
+            CategoryDelta {
+                category,
+                only_in_new,
+                only_in_old,
+                changed,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,295 @@
+use crate::diff::{
+    BinaryDelta, EntryKind, FileDiff, HashAlgorithm, Rename, Symlink, TypeChange,
+    calculate_file_hash,
+};
+use crate::merge::apply_line_changes;
+use crate::patch::{
+    PatchData, apply_rename, copy_payload_region, extract_patch_data, read_stored_diff,
+    rebuild_from_delta, remove_for_type_change, replace_with_symlink,
+};
+use crate::target::{LocalTarget, PatchTarget, stage_and_swap};
+use crate::tokenize::apply_token_changes;
+use crate::utils::{DEFAULT_IO_BUFFER_SIZE, portable_path};
+use crate::verify::{Drift, find_drift};
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+use tempfile::tempdir;
+use zip::ZipArchive;
+
+/// What a repair run changed and what still differs afterwards
+pub struct RepairOutcome {
+    pub repaired: Vec<Drift>,
+    pub remaining: Vec<Drift>,
+}
+
+/// Bring a partially patched directory to the patch's end state
+///
+/// Only drifting entries are touched: missing or wrong full files are
+/// rewritten from the payload, diffs are re-applied to files still in their
+/// original state, and pending removals and type changes are completed.
+/// Running it again on a repaired directory changes nothing.
+pub fn repair(patch_file: &Path, target_dir: &Path) -> Result<RepairOutcome> {
+    let (patch_data, payload) = extract_patch_data(patch_file)?;
+    let drift = find_drift(&patch_data, target_dir)?;
+    if drift.is_empty() {
+        return Ok(RepairOutcome {
+            repaired: Vec::new(),
+            remaining: Vec::new(),
+        });
+    }
+
+    let temp_dir = tempdir().context("Failed to create temporary directory")?;
+    let zip_path = temp_dir.path().join("content.zip");
+    copy_payload_region(patch_file, payload, &zip_path, DEFAULT_IO_BUFFER_SIZE)?;
+    let file = File::open(&zip_path).context("Failed to open zip file")?;
+    let mut archive = ZipArchive::new(file).context("Failed to read zip archive")?;
+
+    // Type changes first, so files can be written where directories were and vice versa
+    let (type_drift, file_drift): (Vec<Drift>, Vec<Drift>) = drift
+        .into_iter()
+        .partition(|d| matches!(d, Drift::WrongType { .. }));
+
+    let entries = PatchEntries::new(&patch_data);
+    let mut repaired = Vec::new();
+    for item in type_drift.into_iter().chain(file_drift) {
+        if repair_entry(&patch_data, &entries, &mut archive, target_dir, &item)? {
+            repaired.push(item);
+        }
+    }
+
+    let remaining = find_drift(&patch_data, target_dir)?;
+    Ok(RepairOutcome {
+        repaired,
+        remaining,
+    })
+}
+
+/// What the patch records for each path it changes, looked up by relative path
+struct PatchEntries<'a> {
+    /// Zip entry holding each full file, and its mode; deduplicated files share
+    /// another file's entry
+    full_files: HashMap<&'a Path, (&'a Path, Option<u32>)>,
+    diffs: HashMap<&'a Path, &'a FileDiff>,
+    deltas: HashMap<&'a Path, &'a BinaryDelta>,
+    renames: HashMap<&'a Path, &'a Rename>, // by destination
+    symlinks: HashMap<&'a Path, &'a Symlink>,
+    type_changes: HashMap<&'a Path, &'a TypeChange>,
+}
+
+impl<'a> PatchEntries<'a> {
+    fn new(patch_data: &'a PatchData) -> Self {
+        Self {
+            full_files: patch_data
+                .added_files
+                .iter()
+                .chain(patch_data.modified_files.iter())
+                .map(|f| {
+                    let entry = f.same_as.as_deref().unwrap_or(&f.relative_path);
+                    (f.relative_path.as_path(), (entry, f.mode))
+                })
+                .collect(),
+            diffs: patch_data
+                .modified_diffs
+                .iter()
+                .map(|d| (d.relative_path.as_path(), d))
+                .collect(),
+            deltas: patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| (d.relative_path.as_path(), d))
+                .collect(),
+            renames: patch_data
+                .renamed_files
+                .iter()
+                .map(|r| (r.to.as_path(), r))
+                .collect(),
+            symlinks: patch_data
+                .symlinks
+                .iter()
+                .map(|l| (l.relative_path.as_path(), l))
+                .collect(),
+            type_changes: patch_data
+                .type_changes
+                .iter()
+                .map(|c| (c.relative_path.as_path(), c))
+                .collect(),
+        }
+    }
+}
+
+/// Fix a single drifting entry, returning whether it could be repaired
+fn repair_entry<R: Read + Seek>(
+    patch_data: &PatchData,
+    entries: &PatchEntries,
+    archive: &mut ZipArchive<R>,
+    target_dir: &Path,
+    drift: &Drift,
+) -> Result<bool> {
+    match drift {
+        Drift::WrongType { path, expected } => {
+            if let Some(change) = entries.type_changes.get(path.as_path()) {
+                remove_for_type_change(&LocalTarget::new(target_dir), change)?;
+            }
+            if *expected == EntryKind::Directory {
+                let full_path = target_dir.join(path);
+                fs::create_dir_all(&full_path).with_context(|| {
+                    format!("Failed to create directory: {}", full_path.display())
+                })?;
+            }
+            Ok(true)
+        }
+        Drift::WrongLink { path, .. } => match entries.symlinks.get(path.as_path()) {
+            Some(link) => {
+                replace_with_symlink(&LocalTarget::new(target_dir), link)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Drift::NotRemoved(path) => {
+            let full_path = target_dir.join(path);
+            fs::remove_file(&full_path)
+                .with_context(|| format!("Failed to remove file: {}", full_path.display()))?;
+            Ok(true)
+        }
+        Drift::Missing(path) | Drift::HashMismatch { path, .. } => {
+            if let Some(&(entry, mode)) = entries.full_files.get(path.as_path()) {
+                extract_entry(archive, entry, path, mode, target_dir)?;
+                return Ok(true);
+            }
+
+            if let Some(rename) = entries.renames.get(path.as_path()) {
+                let from = target_dir.join(&rename.from);
+                if !from.is_file()
+                    || calculate_file_hash(&from, patch_data.hash_algorithm)? != rename.hash
+                {
+                    warn!(
+                        "Cannot repair {}: {} is not there to move",
+                        path.display(),
+                        rename.from.display()
+                    );
+                    return Ok(false);
+                }
+                apply_rename(
+                    &LocalTarget::new(target_dir),
+                    rename,
+                    patch_data.hash_algorithm,
+                )?;
+                return Ok(true);
+            }
+
+            if let Some(delta) = entries.deltas.get(path.as_path()) {
+                return repair_binary_delta(archive, target_dir, delta, patch_data.hash_algorithm);
+            }
+
+            let Some(file_diff) = entries.diffs.get(path.as_path()) else {
+                return Ok(false);
+            };
+            // A diff can only be re-applied to the file it was computed against
+            let full_path = target_dir.join(path);
+            if !full_path.is_file()
+                || calculate_file_hash(&full_path, patch_data.hash_algorithm)?
+                    != file_diff.original_hash
+            {
+                warn!(
+                    "Cannot repair {}: it is neither the original nor the patched version",
+                    path.display()
+                );
+                return Ok(false);
+            }
+
+            let stored_diff;
+            let file_diff = if patch_data.diffs_in_zip {
+                stored_diff = read_stored_diff(archive, path)?;
+                &stored_diff
+            } else {
+                *file_diff
+            };
+            let content = fs::read_to_string(&full_path)
+                .with_context(|| format!("Failed to read file: {}", full_path.display()))?;
+            let new_content = if file_diff.tokenized {
+                apply_token_changes(&content, &file_diff.changes)
+                    .with_context(|| format!("Failed to patch {}", path.display()))?
+            } else {
+                apply_line_changes(
+                    &content,
+                    &file_diff.changes,
+                    file_diff.line_style_for(&content),
+                )
+            };
+            stage_and_swap(&full_path, |out_path| fs::write(out_path, &new_content))
+                .and_then(|_| LocalTarget::new(target_dir).set_mode(path, file_diff.mode))
+                .with_context(|| format!("Failed to write file: {}", full_path.display()))?;
+            Ok(true)
+        }
+    }
+}
+
+/// Rebuild a file shipped as a binary delta if the original is still in place
+fn repair_binary_delta<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    target_dir: &Path,
+    delta: &BinaryDelta,
+    algorithm: HashAlgorithm,
+) -> Result<bool> {
+    let full_path = target_dir.join(&delta.relative_path);
+    if !full_path.is_file() || calculate_file_hash(&full_path, algorithm)? != delta.original_hash {
+        warn!(
+            "Cannot repair {}: it is neither the original nor the patched version",
+            delta.relative_path.display()
+        );
+        return Ok(false);
+    }
+
+    let temp_dir = tempdir().context("Failed to create temporary directory")?;
+    let rebuilt = temp_dir.path().join("rebuilt");
+    let target = LocalTarget::new(target_dir);
+    rebuild_from_delta(
+        archive,
+        &target,
+        delta,
+        &rebuilt,
+        algorithm,
+        DEFAULT_IO_BUFFER_SIZE,
+    )?;
+    stage_and_swap(&full_path, |out_path| {
+        fs::copy(&rebuilt, out_path).map(|_| ())
+    })
+    .and_then(|_| target.set_mode(&delta.relative_path, delta.mode))
+    .with_context(|| format!("Failed to write file: {}", full_path.display()))?;
+    Ok(true)
+}
+
+/// Write one file from the payload archive into the target directory
+fn extract_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    entry_path: &Path,
+    relative_path: &Path,
+    mode: Option<u32>,
+    target_dir: &Path,
+) -> Result<()> {
+    let mut entry = archive
+        .by_name(&portable_path(entry_path).to_string_lossy())
+        .with_context(|| format!("Patch is missing {}", entry_path.display()))?;
+
+    let dest_path = target_dir.join(relative_path);
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    let mut content = Vec::new();
+    entry
+        .read_to_end(&mut content)
+        .with_context(|| format!("Failed to read {} from the patch", relative_path.display()))?;
+    stage_and_swap(&dest_path, |out_path| {
+        let mut writer = BufWriter::with_capacity(DEFAULT_IO_BUFFER_SIZE, File::create(out_path)?);
+        writer.write_all(&content)?;
+        writer.flush()
+    })
+    .and_then(|_| LocalTarget::new(target_dir).set_mode(relative_path, mode))
+    .with_context(|| format!("Failed to write file: {}", dest_path.display()))
+}
@@ -0,0 +1,182 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What applying a patch did to one path
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Added,
+    Modified,
+    Diffed,
+    DeltaPatched,
+    Renamed,
+    Symlinked,
+    TypeChanged,
+    Removed,
+}
+
+/// Whether the change to a path was made
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    Succeeded,
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    pub action: Action,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+/// How many paths of one action ended in each status
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ActionCounts {
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Per-path outcomes of an apply, recorded from the parallel copy and remove loops
+#[derive(Debug, Default)]
+pub struct Outcomes {
+    files: Mutex<Vec<FileOutcome>>,
+    rolled_back: AtomicBool,
+    tree_hashes: Mutex<(Option<String>, Option<String>)>,
+}
+
+impl Outcomes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, path: &Path, action: Action, status: Status) {
+        self.files.lock().unwrap().push(FileOutcome {
+            path: path.to_path_buf(),
+            action,
+            status,
+        });
+    }
+
+    pub fn succeeded(&self, path: &Path, action: Action) {
+        self.record(path, action, Status::Succeeded);
+    }
+
+    pub fn skipped(&self, path: &Path, action: Action, reason: impl Into<String>) {
+        self.record(
+            path,
+            action,
+            Status::Skipped {
+                reason: reason.into(),
+            },
+        );
+    }
+
+    pub fn failed(&self, path: &Path, action: Action, error: impl ToString) {
+        self.record(
+            path,
+            action,
+            Status::Failed {
+                error: error.to_string(),
+            },
+        );
+    }
+
+    /// Note that the recorded changes were undone after a failure
+    pub fn mark_rolled_back(&self) {
+        self.rolled_back.store(true, Ordering::Relaxed);
+    }
+
+    pub fn rolled_back(&self) -> bool {
+        self.rolled_back.load(Ordering::Relaxed)
+    }
+
+    /// Record the `--merkle` tree hash of the directory before the patch was applied
+    pub fn set_tree_hash_before(&self, hash: String) {
+        self.tree_hashes.lock().unwrap().0 = Some(hash);
+    }
+
+    /// Record the `--merkle` tree hash of the directory after the patch was applied
+    pub fn set_tree_hash_after(&self, hash: String) {
+        self.tree_hashes.lock().unwrap().1 = Some(hash);
+    }
+
+    /// Tree hashes before and after, those that were computed
+    pub fn tree_hashes(&self) -> (Option<String>, Option<String>) {
+        self.tree_hashes.lock().unwrap().clone()
+    }
+
+    /// Count what happened per action, and the skipped paths per reason
+    ///
+    /// A path recorded more than once (written, then failing verification)
+    /// counts with its last status.
+    pub fn counts(&self) -> (BTreeMap<Action, ActionCounts>, BTreeMap<String, usize>) {
+        let files = self.files.lock().unwrap();
+        let mut last: HashMap<(&Path, Action), &Status> = HashMap::new();
+        for outcome in files.iter() {
+            last.insert((outcome.path.as_path(), outcome.action), &outcome.status);
+        }
+
+        let mut counts: BTreeMap<Action, ActionCounts> = BTreeMap::new();
+        let mut skip_reasons: BTreeMap<String, usize> = BTreeMap::new();
+        for ((_, action), status) in last {
+            let action_counts = counts.entry(action).or_default();
+            match status {
+                Status::Succeeded => action_counts.succeeded += 1,
+                Status::Skipped { reason } => {
+                    action_counts.skipped += 1;
+                    *skip_reasons.entry(reason.clone()).or_default() += 1;
+                }
+                Status::Failed { .. } => action_counts.failed += 1,
+            }
+        }
+        (counts, skip_reasons)
+    }
+
+    /// Outcomes sorted by path, for a stable report
+    pub fn into_sorted(self) -> Vec<FileOutcome> {
+        let mut files = self.files.into_inner().unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+}
+
+/// Machine-readable record of an apply, written with `--report`
+#[derive(Serialize, Debug)]
+pub struct ApplyReport {
+    pub patch: PathBuf,
+    pub target: PathBuf,
+    pub succeeded: bool,
+    /// Why the apply failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Changes were undone after a failure, so the files listed are not in place
+    pub rolled_back: bool,
+    /// Start of the apply in UTC, as RFC 3339
+    pub started_at: String,
+    pub duration_ms: u64,
+    /// `--merkle` tree hash of the directory before the patch was applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree_hash_before: Option<String>,
+    /// `--merkle` tree hash of the directory after the patch was applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree_hash_after: Option<String>,
+    pub files: Vec<FileOutcome>,
+}
+
+impl ApplyReport {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content =
+            serde_json::to_vec_pretty(self).context("Failed to serialize apply report")?;
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write report: {}", path.display()))
+    }
+}
@@ -0,0 +1,118 @@
+use crate::diff::{FileDiff, render_file_diff};
+use crate::patch::{PatchData, read_stored_diff};
+use crate::utils::assume_yes;
+use anyhow::{Context, Result, anyhow};
+use dialoguer::Select;
+use std::collections::HashSet;
+use std::io::{IsTerminal, Read, Seek};
+use std::path::{Path, PathBuf};
+
+/// Answer to the question asked for each file by `--interactive`
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+/// Ask about every added, modified, patched and removed file before the apply
+///
+/// Returns the paths that were declined, or `None` if the user quit. `--yes`
+/// accepts everything without asking. Paths in `settled` were already decided
+/// and are not asked about.
+pub fn review_changes<R: Read + Seek>(
+    patch_data: &PatchData,
+    archive: &mut zip::ZipArchive<R>,
+    removals: &[PathBuf],
+    settled: &HashSet<&Path>,
+) -> Result<Option<HashSet<PathBuf>>> {
+    let mut declined = HashSet::new();
+    if assume_yes() {
+        return Ok(Some(declined));
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("Cannot review changes: stdin is not a terminal"));
+    }
+
+    let full_files = patch_data
+        .added_files
+        .iter()
+        .map(|f| ("add", f.relative_path.as_path()))
+        .chain(
+            patch_data
+                .modified_files
+                .iter()
+                .map(|f| ("overwrite", f.relative_path.as_path())),
+        )
+        .chain(
+            patch_data
+                .binary_deltas
+                .iter()
+                .map(|d| ("patch", d.relative_path.as_path())),
+        );
+    for (action, path) in full_files.filter(|(_, path)| !settled.contains(path)) {
+        match ask(action, path)? {
+            Answer::Yes => {}
+            Answer::No => {
+                declined.insert(path.to_path_buf());
+            }
+            Answer::All => return Ok(Some(declined)),
+            Answer::Quit => return Ok(None),
+        }
+    }
+
+    for file_diff in &patch_data.modified_diffs {
+        let path = file_diff.relative_path.as_path();
+        if settled.contains(path) {
+            continue;
+        }
+        if patch_data.diffs_in_zip {
+            print_preview(&read_stored_diff(archive, path)?);
+        } else {
+            print_preview(file_diff);
+        }
+        match ask("patch", path)? {
+            Answer::Yes => {}
+            Answer::No => {
+                declined.insert(path.to_path_buf());
+            }
+            Answer::All => return Ok(Some(declined)),
+            Answer::Quit => return Ok(None),
+        }
+    }
+
+    for path in removals
+        .iter()
+        .filter(|path| !settled.contains(path.as_path()))
+    {
+        match ask("remove", path)? {
+            Answer::Yes => {}
+            Answer::No => {
+                declined.insert(path.clone());
+            }
+            Answer::All => return Ok(Some(declined)),
+            Answer::Quit => return Ok(None),
+        }
+    }
+    Ok(Some(declined))
+}
+
+fn ask(action: &str, path: &Path) -> Result<Answer> {
+    let choice = Select::new()
+        .with_prompt(format!("{} {}?", action, path.display()))
+        .items(&["yes", "no", "all remaining", "quit"])
+        .default(0)
+        .interact()
+        .context("Failed to get user choice")?;
+    Ok(match choice {
+        0 => Answer::Yes,
+        1 => Answer::No,
+        2 => Answer::All,
+        _ => Answer::Quit,
+    })
+}
+
+/// Show what a diff changes before asking about it
+fn print_preview(file_diff: &FileDiff) {
+    print!("{}", render_file_diff(file_diff, console::colors_enabled()));
+}
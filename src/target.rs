@@ -0,0 +1,504 @@
+use crate::diff::{COPY_BLOCK_SIZE, HashAlgorithm, calculate_reader_hash, hash_block, read_block};
+use crate::utils::{DEFAULT_IO_BUFFER_SIZE, create_symlink, file_mode, set_file_mode};
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use log::info;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+/// Content of a file that can be read from any offset
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Source of a file's content, positioned at the given byte offset
+pub type OpenAt<'a> = Box<dyn FnOnce(u64) -> io::Result<Box<dyn Read + 'a>> + 'a>;
+
+/// Storage a patch is applied to, addressed by paths relative to its root
+///
+/// Writes create missing parent directories. Targets without directories,
+/// permissions or links keep the defaults, which do nothing or refuse.
+pub trait PatchTarget: Sync {
+    /// Read the full content of a file
+    fn get(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Open a file to read it from any offset
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(Cursor::new(self.get(path)?)))
+    }
+    /// Hash the content of a file
+    fn hash(&self, path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+        calculate_reader_hash(&mut self.open(path)?, algorithm)
+    }
+    /// Replace the content of a file, creating it if needed
+    fn put(&self, path: &Path, content: &[u8]) -> io::Result<()>;
+    /// Replace the content of a file with what `write` writes
+    ///
+    /// `write` is called again if the first attempt is refused.
+    fn put_with(
+        &self,
+        path: &Path,
+        write: &dyn Fn(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut content = Vec::new();
+        write(&mut content)?;
+        self.put(path, &content)
+    }
+    /// Write a large file block by block, verifying each block against its recorded hash
+    fn put_blocks(&self, path: &Path, block_hashes: &[String], open_at: OpenAt) -> io::Result<()> {
+        let mut content = Vec::new();
+        copy_verified_blocks(&mut open_at(0)?, &mut content, block_hashes, 0)?;
+        self.put(path, &content)
+    }
+    /// Copy a file to another path
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.put(to, &self.get(from)?)
+    }
+    /// Move a file to another path
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.copy(from, to)?;
+        self.delete(from)
+    }
+    /// Remove a file
+    fn delete(&self, path: &Path) -> io::Result<()>;
+    /// Remove a directory and everything below it
+    fn delete_dir(&self, path: &Path) -> io::Result<()>;
+    /// Whether a file or link exists
+    fn exists(&self, path: &Path) -> bool;
+    /// Whether a directory exists
+    fn is_dir(&self, path: &Path) -> bool;
+    /// Every file and link below a directory
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Permissions of a file, if the target records them
+    fn mode(&self, _path: &Path) -> Option<u32> {
+        None
+    }
+    /// Set the permissions of a file, if given and the target records them
+    fn set_mode(&self, _path: &Path, _mode: Option<u32>) -> io::Result<()> {
+        Ok(())
+    }
+    /// Create a symbolic link, replacing whatever is at its path
+    fn symlink(&self, path: &Path, _link_target: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("links are not supported by this target: {}", path.display()),
+        ))
+    }
+    /// Remove a directory if it is empty, returning whether it was removed
+    fn remove_empty_dir(&self, _dir: &Path) -> bool {
+        false
+    }
+}
+
+/// A directory on the local filesystem
+pub struct LocalTarget {
+    root: PathBuf,
+    force: bool,
+    buffer_size: usize,
+}
+
+impl LocalTarget {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            force: false,
+            buffer_size: DEFAULT_IO_BUFFER_SIZE,
+        }
+    }
+
+    /// Make read-only files writable when writing or removing them fails
+    pub fn force(mut self, enabled: bool) -> Self {
+        self.force = enabled;
+        self
+    }
+
+    /// Buffer size in bytes for reading and writing files
+    pub fn io_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Full path of a file, with its parent directory created
+    fn dest_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let dest_path = self.root.join(path);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(dest_path)
+    }
+}
+
+impl PatchTarget for LocalTarget {
+    fn get(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        let file = File::open(self.root.join(path))?;
+        Ok(Box::new(BufReader::with_capacity(self.buffer_size, file)))
+    }
+
+    fn put(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let dest_path = self.dest_path(path)?;
+        retry_writable(&dest_path, self.force, || {
+            stage_and_swap(&dest_path, |out_path| fs::write(out_path, content))
+        })
+    }
+
+    fn put_with(
+        &self,
+        path: &Path,
+        write: &dyn Fn(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let dest_path = self.dest_path(path)?;
+        retry_writable(&dest_path, self.force, || {
+            stage_and_swap(&dest_path, |out_path| {
+                let file = File::create(out_path)?;
+                let mut writer = BufWriter::with_capacity(self.buffer_size, file);
+                write(&mut writer)?;
+                writer.flush()
+            })
+        })
+    }
+
+    fn put_blocks(&self, path: &Path, block_hashes: &[String], open_at: OpenAt) -> io::Result<()> {
+        let dest_path = self.dest_path(path)?;
+        // A retry finds every block already staged and does not read the source again
+        let open_at = std::cell::Cell::new(Some(open_at));
+        retry_writable(&dest_path, self.force, || {
+            write_verified_blocks(&dest_path, block_hashes, |offset| match open_at.take() {
+                Some(open_at) => open_at(offset),
+                None => Err(io::Error::other("the source was already read")),
+            })
+        })
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let src_path = self.root.join(from);
+        let dest_path = self.dest_path(to)?;
+        retry_writable(&dest_path, self.force, || {
+            stage_and_swap(&dest_path, |out_path| {
+                fs::copy(&src_path, out_path).map(|_| ())
+            })
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let dest_path = self.dest_path(to)?;
+        fs::rename(self.root.join(from), dest_path)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let full_path = self.root.join(path);
+        retry_writable(&full_path, self.force, || fs::remove_file(&full_path))
+    }
+
+    fn delete_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(self.root.join(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        // A link counts as existing even if what it points to does not
+        fs::symlink_metadata(self.root.join(path)).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        fs::symlink_metadata(self.root.join(path)).is_ok_and(|m| m.is_dir())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in WalkDir::new(self.root.join(dir)) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                let relative_path = entry
+                    .path()
+                    .strip_prefix(&self.root)
+                    .unwrap_or(entry.path());
+                paths.push(relative_path.to_path_buf());
+            }
+        }
+        Ok(paths)
+    }
+
+    fn mode(&self, path: &Path) -> Option<u32> {
+        fs::metadata(self.root.join(path))
+            .ok()
+            .and_then(|m| file_mode(&m))
+    }
+
+    fn set_mode(&self, path: &Path, mode: Option<u32>) -> io::Result<()> {
+        set_file_mode(&self.root.join(path), mode)
+    }
+
+    fn symlink(&self, path: &Path, link_target: &Path) -> io::Result<()> {
+        let full_path = self.root.join(path);
+        match fs::symlink_metadata(&full_path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&full_path)?,
+            Ok(_) => fs::remove_file(&full_path)?,
+            Err(_) => {
+                self.dest_path(path)?;
+            }
+        }
+        create_symlink(link_target, &full_path)
+    }
+
+    fn remove_empty_dir(&self, dir: &Path) -> bool {
+        let path = self.root.join(dir);
+        fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) && fs::remove_dir(&path).is_ok()
+    }
+}
+
+/// A prefix in an object store, such as an S3 bucket
+///
+/// Object stores have no directories, permissions or links: directories exist
+/// while objects are stored below them, and recorded permissions are ignored.
+pub struct ObjectStoreTarget {
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl ObjectStoreTarget {
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: ObjectPath) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .context("Failed to start the object store runtime")?;
+        Ok(Self {
+            store,
+            prefix,
+            runtime,
+        })
+    }
+
+    /// Open the store a URI like `s3://bucket/prefix` points to
+    ///
+    /// Credentials and settings are read from the environment, e.g.
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_REGION`.
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let url = url::Url::parse(uri).with_context(|| format!("Invalid target URI: {}", uri))?;
+        let settings = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, prefix) = object_store::parse_url_opts(&url, settings)
+            .with_context(|| format!("Failed to open object store: {}", uri))?;
+        Self::new(Arc::from(store), prefix)
+    }
+
+    /// Location of a relative path below the prefix
+    fn location(&self, path: &Path) -> ObjectPath {
+        path.components()
+            .fold(self.prefix.clone(), |location, part| {
+                location.child(part.as_os_str().to_string_lossy().as_ref())
+            })
+    }
+
+    /// Every object below a relative path
+    fn objects(&self, dir: &Path) -> io::Result<Vec<ObjectPath>> {
+        let prefix = self.location(dir);
+        let objects = self.block_on(self.store.list(Some(&prefix)).try_collect::<Vec<_>>())?;
+        Ok(objects.into_iter().map(|meta| meta.location).collect())
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+}
+
+impl PatchTarget for ObjectStoreTarget {
+    fn get(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let location = self.location(path);
+        let bytes = self.block_on(async { self.store.get(&location).await?.bytes().await })?;
+        Ok(bytes.to_vec())
+    }
+
+    fn put(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let payload = PutPayload::from(content.to_vec());
+        self.block_on(self.store.put(&self.location(path), payload))?;
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        Ok(self.block_on(self.store.copy(&self.location(from), &self.location(to)))?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        Ok(self.block_on(self.store.rename(&self.location(from), &self.location(to)))?)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        Ok(self.block_on(self.store.delete(&self.location(path)))?)
+    }
+
+    fn delete_dir(&self, path: &Path) -> io::Result<()> {
+        for location in self.objects(path)? {
+            self.block_on(self.store.delete(&location))?;
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.block_on(self.store.head(&self.location(path))).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.objects(path).is_ok_and(|objects| !objects.is_empty())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .objects(dir)?
+            .iter()
+            .filter_map(|location| location.prefix_match(&self.prefix))
+            .map(|parts| parts.map(|part| part.as_ref().to_string()).collect())
+            .collect())
+    }
+}
+
+/// Run a write, and with `force` retry it once after making `path` writable
+/// if it was refused (e.g. a file marked read-only on Windows)
+pub fn retry_writable<T, F>(path: &Path, force: bool, write: F) -> io::Result<T>
+where
+    F: Fn() -> io::Result<T>,
+{
+    match write() {
+        Err(e) if force && e.kind() == io::ErrorKind::PermissionDenied && path.is_file() => {
+            info!("Clearing read-only flag of {}", path.display());
+            make_writable(path)?;
+            write()
+        }
+        result => result,
+    }
+}
+
+/// Give the owner write permission on a file
+fn make_writable(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions)
+}
+
+/// Sibling path used to stage a file before it is swapped into place
+fn staging_path(dest_path: &Path) -> PathBuf {
+    let file_name = dest_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dest_path.with_file_name(format!(".{}.diffpatch-tmp", file_name))
+}
+
+/// Write a file under a sibling temp name and rename it over the destination
+///
+/// Renaming over a file works in cases where opening it for writing does not
+/// (e.g. a running executable), and readers never observe a partially written
+/// file. Falls back to writing the destination directly only if the rename
+/// fails; an error writing the staged file is returned as is.
+pub fn stage_and_swap<F>(dest_path: &Path, write: F) -> io::Result<()>
+where
+    F: Fn(&Path) -> io::Result<()>,
+{
+    let staged = staging_path(dest_path);
+    if let Err(err) = write(&staged) {
+        let _ = fs::remove_file(&staged);
+        return Err(err);
+    }
+    if fs::rename(&staged, dest_path).is_err() {
+        let _ = fs::remove_file(&staged);
+        return write(dest_path);
+    }
+    Ok(())
+}
+
+/// Write a large file block by block, verifying each block against its recorded hash
+///
+/// Verified blocks left in the staging file by an interrupted run are kept,
+/// so the write resumes at the first missing or damaged block. `open_at` is
+/// given the offset of that block and returns the source positioned there.
+/// The staging file is only renamed over the destination once every block matches.
+pub fn write_verified_blocks<R, F>(
+    dest_path: &Path,
+    block_hashes: &[String],
+    open_at: F,
+) -> io::Result<()>
+where
+    R: Read,
+    F: FnOnce(u64) -> io::Result<R>,
+{
+    let staged = staging_path(dest_path);
+    let mut staged_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&staged)?;
+
+    // Keep the verified prefix of a previous attempt
+    let mut buf = vec![0u8; COPY_BLOCK_SIZE];
+    let mut resume_at = 0;
+    for expected in block_hashes {
+        let n = read_block(&mut staged_file, &mut buf)?;
+        if n == 0 || hash_block(&buf[..n]) != *expected {
+            break;
+        }
+        resume_at += 1;
+    }
+    if resume_at > 0 {
+        info!(
+            "Resuming copy of {} at block {}/{}",
+            dest_path.display(),
+            resume_at,
+            block_hashes.len()
+        );
+    }
+
+    // Every block before the first missing one is full size
+    if resume_at < block_hashes.len() {
+        let offset = (resume_at * COPY_BLOCK_SIZE) as u64;
+        staged_file.set_len(offset)?;
+        staged_file.seek(SeekFrom::Start(offset))?;
+        let mut src = open_at(offset)?;
+        copy_verified_blocks(
+            &mut src,
+            &mut staged_file,
+            &block_hashes[resume_at..],
+            resume_at,
+        )?;
+    }
+    staged_file.sync_all()?;
+    drop(staged_file);
+
+    fs::rename(&staged, dest_path)
+}
+
+/// Copy blocks from `src` to `dest`, failing at the first that does not match its hash
+///
+/// `first_index` is the number of the first block, used in the error.
+fn copy_verified_blocks(
+    src: &mut impl Read,
+    dest: &mut impl Write,
+    block_hashes: &[String],
+    first_index: usize,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_BLOCK_SIZE];
+    for (index, expected) in block_hashes.iter().enumerate() {
+        let n = read_block(src, &mut buf)?;
+        if hash_block(&buf[..n]) != *expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("block {} failed hash verification", first_index + index),
+            ));
+        }
+        dest.write_all(&buf[..n])?;
+    }
+    Ok(())
+}
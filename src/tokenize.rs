@@ -0,0 +1,78 @@
+use crate::diff::DiffChange;
+use anyhow::{Result, anyhow};
+use std::path::Path;
+
+/// Extensions of languages whose blocks are delimited by braces and semicolons
+const C_LIKE_EXTENSIONS: &[&str] = &[
+    "c", "h", "cc", "cpp", "cxx", "hpp", "hh", "cs", "java", "js", "jsx", "ts", "tsx", "rs", "go",
+    "swift", "kt", "scala", "php", "css", "scss",
+];
+
+/// Whether a file is source code the brace/indent-aware tokenizer understands
+pub fn is_c_like(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            C_LIKE_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+/// Split source code into indentation, brace/semicolon-terminated segments and line endings
+///
+/// Concatenating the tokens reproduces the input exactly, so a reindented
+/// block only changes its indentation tokens.
+pub fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    for line in text.split_inclusive('\n') {
+        let (body, ending) = line.split_at(line.trim_end_matches(['\r', '\n']).len());
+        let (indent, code) = body.split_at(body.len() - body.trim_start().len());
+        if !indent.is_empty() {
+            tokens.push(indent);
+        }
+
+        let mut start = 0;
+        for (i, c) in code.char_indices() {
+            if matches!(c, '{' | '}' | ';') {
+                tokens.push(&code[start..=i]);
+                start = i + 1;
+            }
+        }
+        if start < code.len() {
+            tokens.push(&code[start..]);
+        }
+
+        if !ending.is_empty() {
+            tokens.push(ending);
+        }
+    }
+    tokens
+}
+
+/// Apply token-level changes to the content they were computed against
+///
+/// Fails if a change lies outside the content, which then isn't the content
+/// the diff was made from.
+pub fn apply_token_changes(content: &str, changes: &[DiffChange]) -> Result<String> {
+    let mut tokens: Vec<&str> = tokenize(content);
+
+    // Changes are in ascending order; applying them back to front keeps earlier ranges valid
+    for change in changes.iter().rev() {
+        let Some((start, len)) = change.old_range else {
+            return Err(anyhow!("Token change has no range in the original content"));
+        };
+        let end = start + len;
+        if end > tokens.len() {
+            return Err(anyhow!(
+                "Token change {}..{} is outside the {} tokens of the content",
+                start,
+                end,
+                tokens.len()
+            ));
+        }
+        tokens.splice(start..end, std::iter::once(change.content.as_str()));
+    }
+
+    Ok(tokens.concat())
+}
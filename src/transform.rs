@@ -0,0 +1,113 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Line ending to write text files with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EolMode {
+    Lf,
+    Crlf,
+    Native,
+}
+
+impl EolMode {
+    /// Resolve `Native` to the platform line ending
+    fn resolve(self) -> EolMode {
+        match self {
+            EolMode::Native if cfg!(windows) => EolMode::Crlf,
+            EolMode::Native => EolMode::Lf,
+            other => other,
+        }
+    }
+}
+
+/// Line ending and final newline convention of a text file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineStyle {
+    pub crlf: bool,             // dominant line ending is CRLF
+    pub trailing_newline: bool, // last line is terminated
+}
+
+impl LineStyle {
+    /// Detect the dominant line ending and whether the text ends with a newline
+    pub fn detect(text: &str) -> LineStyle {
+        let newlines = text.matches('\n').count();
+        let crlf = text.matches("\r\n").count();
+        LineStyle {
+            crlf: crlf * 2 > newlines,
+            trailing_newline: text.ends_with('\n'),
+        }
+    }
+
+    /// Join lines split by `str::lines` back together using this style
+    pub fn join(self, lines: &[&str]) -> String {
+        let ending = if self.crlf { "\r\n" } else { "\n" };
+        let mut text = lines.join(ending);
+        if self.trailing_newline && !lines.is_empty() {
+            text.push_str(ending);
+        }
+        text
+    }
+}
+
+/// Bytes of a file looked at to tell text from binary content
+const SNIFF_LEN: usize = 8192;
+
+/// Heuristic check for binary content in the first 8KB: NUL bytes, invalid
+/// UTF-8, or more than one control character in ten
+pub fn looks_binary(content: &[u8]) -> bool {
+    let sample = &content[..content.len().min(SNIFF_LEN)];
+    if sample.contains(&0) {
+        return true;
+    }
+    // Tabs, line breaks, form feeds and escape sequences are common in text
+    let control = sample
+        .iter()
+        .filter(|&&b| b == 0x7f || (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)))
+        .count();
+    if control * 10 > sample.len() {
+        return true;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => false,
+        // A multi-byte character cut off at the end of the sample is still text
+        Err(e) => e.error_len().is_some(),
+    }
+}
+
+/// Whether a file looks binary, reading only its first 8KB
+pub fn file_looks_binary(path: &Path) -> io::Result<bool> {
+    let mut sample = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut sample)?;
+    Ok(looks_binary(&sample))
+}
+
+/// Convert all line endings in text content to the requested style
+pub fn convert_eol(content: &[u8], mode: EolMode) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(content.len());
+    let mut iter = content.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        if byte == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+
+    match mode.resolve() {
+        EolMode::Crlf => {
+            let mut converted = Vec::with_capacity(normalized.len() + normalized.len() / 32);
+            for byte in normalized {
+                if byte == b'\n' {
+                    converted.push(b'\r');
+                }
+                converted.push(byte);
+            }
+            converted
+        }
+        _ => normalized,
+    }
+}
@@ -1,7 +1,39 @@
-use anyhow::{Context, Result};
-use dialoguer::Confirm;
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use console::Term;
+use dialoguer::{Confirm, Password};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle, TermLike};
+use log::warn;
 use std::env;
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Default size of buffered readers and writers (64KB)
+pub const DEFAULT_IO_BUFFER_SIZE: usize = 65536;
+
+static DEFAULT_YES: AtomicBool = AtomicBool::new(false);
+
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+static PROGRESS_MODE: AtomicU8 = AtomicU8::new(ProgressMode::Auto as u8);
+
+/// Time between the plain progress lines written when stderr is not a terminal
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Environment variable that answers yes to every confirmation, like `--yes`
+pub const ASSUME_YES_ENV: &str = "DIFFPATCH_ASSUME_YES";
+
+/// Environment variable holding the passphrase of encrypted patches
+pub const PASSPHRASE_ENV: &str = "DIFFPATCH_PASSPHRASE";
 
 /// Check if path exists, return error if it doesn't
 pub fn check_path_exists(path: &Path, path_type: &str) -> Result<()> {
@@ -28,25 +60,859 @@ pub fn check_is_directory(path: &Path) -> Result<()> {
     }
 }
 
-/// Interactive confirmation
-pub fn confirm_action(message: &str) -> Result<bool> {
+/// Read a list of relative paths from a file, one per line
+///
+/// Blank lines and lines starting with `#` are ignored. Paths that are not
+/// inside the directory they will be joined to are rejected.
+pub fn read_path_list(path: &Path) -> Result<Vec<PathBuf>> {
+    read_pattern_list(path)?
+        .into_iter()
+        .map(|line| {
+            let entry = PathBuf::from(line);
+            check_contained_path(&entry)
+                .with_context(|| format!("Invalid entry in file list: {}", path.display()))?;
+            Ok(entry)
+        })
+        .collect()
+}
+
+/// Fail unless a relative path stays inside the directory it is joined to
+///
+/// A root, drive prefix or `..` component would let a file list or a patch
+/// reach files outside the directory being patched.
+pub fn check_contained_path(path: &Path) -> Result<()> {
+    let contained = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !contained {
+        return Err(anyhow!(
+            "Path must be relative and may not contain '..': {}",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a path list entry is a glob pattern rather than a literal path
+pub fn is_glob_pattern(entry: &Path) -> bool {
+    entry.to_string_lossy().contains(['*', '?', '[', '{'])
+}
+
+/// Build a matcher for glob patterns over `/`-separated relative paths
+pub fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid glob pattern: {}", pattern))?;
+        builder.add(glob);
+    }
+    builder.build().context("Failed to build glob matcher")
+}
+
+/// Build a matcher for gitignore-style exclude patterns
+///
+/// A pattern without a slash matches at any depth, a leading slash anchors it
+/// to the directory root and a trailing slash only matches directories.
+/// Matching a directory excludes everything below it. Negated (`!`)
+/// patterns are not supported and are skipped with a warning.
+pub fn build_exclude_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut globs = Vec::new();
+    for pattern in patterns {
+        if pattern.starts_with('!') {
+            warn!(
+                "Negated exclude patterns are not supported, ignoring: {}",
+                pattern
+            );
+            continue;
+        }
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let glob = match trimmed.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if trimmed.contains('/') => trimmed.to_string(),
+            None => format!("**/{}", trimmed),
+        };
+        if !dir_only {
+            globs.push(glob.clone());
+        }
+        globs.push(format!("{}/**", glob));
+    }
+    build_glob_set(&globs)
+}
+
+/// Read gitignore-style patterns from a file, one per line
+///
+/// Blank lines and lines starting with `#` are ignored.
+pub fn read_pattern_list(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Recursively copy a directory tree into a new directory
+///
+/// `fs::copy` uses copy_file_range on Linux, which shares extents
+/// (a reflink) on filesystems that support it.
+pub fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry.with_context(|| format!("Failed to read {}", src.display()))?;
+        let relative_path = entry.path().strip_prefix(src).unwrap_or(entry.path());
+        let dest_path = dst.join(relative_path);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest_path)
+                .with_context(|| format!("Failed to create directory: {}", dest_path.display()))?;
+        } else if entry.file_type().is_symlink() {
+            // Links are copied as links, not as the entries they point to
+            let link_target = fs::read_link(entry.path())
+                .with_context(|| format!("Failed to read link: {}", entry.path().display()))?;
+            create_symlink(&link_target, &dest_path)
+                .with_context(|| format!("Failed to create link: {}", dest_path.display()))?;
+        } else {
+            fs::copy(entry.path(), &dest_path)
+                .with_context(|| format!("Failed to copy file: {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Create a symbolic link at `link` pointing to `target`
+#[cfg(unix)]
+pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Create a symbolic link at `link` pointing to `target`
+///
+/// Windows needs to know whether the link points to a directory, so the
+/// target is resolved relative to the link's directory to find out.
+#[cfg(windows)]
+pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn create_symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symbolic links are not supported on this platform",
+    ))
+}
+
+/// Permission bits of a file, on platforms that have them
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Give a written file recorded permission bits, if there are any
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_path: &Path, _mode: Option<u32>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the volume holding `path`
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    let stat = rustix::fs::statvfs(path).ok()?;
+    Some(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Bytes available to the current user on the volume holding `path`
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    let path = wide_path(path);
+    let mut available = 0u64;
+    // SAFETY: `path` is NUL-terminated and the totals we don't need may be null
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// NUL-terminated UTF-16 form of a path, for Windows API calls
+#[cfg(windows)]
+fn wide_path(path: &Path) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+/// Report the files a parallel step could not handle
+///
+/// They fail the step unless `continue_on_error` is set, in which case each one
+/// is logged and skipped. Applying passes `false`, since a patch is applied
+/// completely or rolled back.
+pub fn collected_failures(step: &str, errors: Vec<String>, continue_on_error: bool) -> Result<()> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    if continue_on_error {
+        for error in &errors {
+            warn!("Failed to {}, skipped: {}", step, error);
+        }
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Failed to {} {} files:\n  {}",
+        step,
+        errors.len(),
+        errors.join("\n  ")
+    ))
+}
+
+/// A relative path as stored in a patch, with `/` separators on every platform
+pub fn portable_path(path: &Path) -> PathBuf {
+    if std::path::MAIN_SEPARATOR == '/' {
+        return path.to_path_buf();
+    }
+    PathBuf::from(
+        path.to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "/"),
+    )
+}
+
+/// A relative path read from a patch, with the native separator
+pub fn native_path(path: &Path) -> PathBuf {
+    if std::path::MAIN_SEPARATOR == '/' {
+        return path.to_path_buf();
+    }
+    PathBuf::from(
+        path.to_string_lossy()
+            .replace('/', std::path::MAIN_SEPARATOR_STR),
+    )
+}
+
+/// Whether two existing paths are on the same volume
+#[cfg(unix)]
+pub fn same_volume(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Whether two existing paths are on the same volume
+///
+/// Compares the mount points the paths are under, such as `C:\` or a volume
+/// mounted in a folder.
+#[cfg(windows)]
+pub fn same_volume(a: &Path, b: &Path) -> bool {
+    if !(a.exists() && b.exists()) {
+        return false;
+    }
+    match (volume_path(a), volume_path(b)) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => false,
+    }
+}
+
+/// Mount point of the volume holding `path`
+#[cfg(windows)]
+fn volume_path(path: &Path) -> Option<String> {
+    use windows_sys::Win32::Storage::FileSystem::GetVolumePathNameW;
+    let path = wide_path(path);
+    // The mount point is never longer than the path it was found for
+    let mut volume = vec![0u16; path.len().max(261)];
+    // SAFETY: `path` is NUL-terminated and `volume` holds as many characters as passed
+    let ok = unsafe { GetVolumePathNameW(path.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) };
+    if ok == 0 {
+        return None;
+    }
+    let len = volume.iter().position(|&c| c == 0)?;
+    Some(String::from_utf16_lossy(&volume[..len]))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn same_volume(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Fail early if the volume holding `dir` has less than `needed` bytes free
+///
+/// Passes when the free space cannot be determined.
+pub fn check_free_space(dir: &Path, needed: u64) -> Result<()> {
+    const MB: u64 = 1024 * 1024;
+    match available_space(dir) {
+        Some(available) if available < needed => Err(anyhow!(
+            "Not enough free space in {}: {} MB needed, {} MB available",
+            dir.display(),
+            needed.div_ceil(MB),
+            available / MB
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Create a temporary working directory inside `dir`, or in the system
+/// temporary directory (`TMPDIR`, `TEMP` on Windows) if none is given
+pub fn create_temp_dir(dir: Option<&Path>) -> Result<TempDir> {
+    let Some(dir) = dir else {
+        return tempfile::tempdir().context("Failed to create temporary directory");
+    };
+    check_path_exists(dir, "Temporary directory").context("Temporary directory check failed")?;
+    check_is_directory(dir).context("Temporary directory check failed")?;
+    tempfile::Builder::new()
+        .prefix(".diffpatch-")
+        .tempdir_in(dir)
+        .with_context(|| format!("Failed to create temporary directory in {}", dir.display()))
+}
+
+/// Like `create_temp_dir`, but named after `seed` instead of at random
+///
+/// The first free `.diffpatch-<seed>-<n>` name is taken, so runs with the same
+/// seed use the same paths while concurrent runs still get their own directory.
+pub fn create_seeded_temp_dir(dir: Option<&Path>, seed: u64) -> Result<TempDir> {
+    let parent = match dir {
+        Some(dir) => {
+            check_path_exists(dir, "Temporary directory")
+                .context("Temporary directory check failed")?;
+            check_is_directory(dir).context("Temporary directory check failed")?;
+            dir.to_path_buf()
+        }
+        None => env::temp_dir(),
+    };
+    let mut attempt = 0u32;
+    loop {
+        let created = tempfile::Builder::new()
+            .prefix(&format!(".diffpatch-{:016x}-{}", seed, attempt))
+            .rand_bytes(0)
+            .tempdir_in(&parent);
+        match created {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            created => {
+                return created.with_context(|| {
+                    format!(
+                        "Failed to create temporary directory in {}",
+                        parent.display()
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Interactive confirmation, answering `default` when Enter is pressed.
+/// `--default-yes` turns the default into yes for every prompt, and `--yes`
+/// skips the prompt altogether.
+pub fn confirm_action(message: &str, default: bool) -> Result<bool> {
+    if assume_yes() {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Cannot ask \"{}\": stdin is not a terminal. Pass --yes or set {}=1 to answer yes \
+             without prompting.",
+            message,
+            ASSUME_YES_ENV
+        ));
+    }
+    confirm_prompt(message, prompt_default(default))
+}
+
+/// Answer pressing Enter gives at a prompt whose own default is `default`
+pub fn prompt_default(default: bool) -> bool {
+    default || default_yes()
+}
+
+/// Interactive confirmation that neither `--default-yes` nor `--yes` answers
+///
+/// Fails instead of waiting forever when there is no terminal to answer it.
+pub fn confirm_prompt(message: &str, default: bool) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Cannot ask \"{}\": stdin is not a terminal",
+            message
+        ));
+    }
     Confirm::new()
         .with_prompt(message)
-        .default(false)
+        .default(default)
         .interact()
         .context("Failed to get user confirmation")
 }
 
-/// Get IO thread count from environment or use reasonable default
-pub fn get_io_thread_count() -> usize {
-    match env::var("DIFFPATCH_IO_THREADS") {
-        Ok(val) => val.parse().unwrap_or_else(|_| {
-            let cpus = num_cpus::get();
-            std::cmp::min(cpus, 4)
-        }),
-        Err(_) => {
-            let cpus = num_cpus::get();
-            std::cmp::min(cpus, 4)
+/// Passphrase from `DIFFPATCH_PASSPHRASE`, or asked for on the terminal
+///
+/// With `confirm` the passphrase has to be typed twice, as when encrypting.
+pub fn read_passphrase(message: &str, confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV)
+        && !passphrase.is_empty()
+    {
+        return Ok(passphrase);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Cannot ask for the passphrase: stdin is not a terminal. Set {} instead.",
+            PASSPHRASE_ENV
+        ));
+    }
+    let mut prompt = Password::new().with_prompt(message);
+    if confirm {
+        prompt = prompt.with_confirmation("Repeat passphrase", "Passphrases do not match");
+    }
+    prompt.interact().context("Failed to read passphrase")
+}
+
+/// Create a thread pool with limited threads to avoid I/O contention
+pub fn build_io_pool(threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap_or_else(|_| rayon::ThreadPoolBuilder::new().build().unwrap())
+}
+
+/// Kind of storage backing a path, used to pick a sensible I/O parallelism
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Ssd,
+    Hdd,
+    Network,
+    Unknown,
+}
+
+/// Check whether a user is attached to stdin and stderr
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Print why the patch failed, then block until the user presses Enter
+///
+/// The reason is written and flushed before waiting, since a console window
+/// opened for a double-clicked patch closes as soon as the process exits.
+pub fn show_error_and_wait(
+    error: &anyhow::Error,
+    output: &mut impl Write,
+    input: &mut impl BufRead,
+) -> io::Result<()> {
+    writeln!(output, "Error: {:#}", error)?;
+    writeln!(output, "Press Enter to exit...")?;
+    output.flush()?;
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(())
+}
+
+/// Get IO thread count for work on the given path
+///
+/// `threads` from the options takes precedence, 0 meaning one per logical
+/// core, then `DIFFPATCH_IO_THREADS`; otherwise the storage backing `path` is
+/// probed and the default is chosen from its kind.
+pub fn get_io_thread_count(path: &Path, threads: Option<usize>) -> usize {
+    if threads.is_some() {
+        return io_thread_count(StorageKind::Unknown, threads);
+    }
+    io_thread_count(detect_storage_kind(path), threads)
+}
+
+/// Threads for I/O on a kind of storage, unless set in the options or the environment
+pub fn io_thread_count(kind: StorageKind, threads: Option<usize>) -> usize {
+    match threads {
+        Some(0) => return num_cpus::get(),
+        Some(threads) => return threads,
+        None => {}
+    }
+    if let Ok(val) = env::var("DIFFPATCH_IO_THREADS")
+        && let Ok(count) = val.parse::<usize>()
+    {
+        return count;
+    }
+
+    default_thread_count(kind)
+}
+
+/// Default IO thread count for a kind of storage
+pub fn default_thread_count(kind: StorageKind) -> usize {
+    let cpus = num_cpus::get();
+    match kind {
+        // Solid state storage handles deep queues well
+        StorageKind::Ssd => std::cmp::min(cpus, 16),
+        // Seeking disks and network mounts degrade with many concurrent requests
+        StorageKind::Hdd | StorageKind::Network => std::cmp::min(cpus, 2),
+        StorageKind::Unknown => std::cmp::min(cpus, 4),
+    }
+}
+
+/// Detect the kind of storage a path lives on
+#[cfg(target_os = "linux")]
+pub fn detect_storage_kind(path: &Path) -> StorageKind {
+    use std::fs;
+
+    let Ok(path) = fs::canonicalize(path) else {
+        return StorageKind::Unknown;
+    };
+    let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+        return StorageKind::Unknown;
+    };
+
+    // Find the mount with the longest mount point containing the path
+    // Format: id parent major:minor root mount_point options ... - fstype source ...
+    let mut best: Option<(usize, &str, &str)> = None;
+    for line in mountinfo.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(sep) = fields.iter().position(|f| *f == "-") else {
+            continue;
+        };
+        if fields.len() < 5 || sep + 1 >= fields.len() {
+            continue;
+        }
+        let mount_point = Path::new(fields[4]);
+        if path.starts_with(mount_point) {
+            let depth = mount_point.components().count();
+            if best.is_none_or(|(d, _, _)| depth >= d) {
+                best = Some((depth, fields[2], fields[sep + 1]));
+            }
         }
     }
+
+    let Some((_, device, fs_type)) = best else {
+        return StorageKind::Unknown;
+    };
+
+    if matches!(
+        fs_type,
+        "nfs" | "nfs4" | "cifs" | "smb3" | "smbfs" | "9p" | "fuse.sshfs" | "afs" | "ceph"
+    ) {
+        return StorageKind::Network;
+    }
+
+    // Partitions have no queue directory, so fall back to the parent device
+    let Ok(device_dir) = fs::canonicalize(format!("/sys/dev/block/{}", device)) else {
+        return StorageKind::Unknown;
+    };
+    let rotational = fs::read_to_string(device_dir.join("queue/rotational")).or_else(|_| {
+        fs::read_to_string(
+            device_dir
+                .parent()
+                .unwrap_or(&device_dir)
+                .join("queue/rotational"),
+        )
+    });
+
+    match rotational.as_deref().map(str::trim) {
+        Ok("0") => StorageKind::Ssd,
+        Ok("1") => StorageKind::Hdd,
+        _ => StorageKind::Unknown,
+    }
+}
+
+/// Detect the kind of storage a path lives on
+#[cfg(not(target_os = "linux"))]
+pub fn detect_storage_kind(_path: &Path) -> StorageKind {
+    StorageKind::Unknown
+}
+
+/// Buffer size for buffered file I/O, `DEFAULT_IO_BUFFER_SIZE` unless the options set one
+pub fn io_buffer_size(size: Option<NonZeroUsize>) -> usize {
+    size.map_or(DEFAULT_IO_BUFFER_SIZE, NonZeroUsize::get)
+}
+
+/// Make pressing Enter at a confirmation prompt answer yes
+pub fn set_default_yes(enabled: bool) {
+    DEFAULT_YES.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether pressing Enter at a confirmation prompt answers yes
+pub fn default_yes() -> bool {
+    DEFAULT_YES.load(Ordering::Relaxed)
+}
+
+pub fn set_assume_yes(enabled: bool) {
+    ASSUME_YES.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether confirmations are answered yes without prompting
+///
+/// Set by `--yes`, or by `DIFFPATCH_ASSUME_YES` for patch executables that
+/// are started by scripts without arguments.
+pub fn assume_yes() -> bool {
+    ASSUME_YES.load(Ordering::Relaxed)
+        || env::var(ASSUME_YES_ENV).is_ok_and(|v| !matches!(v.as_str(), "" | "0" | "false"))
+}
+
+/// Run an I/O operation, retrying with exponential backoff on failure
+///
+/// Freshly written executables are often locked briefly by antivirus
+/// scanners, so transient failures are retried before giving up.
+pub fn retry_with_backoff<T, F>(what: &str, attempts: u32, mut op: F) -> std::io::Result<T>
+where
+    F: FnMut() -> std::io::Result<T>,
+{
+    let mut delay = Duration::from_millis(200);
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts => {
+                warn!(
+                    "{} failed (attempt {}/{}): {}. Retrying in {} ms...",
+                    what,
+                    attempt,
+                    attempts,
+                    err,
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// When progress is shown, set with `--progress`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Progress bars on a terminal, plain progress lines otherwise
+    #[default]
+    Auto,
+    /// Progress bars even when stderr is not a terminal
+    Always,
+    /// No progress output
+    Never,
+}
+
+/// Choose how progress is shown
+pub fn set_progress_mode(mode: ProgressMode) {
+    PROGRESS_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+fn progress_mode() -> ProgressMode {
+    match PROGRESS_MODE.load(Ordering::Relaxed) {
+        m if m == ProgressMode::Always as u8 => ProgressMode::Always,
+        m if m == ProgressMode::Never as u8 => ProgressMode::Never,
+        _ => ProgressMode::Auto,
+    }
+}
+
+/// Progress bar for a step over `len` items
+///
+/// When stderr is not a terminal (log files, CI), `auto` writes a plain line
+/// every few seconds instead of redrawing a bar with escape sequences.
+pub fn progress_bar(len: u64, message: &'static str) -> ProgressBar {
+    let pb = match progress_mode() {
+        ProgressMode::Never => return ProgressBar::hidden(),
+        ProgressMode::Auto if !io::stderr().is_terminal() => {
+            let lines = PlainLines::default();
+            let finished = lines.finished.clone();
+            let pb = ProgressBar::with_draw_target(
+                Some(len),
+                ProgressDrawTarget::term_like(Box::new(lines)),
+            );
+            // The empty `{finished}` key lets the final count through the throttling
+            let template = "{msg} {pos}/{len} [{elapsed_precise}]{finished}";
+            let style = ProgressStyle::with_template(template).unwrap().with_key(
+                "finished",
+                move |state: &ProgressState, _: &mut dyn fmt::Write| {
+                    finished.store(state.is_finished(), Ordering::Relaxed);
+                },
+            );
+            pb.set_style(style);
+            pb.set_message(message);
+            return pb;
+        }
+        ProgressMode::Auto => ProgressBar::new(len),
+        // Drawn through `TermLike`, which unlike a `Term` target is not hidden off a terminal
+        ProgressMode::Always => ProgressBar::with_draw_target(
+            Some(len),
+            ProgressDrawTarget::term_like(Box::new(Term::stderr())),
+        ),
+    };
+    pb.set_message(message);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} {msg:<25.bold.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    pb
+}
+
+/// Draw target writing progress updates as lines of their own, at most one per
+/// interval and once more when the bar is finished
+#[derive(Debug, Default)]
+struct PlainLines {
+    last_written: Mutex<Option<Instant>>,
+    finished: Arc<AtomicBool>,
+}
+
+impl TermLike for PlainLines {
+    fn width(&self) -> u16 {
+        80
+    }
+
+    fn move_cursor_up(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _n: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.write_str(s)
+    }
+
+    // Bars are drawn as the line itself, a leading `\r` and trailing padding
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        let line = s.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let mut last_written = self.last_written.lock().unwrap();
+        if !self.finished.load(Ordering::Relaxed)
+            && last_written.is_some_and(|at| at.elapsed() < PLAIN_PROGRESS_INTERVAL)
+        {
+            return Ok(());
+        }
+        eprintln!("{}", line);
+        *last_written = Some(Instant::now());
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Output shared with the input, which records what was shown before it was read
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Enter {
+        output: Rc<RefCell<Vec<u8>>>,
+        shown_before_read: Option<String>,
+    }
+
+    impl io::Read for Enter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let shown = String::from_utf8_lossy(&self.output.borrow()).into_owned();
+            self.shown_before_read.get_or_insert(shown);
+            buf[0] = b'\n';
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn failure_reason_is_shown_before_waiting_for_enter() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut enter = Enter {
+            output: output.clone(),
+            shown_before_read: None,
+        };
+        let error = anyhow!("Directory verification failed").context("Patch was not applied");
+        show_error_and_wait(
+            &error,
+            &mut SharedOutput(output),
+            &mut io::BufReader::new(&mut enter),
+        )
+        .unwrap();
+        let shown = enter.shown_before_read.unwrap();
+        assert!(
+            shown.contains("Error: Patch was not applied: Directory verification failed"),
+            "{}",
+            shown
+        );
+    }
+
+    #[test]
+    fn retry_succeeds_after_a_transient_failure() {
+        let mut attempts = 0;
+        let result = retry_with_backoff("Copying executable", 3, || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "locked by a scanner",
+                ))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn retry_gives_up_after_the_last_attempt() {
+        let mut attempts = 0;
+        let result: io::Result<()> = retry_with_backoff("Copying executable", 2, || {
+            attempts += 1;
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "locked by a scanner",
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
 }
@@ -0,0 +1,180 @@
+use crate::diff::{EntryKind, HashAlgorithm, calculate_file_hash};
+use crate::patch::{PatchData, extract_patch_data, find_glob_matches};
+use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A difference between a directory and a patch's intended end state
+#[derive(Debug)]
+pub enum Drift {
+    Missing(PathBuf),
+    HashMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    NotRemoved(PathBuf),
+    WrongType {
+        path: PathBuf,
+        expected: EntryKind,
+    },
+    WrongLink {
+        path: PathBuf,
+        expected: PathBuf,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Missing(path) => write!(f, "missing: {}", path.display()),
+            Drift::HashMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "hash mismatch: {} (expected {}, found {})",
+                path.display(),
+                expected,
+                actual
+            ),
+            Drift::NotRemoved(path) => write!(f, "not removed: {}", path.display()),
+            Drift::WrongType { path, expected } => {
+                write!(
+                    f,
+                    "wrong type: {} (expected {:?})",
+                    path.display(),
+                    expected
+                )
+            }
+            Drift::WrongLink { path, expected } => write!(
+                f,
+                "wrong link: {} (expected -> {})",
+                path.display(),
+                expected.display()
+            ),
+        }
+    }
+}
+
+/// Check that a file exists with the expected hash
+fn check_file(
+    target_dir: &Path,
+    relative_path: &Path,
+    expected: &str,
+    algorithm: HashAlgorithm,
+) -> Result<Option<Drift>> {
+    let full_path = target_dir.join(relative_path);
+    if !full_path.is_file() {
+        return Ok(Some(Drift::Missing(relative_path.to_path_buf())));
+    }
+
+    let actual = calculate_file_hash(&full_path, algorithm)?;
+    if actual != expected {
+        return Ok(Some(Drift::HashMismatch {
+            path: relative_path.to_path_buf(),
+            expected: expected.to_string(),
+            actual,
+        }));
+    }
+    Ok(None)
+}
+
+/// Compare a directory against the end state described by patch data
+pub fn find_drift(patch_data: &PatchData, target_dir: &Path) -> Result<Vec<Drift>> {
+    let mut drift = Vec::new();
+
+    for file_info in patch_data
+        .added_files
+        .iter()
+        .chain(patch_data.modified_files.iter())
+    {
+        drift.extend(check_file(
+            target_dir,
+            &file_info.relative_path,
+            &file_info.hash,
+            patch_data.hash_algorithm,
+        )?);
+    }
+
+    for file_diff in &patch_data.modified_diffs {
+        drift.extend(check_file(
+            target_dir,
+            &file_diff.relative_path,
+            &file_diff.hash,
+            patch_data.hash_algorithm,
+        )?);
+    }
+
+    for delta in &patch_data.binary_deltas {
+        drift.extend(check_file(
+            target_dir,
+            &delta.relative_path,
+            &delta.hash,
+            patch_data.hash_algorithm,
+        )?);
+    }
+
+    for rename in &patch_data.renamed_files {
+        match check_file(
+            target_dir,
+            &rename.to,
+            &rename.hash,
+            patch_data.hash_algorithm,
+        )? {
+            // Repairing this moves the old file into place, so it isn't reported too
+            Some(drift_at_new_path) => drift.push(drift_at_new_path),
+            None if fs::symlink_metadata(target_dir.join(&rename.from)).is_ok() => {
+                drift.push(Drift::NotRemoved(rename.from.clone()))
+            }
+            None => {}
+        }
+    }
+
+    for link in &patch_data.symlinks {
+        let full_path = target_dir.join(&link.relative_path);
+        if fs::read_link(&full_path).ok().as_ref() != Some(&link.target) {
+            drift.push(Drift::WrongLink {
+                path: link.relative_path.clone(),
+                expected: link.target.clone(),
+            });
+        }
+    }
+
+    for path in &patch_data.removed_files {
+        if fs::symlink_metadata(target_dir.join(path)).is_ok() {
+            drift.push(Drift::NotRemoved(path.clone()));
+        }
+    }
+
+    let written: Vec<&Path> = patch_data.written_paths().collect();
+    for path in find_glob_matches(target_dir, &patch_data.removed_globs)? {
+        if !written.contains(&path.as_path()) {
+            drift.push(Drift::NotRemoved(path));
+        }
+    }
+
+    for change in &patch_data.type_changes {
+        let full_path = target_dir.join(&change.relative_path);
+        let matches = match change.new_kind {
+            EntryKind::File => full_path.is_file(),
+            EntryKind::Directory => full_path.is_dir(),
+        };
+        if !matches {
+            drift.push(Drift::WrongType {
+                path: change.relative_path.clone(),
+                expected: change.new_kind,
+            });
+        }
+    }
+
+    Ok(drift)
+}
+
+/// Verify that a patch has been fully applied to a directory
+pub fn verify_applied(patch_file: &Path, target_dir: &Path) -> Result<Vec<Drift>> {
+    let (patch_data, _) = extract_patch_data(patch_file)?;
+    find_drift(&patch_data, target_dir)
+}
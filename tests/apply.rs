@@ -0,0 +1,684 @@
+mod common;
+
+use common::{Fixture, apply_patch, read_files, run_diffpatch, write_files};
+use diffpatch::backup::VERSION_FILE;
+use diffpatch::diff::{HashAlgorithm, calculate_file_hash, compare_directories};
+use diffpatch::patch::{PatchFormat, create_patch, verify_patch_file};
+use diffpatch::target::stage_and_swap;
+use diffpatch::transform::EolMode;
+use diffpatch::utils::{self, copy_dir_all};
+use diffpatch::{ApplyOptions, ApplyStatus, CreateOptions, Patch};
+use std::fs;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+#[test]
+fn eula_must_be_accepted_before_any_file_changes() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2"), ("new.txt", "added")],
+    );
+    let patch = fixture.create_patch(&CreateOptions::new().eula(Some("Terms".to_string())));
+    let install = fixture.install();
+
+    // Without a terminal the license can't be accepted at a prompt, --yes doesn't accept it
+    let declined = run_diffpatch(&[&"apply-archive", &patch, &"--target", &install, &"--yes"]);
+    assert!(!declined.status.success());
+    assert_eq!(read_files(&install), read_files(&fixture.source()));
+
+    let accepted = run_diffpatch(&[
+        &"apply-archive",
+        &patch,
+        &"--target",
+        &install,
+        &"--yes",
+        &"--accept-eula",
+    ]);
+    assert!(accepted.status.success());
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn file_replaced_by_directory() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("data", "a file"), ("keep.txt", "kept")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("data/inner.txt", "in a directory"), ("keep.txt", "kept")],
+    );
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn directory_replaced_by_file() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("data/a.txt", "a"), ("data/sub/b.txt", "b")],
+    );
+    write_files(&fixture.target(), &[("data", "a file now")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn directory_with_local_files_is_not_replaced() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("data/a.txt", "a")]);
+    write_files(&fixture.target(), &[("data", "a file now")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+    write_files(&install, &[("data/local.txt", "added by the user")]);
+    let before = read_files(&install);
+
+    let error = apply_patch(&patch, &install, &ApplyOptions::new()).unwrap_err();
+    assert!(format!("{:#}", error).contains("local.txt"), "{:#}", error);
+    assert_eq!(read_files(&install), before);
+}
+
+#[test]
+fn reverse_patch_restores_the_original_directory() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[
+            ("lines.txt", "one\r\ntwo\r\nthree"),
+            ("same.txt", "unchanged"),
+            ("gone/old.txt", "removed by the update"),
+        ],
+    );
+    write_files(
+        &fixture.target(),
+        &[
+            ("lines.txt", "one\r\n2\r\nthree\r\nfour\n"),
+            ("same.txt", "unchanged"),
+            ("fresh/new.txt", "added by the update"),
+        ],
+    );
+    fs::write(fixture.source().join("data.bin"), [0u8, 1, 2, 255, 0, 7]).unwrap();
+    fs::write(fixture.target().join("data.bin"), [0u8, 1, 3, 255, 0, 7, 9]).unwrap();
+
+    let forward = fixture.path("forward.dpatch");
+    let reverse = fixture.path("reverse.dpatch");
+    let created = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &forward,
+        &"--emit-reverse",
+        &reverse,
+        &"--use-diff-patches",
+        &"--format",
+        &"archive",
+        &"--allow-unverified",
+        &"--yes",
+    ]);
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+
+    let install = fixture.install();
+    apply_patch(&forward, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+    apply_patch(&reverse, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.source()));
+}
+
+#[test]
+fn reverse_patch_requires_the_version_the_forward_patch_installs() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let forward = fixture.path("forward.dpatch");
+    let reverse = fixture.path("reverse.dpatch");
+    let created = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &forward,
+        &"--emit-reverse",
+        &reverse,
+        &"--patch-version",
+        &"2.0",
+        &"--requires-version",
+        &"1.0",
+        &"--format",
+        &"archive",
+        &"--allow-unverified",
+        &"--yes",
+    ]);
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+
+    let install = fixture.install();
+    let version = install.join(VERSION_FILE);
+    fs::write(&version, "1.0\n").unwrap();
+    apply_patch(&forward, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(fs::read_to_string(&version).unwrap(), "2.0\n");
+    apply_patch(&reverse, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(fs::read_to_string(&version).unwrap(), "1.0\n");
+    assert_eq!(fs::read(install.join("app.txt")).unwrap(), b"v1");
+}
+
+#[test]
+fn reverse_patch_is_created_with_an_expected_source_manifest() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let manifest = fixture.path("source.json");
+    let scanned = run_diffpatch(&[
+        &"manifest",
+        &"--dir",
+        &fixture.source(),
+        &"--output",
+        &manifest,
+    ]);
+    assert!(
+        scanned.status.success(),
+        "{}",
+        String::from_utf8_lossy(&scanned.stderr)
+    );
+
+    let reverse = fixture.path("reverse.dpatch");
+    let created = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &fixture.path("forward.dpatch"),
+        &"--emit-reverse",
+        &reverse,
+        &"--expect-source-manifest",
+        &manifest,
+        &"--format",
+        &"archive",
+        &"--allow-unverified",
+        &"--yes",
+    ]);
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+    assert!(reverse.exists());
+}
+
+#[test]
+fn eol_crlf_converts_stored_lf_files() {
+    let fixture = Fixture::new();
+    write_files(&fixture.target(), &[("readme.txt", "first\nsecond\n")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+
+    apply_patch(
+        &patch,
+        &install,
+        &ApplyOptions::new().eol(Some(EolMode::Crlf)),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read(install.join("readme.txt")).unwrap(),
+        b"first\r\nsecond\r\n"
+    );
+}
+
+#[test]
+fn replaced_files_are_never_seen_partially_written() {
+    let old = "a".repeat(2 << 20);
+    let new = "b".repeat(3 << 20);
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("big.txt", &old)]);
+    write_files(&fixture.target(), &[("big.txt", &new)]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+    let path = install.join("big.txt");
+
+    // Readers see the old file until the new one is renamed over it in one step
+    let done = AtomicBool::new(false);
+    let reads = thread::scope(|scope| {
+        let observer = scope.spawn(|| {
+            let mut reads = 0;
+            while !done.load(Ordering::Relaxed) {
+                let content = fs::read(&path).unwrap();
+                assert!(
+                    content == old.as_bytes() || content == new.as_bytes(),
+                    "read {} bytes of neither version",
+                    content.len()
+                );
+                reads += 1;
+            }
+            reads
+        });
+        let result = apply_patch(&patch, &install, &ApplyOptions::new());
+        done.store(true, Ordering::Relaxed);
+        result.unwrap();
+        observer.join().unwrap()
+    });
+    assert!(reads > 0);
+    assert_eq!(fs::read(&path).unwrap(), new.as_bytes());
+}
+
+#[test]
+fn published_hash_must_match_before_applying() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+    let hash = calculate_file_hash(&patch, HashAlgorithm::Sha256).unwrap();
+
+    let wrong = ApplyOptions::new().expect_sha256(Some("0".repeat(64)));
+    assert!(apply_patch(&patch, &install, &wrong).is_err());
+    assert_eq!(read_files(&install), read_files(&fixture.source()));
+
+    let published = ApplyOptions::new().expect_sha256(Some(hash.to_uppercase()));
+    apply_patch(&patch, &install, &published).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn out_dir_receives_the_patched_copy_of_the_base() {
+    let fixture = Fixture::new();
+    for dir in [fixture.source(), fixture.target()] {
+        write_files(&dir, &[("lib/data.txt", "shared")]);
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("lib/data.txt", dir.join("current")).unwrap();
+    }
+    write_files(
+        &fixture.source(),
+        &[("app.txt", "v1"), ("old.txt", "removed")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2"), ("new.txt", "added")],
+    );
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let base = fixture.install();
+
+    let inside = ApplyOptions::new()
+        .base(Some(base.clone()))
+        .out(Some(base.join("next")));
+    let error = apply_patch(&patch, &fixture.path("unused"), &inside).unwrap_err();
+    assert!(
+        error.to_string().contains("inside the base directory"),
+        "{:#}",
+        error
+    );
+    assert!(!base.join("next").exists());
+
+    let out = fixture.path("next");
+    let options = ApplyOptions::new()
+        .base(Some(base.clone()))
+        .out(Some(out.clone()));
+    apply_patch(&patch, &fixture.path("unused"), &options).unwrap();
+    assert_eq!(read_files(&base), read_files(&fixture.source()));
+    assert_eq!(read_files(&out), read_files(&fixture.target()));
+    #[cfg(unix)]
+    for dir in [&base, &out] {
+        assert!(
+            dir.join("current")
+                .symlink_metadata()
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert_eq!(
+            fs::read_link(dir.join("current")).unwrap().to_str(),
+            Some("lib/data.txt")
+        );
+    }
+}
+
+#[test]
+fn path_in_two_change_sets_is_rejected() {
+    let fixture = Fixture::new();
+    let text = "line\n".repeat(100);
+    write_files(&fixture.source(), &[("app.txt", &text)]);
+    write_files(
+        &fixture.target(),
+        &[("app.txt", &text.replacen("line", "edited", 1))],
+    );
+
+    // The same file both as a full copy and as a diff, as a faulty create could store it
+    let full = CreateOptions::new().format(PatchFormat::Archive);
+    let diffed = full.clone().use_diff_patches(true);
+    let mut diffs = compare_directories(&fixture.source(), &fixture.target(), &full).unwrap();
+    diffs.extend(compare_directories(&fixture.source(), &fixture.target(), &diffed).unwrap());
+    let patch = create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("update"),
+        diffs,
+        &diffed,
+    )
+    .unwrap();
+
+    let problems = verify_patch_file(&patch).unwrap();
+    assert!(
+        problems.contains(&"listed more than once: app.txt".to_string()),
+        "{:?}",
+        problems
+    );
+    let install = fixture.install();
+    let error = apply_patch(&patch, &install, &ApplyOptions::new()).unwrap_err();
+    assert!(
+        error.to_string().contains("more than one change category"),
+        "{:#}",
+        error
+    );
+    assert_eq!(read_files(&install), read_files(&fixture.source()));
+}
+
+#[test]
+fn three_way_merges_local_edits() {
+    let fixture = Fixture::new();
+    let lines: Vec<String> = (1..=200).map(|i| format!("line {}\n", i)).collect();
+    let source = lines.concat();
+    let target = source.replace("line 3\n", "line 3 from the patch\n");
+    write_files(&fixture.source(), &[("app.cfg", &source)]);
+    write_files(&fixture.target(), &[("app.cfg", &target)]);
+    let patch = fixture.create_patch(&CreateOptions::new().use_diff_patches(true));
+    let options = ApplyOptions::new().three_way(true);
+
+    // A local edit away from the patched lines is kept alongside the patch's change
+    let clean = fixture.install();
+    let local = source.replace("line 150\n", "line 150 edited locally\n");
+    write_files(&clean, &[("app.cfg", &local)]);
+    apply_patch(&patch, &clean, &options).unwrap();
+    assert_eq!(
+        fs::read_to_string(clean.join("app.cfg")).unwrap(),
+        target.replace("line 150\n", "line 150 edited locally\n")
+    );
+
+    // A local edit of the patched line becomes a conflict block with both versions
+    let conflicting = fixture.path("conflicting");
+    fs::rename(&clean, &conflicting).unwrap();
+    let local = source.replace("line 3\n", "line 3 edited locally\n");
+    write_files(&conflicting, &[("app.cfg", &local)]);
+    apply_patch(&patch, &conflicting, &options).unwrap();
+    let merged = fs::read_to_string(conflicting.join("app.cfg")).unwrap();
+    let markers = "<<<<<<< local\nline 3 edited locally\n||||||| base\nline 3\n=======\n\
+                   line 3 from the patch\n>>>>>>> patch\n";
+    assert!(merged.contains(markers), "{}", merged);
+    assert!(merged.starts_with("line 1\nline 2\n<<<<<<<") && merged.ends_with("line 200\n"));
+}
+
+#[test]
+fn three_way_leaves_locally_edited_token_diffs_alone() {
+    let fixture = Fixture::new();
+    let body: String = (1..=200).map(|i| format!("    step({});\n", i)).collect();
+    let source = format!("fn main() {{\n{}}}\n", body);
+    let target = source.replace("step(3);", "step(3, fast);");
+    write_files(
+        &fixture.source(),
+        &[("main.rs", &source), ("notes.txt", "v1")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("main.rs", &target), ("notes.txt", "v2")],
+    );
+    let patch = fixture.create_patch(
+        &CreateOptions::new()
+            .use_diff_patches(true)
+            .syntax_diff(true),
+    );
+    let opened = Patch::open(&patch).unwrap();
+    assert!(opened.data().modified_diffs.iter().all(|d| d.tokenized));
+
+    // Token diffs can't be merged, so the locally edited file is left as it is
+    let install = fixture.install();
+    let local = source.replace("step(150);", "step(150, logged);");
+    write_files(&install, &[("main.rs", &local)]);
+    apply_patch(&patch, &install, &ApplyOptions::new().three_way(true)).unwrap();
+    assert_eq!(fs::read_to_string(install.join("main.rs")).unwrap(), local);
+    assert_eq!(fs::read_to_string(install.join("notes.txt")).unwrap(), "v2");
+}
+
+#[test]
+fn diffs_stored_in_the_zip_apply_the_same() {
+    let fixture = Fixture::new();
+    for i in 0..5 {
+        let text = format!("file {}\n{}", i, "unchanged line\n".repeat(100));
+        let path = format!("docs/part{}.txt", i);
+        write_files(&fixture.source(), &[(&path, &text)]);
+        write_files(
+            &fixture.target(),
+            &[(&path, &text.replace("file", "edited file"))],
+        );
+    }
+    write_files(&fixture.target(), &[("new.txt", "added")]);
+
+    let inline = CreateOptions::new().use_diff_patches(true);
+    let in_zip = inline.clone().diffs_in_zip(true);
+    let inline_patch = common::create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("inline"),
+        &inline,
+    );
+    let in_zip_patch = fixture.create_patch(&in_zip);
+
+    let opened = Patch::open(&in_zip_patch).unwrap();
+    let data = opened.data();
+    assert!(data.diffs_in_zip);
+    assert_eq!(data.modified_diffs.len(), 5);
+    assert!(data.modified_diffs.iter().all(|d| d.changes.is_empty()));
+
+    let mut results = Vec::new();
+    for (name, patch) in [
+        ("inline-install", &inline_patch),
+        ("in-zip-install", &in_zip_patch),
+    ] {
+        let install = fixture.path(name);
+        copy_dir_all(&fixture.source(), &install).unwrap();
+        apply_patch(patch, &install, &ApplyOptions::new()).unwrap();
+        results.push(read_files(&install));
+    }
+    assert_eq!(results[0], read_files(&fixture.target()));
+    assert_eq!(results[1], results[0]);
+}
+
+#[test]
+fn default_yes_turns_every_prompt_default_into_yes() {
+    assert!(utils::prompt_default(true));
+    assert!(!utils::prompt_default(false));
+    utils::set_default_yes(true);
+    let defaults = [utils::prompt_default(false), utils::prompt_default(true)];
+    utils::set_default_yes(false);
+    assert_eq!(defaults, [true, true]);
+}
+
+/// Tree hash of a directory's files, computed the way `--merkle` documents it
+fn expected_tree_hash(dir: &std::path::Path) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for (path, content) in read_files(dir) {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(format!("{:x}", Sha256::digest(&content)).as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[test]
+fn merkle_hashes_in_the_report_match_the_end_states() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("app.txt", "v1"), ("lib/old.txt", "removed")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2"), ("lib/new.txt", "added")],
+    );
+    let options = CreateOptions::new().patch_version(Some("2.0".to_string()));
+    let patch = fixture.create_patch(&options);
+    let install = fixture.install();
+
+    // Backups and the installed version file are diffpatch's, not part of the tree
+    let report = fixture.path("report.json");
+    let options = ApplyOptions::new()
+        .merkle(true)
+        .backup(true)
+        .report(Some(report.clone()));
+    apply_patch(&patch, &install, &options).unwrap();
+    assert!(install.join(".diffpatch-version").exists());
+    assert!(install.join(".diffpatch-backup").is_dir());
+
+    let report: serde_json::Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+    assert_eq!(
+        report["tree_hash_before"],
+        expected_tree_hash(&fixture.source()).as_str()
+    );
+    assert_eq!(
+        report["tree_hash_after"],
+        expected_tree_hash(&fixture.target()).as_str()
+    );
+}
+
+#[test]
+fn patch_left_unapplied_is_not_reported_as_applied() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+
+    let dry_run = ApplyOptions::new().dry_run(true);
+    assert_eq!(
+        apply_patch(&patch, &install, &dry_run).unwrap(),
+        ApplyStatus::NotApplied
+    );
+    let output = run_diffpatch(&[
+        &"apply-chain",
+        &patch,
+        &"--target",
+        &install,
+        &"--dry-run",
+        &"--yes",
+    ]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Applied"));
+    assert_eq!(read_files(&install), read_files(&fixture.source()));
+
+    let applied = apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(applied, ApplyStatus::Applied);
+}
+
+#[test]
+fn tiny_io_buffers_still_round_trip_and_zero_is_refused() {
+    let fixture = Fixture::new();
+    let lines: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+    write_files(
+        &fixture.source(),
+        &[("app.txt", &lines), ("old.bin", "gone")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "rewritten\n"), ("new.bin", &lines)],
+    );
+    let buffer = NonZeroUsize::new(7);
+    let patch = fixture.create_patch(&CreateOptions::new().io_buffer_size(buffer));
+    let install = fixture.install();
+    apply_patch(
+        &patch,
+        &install,
+        &ApplyOptions::new().io_buffer_size(buffer),
+    )
+    .unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+
+    let zero = run_diffpatch(&[&"--io-buffer-size", &"0", &"verify", &patch]);
+    assert!(!zero.status.success());
+    assert!(String::from_utf8_lossy(&zero.stderr).contains("--io-buffer-size"));
+}
+
+#[test]
+fn force_removed_leftovers_are_deleted_when_present() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let options =
+        CreateOptions::new().force_remove(vec!["old/legacy.dll".into(), "cache/*.tmp".into()]);
+    let patch = fixture.create_patch(&options);
+
+    // The leftovers were never in the source, only in the installed copy
+    let install = fixture.install();
+    write_files(
+        &install,
+        &[
+            ("old/legacy.dll", "stale"),
+            ("cache/a.tmp", "x"),
+            ("cache/b.dat", "y"),
+        ],
+    );
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    let mut expected = read_files(&fixture.target());
+    expected.insert("cache/b.dat".to_string(), b"y".to_vec());
+    assert_eq!(read_files(&install), expected);
+}
+
+#[test]
+fn force_removed_paths_that_are_absent_are_skipped() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let options =
+        CreateOptions::new().force_remove(vec!["old/legacy.dll".into(), "cache/*.tmp".into()]);
+    let patch = fixture.create_patch(&options);
+
+    let install = fixture.install();
+    let status = apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(status, ApplyStatus::Applied);
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn staged_write_errors_are_not_retried_at_the_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("app.txt");
+    fs::write(&dest, "old").unwrap();
+
+    let written = Mutex::new(Vec::new());
+    let result = stage_and_swap(&dest, |path| {
+        written.lock().unwrap().push(path.to_path_buf());
+        fs::write(path, "partial")?;
+        Err(io::Error::other("disk full"))
+    });
+    assert_eq!(result.unwrap_err().to_string(), "disk full");
+    assert_eq!(written.into_inner().unwrap().len(), 1);
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "old");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    stage_and_swap(&dest, |path| fs::write(path, "new")).unwrap();
+    assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+}
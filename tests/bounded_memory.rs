@@ -0,0 +1,62 @@
+//! Peak memory of verifying a large patch, alone in this binary so no other test's
+//! allocations count toward the high-water mark
+
+// Peak memory is read from /proc
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{Fixture, run_diffpatch, write_noise};
+use std::fs;
+
+/// Size of the incompressible file the patch carries
+const PAYLOAD_SIZE: usize = 32 << 20;
+
+/// Largest resident set size of this process so far, in bytes
+fn peak_rss() -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|l| l.starts_with("VmHWM:")).unwrap();
+    let kb: usize = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    kb * 1024
+}
+
+#[test]
+fn verifying_a_large_patch_uses_bounded_memory() {
+    let fixture = Fixture::new();
+    write_noise(&fixture.target().join("big.bin"), PAYLOAD_SIZE);
+
+    // Created by another process, whose buffers don't count toward this one's peak
+    let patch = fixture.path("big.dpatch");
+    let created = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &patch,
+        &"--format",
+        &"archive",
+        &"--compression",
+        &"stored",
+        &"--allow-unverified",
+        &"--yes",
+    ]);
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+    assert!(fs::metadata(&patch).unwrap().len() > PAYLOAD_SIZE as u64);
+
+    // Opening a patch checks the payload checksum over the whole file
+    let before = peak_rss();
+    diffpatch::Patch::open(&patch).unwrap();
+    let grown = peak_rss().saturating_sub(before);
+    assert!(
+        grown < PAYLOAD_SIZE / 4,
+        "verifying a {} byte payload grew the peak memory by {} bytes",
+        PAYLOAD_SIZE,
+        grown
+    );
+}
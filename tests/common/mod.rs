@@ -0,0 +1,126 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use anyhow::Result;
+use diffpatch::patch::{self, PatchFormat};
+use diffpatch::utils::{self, copy_dir_all};
+use diffpatch::{ApplyOptions, ApplyStatus, CreateOptions, diff};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Source and target directories of a patch, removed when dropped
+pub struct Fixture {
+    root: TempDir,
+}
+
+impl Fixture {
+    /// Empty source and target directories
+    pub fn new() -> Self {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("source")).unwrap();
+        fs::create_dir(root.path().join("target")).unwrap();
+        Self { root }
+    }
+
+    pub fn source(&self) -> PathBuf {
+        self.root.path().join("source")
+    }
+
+    pub fn target(&self) -> PathBuf {
+        self.root.path().join("target")
+    }
+
+    /// Path of a scratch file or directory next to the source and target
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.path().join(name)
+    }
+
+    /// Copy of the source directory for a patch to be applied to
+    pub fn install(&self) -> PathBuf {
+        let dir = self.path("install");
+        copy_dir_all(&self.source(), &dir).unwrap();
+        dir
+    }
+
+    /// Build an archive patch from the source to the target directory
+    pub fn create_patch(&self, options: &CreateOptions) -> PathBuf {
+        create_patch(
+            &self.source(),
+            &self.target(),
+            &self.path("update"),
+            options,
+        )
+    }
+}
+
+/// Write files below `dir`, creating their parent directories
+pub fn write_files(dir: &Path, files: &[(&str, &str)]) {
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+}
+
+/// Every file below `dir` with its content, by `/`-separated relative path
+pub fn read_files(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(Result::unwrap)
+        .filter(|e| e.file_type().is_file())
+        .map(|e| {
+            let relative_path = e.path().strip_prefix(dir).unwrap();
+            let name = utils::portable_path(relative_path)
+                .to_string_lossy()
+                .into_owned();
+            (name, fs::read(e.path()).unwrap())
+        })
+        .collect()
+}
+
+/// Build an archive patch turning `source` into `target`
+pub fn create_patch(
+    source: &Path,
+    target: &Path,
+    output: &Path,
+    options: &CreateOptions,
+) -> PathBuf {
+    let options = options.clone().format(PatchFormat::Archive);
+    let diffs = diff::compare_directories(source, target, &options).unwrap();
+    patch::create_patch(source, target, output, diffs, &options).unwrap()
+}
+
+/// Apply a patch, answering yes to the confirmations the patch asks for
+pub fn apply_patch(patch_file: &Path, dir: &Path, options: &ApplyOptions) -> Result<ApplyStatus> {
+    utils::set_assume_yes(true);
+    patch::apply_patch_file(patch_file, dir, options)
+}
+
+/// Run the `diffpatch` binary without a terminal on stdin
+pub fn run_diffpatch(args: &[&dyn AsRef<OsStr>]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_diffpatch"))
+        .args(args.iter().map(|arg| arg.as_ref()))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// Write `len` bytes that don't compress
+pub fn write_noise(path: &Path, len: usize) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    for _ in 0..len / 8 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        writer.write_all(&state.to_le_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+}
@@ -0,0 +1,555 @@
+mod common;
+
+use common::{Fixture, apply_patch, read_files, run_diffpatch, write_files};
+use diffpatch::diff::{DiffType, HashAlgorithm, calculate_file_hash, compare_directories};
+use diffpatch::manifest::DirectoryManifest;
+use diffpatch::patch::{PatchFormat, create_patch};
+use diffpatch::utils::{copy_dir_all, read_path_list};
+use diffpatch::{ApplyOptions, CreateOptions, Patch, PatchBuilder, PatchData};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn builder_options_are_used_by_create() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("app.txt", "v1\n"), ("skip.log", "old log")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2\n"), ("skip.log", "new log")],
+    );
+
+    let options = CreateOptions::new()
+        .format(PatchFormat::Archive)
+        .exclude_extensions(vec!["log".to_string()])
+        .check_files(vec!["app.txt".to_string()])
+        .author(Some("Builder".to_string()));
+    let patch = PatchBuilder::new(&fixture.source(), &fixture.target())
+        .options(options)
+        .create_patch_to_path(&fixture.path("update"))
+        .unwrap()
+        .expect("the directories differ");
+
+    assert_eq!(patch.path().extension().unwrap(), "dpatch");
+    let data = patch.data();
+    assert_eq!(data.check_files, ["app.txt"]);
+    assert_eq!(data.metadata.author.as_deref(), Some("Builder"));
+    let modified: Vec<&Path> = data
+        .modified_files
+        .iter()
+        .map(|f| f.relative_path.as_path())
+        .collect();
+    assert_eq!(modified, [Path::new("app.txt")]);
+}
+
+/// Relative paths of every file a patch writes, removes or renames
+fn changed_paths(data: &PatchData) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = data
+        .written_paths()
+        .map(Path::to_path_buf)
+        .chain(data.removed_files.iter().cloned())
+        .chain(data.renamed_files.iter().map(|r| r.from.clone()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn only_manifest_listed_files_are_patched() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("listed.txt", "v1"), ("other.txt", "v1"), ("gone.txt", "x")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("listed.txt", "v2"), ("other.txt", "v2"), ("new.txt", "y")],
+    );
+
+    let options = CreateOptions::new().manifest(Some(vec![PathBuf::from("listed.txt")]));
+    let patch = Patch::open(&fixture.create_patch(&options)).unwrap();
+    assert_eq!(changed_paths(patch.data()), [PathBuf::from("listed.txt")]);
+}
+
+#[test]
+fn source_not_matching_the_expected_manifest_aborts_creation() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1"), ("lib.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2"), ("lib.txt", "v1")]);
+    let expected = DirectoryManifest::scan(&fixture.source(), &CreateOptions::new()).unwrap();
+
+    let options = CreateOptions::new().expected_source(Some(expected));
+    assert!(compare_directories(&fixture.source(), &fixture.target(), &options).is_ok());
+
+    write_files(
+        &fixture.source(),
+        &[("lib.txt", "edited after the manifest was taken")],
+    );
+    let error = compare_directories(&fixture.source(), &fixture.target(), &options).unwrap_err();
+    assert!(error.to_string().contains("lib.txt"), "{:#}", error);
+}
+
+#[test]
+fn removal_paths_outside_the_directory_are_rejected() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let list = fixture.path("remove.txt");
+    std::fs::write(&list, "stale.dll\n../outside.txt\n").unwrap();
+    let error = read_path_list(&list).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("outside.txt"),
+        "{:#}",
+        error
+    );
+
+    for path in ["../outside.txt", "/etc/outside.txt"] {
+        let options = CreateOptions::new()
+            .format(PatchFormat::Archive)
+            .force_remove(vec![PathBuf::from(path)]);
+        let created =
+            compare_directories(&fixture.source(), &fixture.target(), &options).and_then(|diffs| {
+                create_patch(
+                    &fixture.source(),
+                    &fixture.target(),
+                    &fixture.path("update"),
+                    diffs,
+                    &options,
+                )
+            });
+        assert!(created.is_err(), "{} was accepted", path);
+    }
+
+    let patch = Patch::open(&fixture.create_patch(&CreateOptions::new())).unwrap();
+    let mut data = serde_json::to_value(patch.data()).unwrap();
+    data["removed_files"] = serde_json::json!(["../outside.txt"]);
+    let error = PatchData::from_json(&serde_json::to_vec(&data).unwrap()).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("outside.txt"),
+        "{:#}",
+        error
+    );
+}
+
+/// Size of the stored diff of the only modified file
+fn stored_diff_size(fixture: &Fixture, options: &CreateOptions) -> usize {
+    let diffs = compare_directories(&fixture.source(), &fixture.target(), options).unwrap();
+    match diffs.as_slice() {
+        [DiffType::ModifiedDiff(file_diff)] => serde_json::to_vec(file_diff).unwrap().len(),
+        other => panic!("expected a single diff, got {:?}", other),
+    }
+}
+
+#[test]
+fn reindented_block_gives_a_smaller_syntax_diff() {
+    let fixture = Fixture::new();
+    let body: String = (0..40)
+        .map(|i| format!("    total += values[{}] * {};\n", i, i))
+        .collect();
+    let reindented = body.replace("    ", "        ");
+    let trailer = "int unrelated(void) { return 0; }\n".repeat(400);
+    let source = format!("int sum(int *values) {{\n{}}}\n{}", body, trailer);
+    let target = format!(
+        "int sum(int *values) {{\n  {{\n{}  }}\n}}\n{}",
+        reindented, trailer
+    );
+    write_files(&fixture.source(), &[("sum.c", &source)]);
+    write_files(&fixture.target(), &[("sum.c", &target)]);
+
+    let lines = CreateOptions::new().use_diff_patches(true);
+    let syntax = lines.clone().syntax_diff(true);
+    let line_size = stored_diff_size(&fixture, &lines);
+    let syntax_size = stored_diff_size(&fixture, &syntax);
+    assert!(syntax_size < line_size, "{} >= {}", syntax_size, line_size);
+
+    let install = fixture.install();
+    apply_patch(
+        &fixture.create_patch(&syntax),
+        &install,
+        &ApplyOptions::new(),
+    )
+    .unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn require_changes_fails_when_excludes_filter_out_everything() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("app.txt", "v1"), ("docs/readme.txt", "v1")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2"), ("docs/readme.txt", "v2")],
+    );
+
+    let options = CreateOptions::new().exclude_extensions(vec!["txt".to_string()]);
+    let diffs = compare_directories(&fixture.source(), &fixture.target(), &options).unwrap();
+    assert!(diffs.is_empty());
+
+    let (source, target, output) = (fixture.source(), fixture.target(), fixture.path("update"));
+    let create = |extra: &[&dyn AsRef<OsStr>]| {
+        let mut args: Vec<&dyn AsRef<OsStr>> = vec![
+            &"create",
+            &"--source",
+            &source,
+            &"--target",
+            &target,
+            &"--output",
+            &output,
+            &"--allow-unverified",
+            &"--exclude-extensions",
+            &"txt",
+        ];
+        args.extend_from_slice(extra);
+        run_diffpatch(&args)
+    };
+    assert!(create(&[]).status.success());
+    let required = create(&[&"--require-changes"]);
+    assert_eq!(required.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&required.stderr).contains("--require-changes"));
+}
+
+/// `len` bytes of noise that neither diff nor delta-encode compactly
+fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn binary_file_is_listed_as_shipped_whole() {
+    let fixture = Fixture::new();
+    let text = "line\n".repeat(200);
+    write_files(&fixture.source(), &[("notes.txt", &text)]);
+    write_files(
+        &fixture.target(),
+        &[("notes.txt", &text.replacen("line", "edited", 1))],
+    );
+    std::fs::write(fixture.source().join("blob.bin"), noise(1, 4096)).unwrap();
+    std::fs::write(fixture.target().join("blob.bin"), noise(2, 4096)).unwrap();
+
+    let output = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &fixture.path("update"),
+        &"--use-diff-patches",
+        &"--allow-unverified",
+        &"--dry-run",
+    ]);
+    let log = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let (_, listed) = log
+        .split_once("Will be shipped as full files")
+        .unwrap_or_else(|| panic!("no full file list in:\n{}", log));
+    let listed = listed.split("Dry run").next().unwrap();
+    assert!(listed.contains("- blob.bin (4096 bytes)"), "{}", log);
+    assert!(!listed.contains("notes.txt"), "{}", log);
+}
+
+#[test]
+fn change_stats_are_printed_even_when_quiet() {
+    let fixture = Fixture::new();
+    let text = "line\n".repeat(200);
+    write_files(&fixture.source(), &[("notes.txt", &text)]);
+    write_files(
+        &fixture.target(),
+        &[("notes.txt", &text.replacen("line", "edited", 1))],
+    );
+
+    let output = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &fixture.path("update"),
+        &"--use-diff-patches",
+        &"--allow-unverified",
+        &"--dry-run",
+        &"--quiet",
+    ]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Lines in diffs: +1 -1"), "{}", stdout);
+    assert!(stdout.contains("Estimated patch size"), "{}", stdout);
+}
+
+#[test]
+fn only_files_containing_the_signature_are_patched() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[
+            ("vulnerable.js", "run(eval(input));\n"),
+            ("lib/also.js", "let x = eval(code);\n"),
+            ("safe.js", "run(input);\n"),
+            ("removed.js", "eval(old);\n"),
+        ],
+    );
+    write_files(
+        &fixture.target(),
+        &[
+            ("vulnerable.js", "run(sandbox(input));\n"),
+            ("lib/also.js", "let x = sandbox(code);\n"),
+            ("safe.js", "run(input, options);\n"),
+            ("added.js", "eval(new);\n"),
+        ],
+    );
+
+    let pattern = regex::bytes::Regex::new(r"eval\(").unwrap();
+    let options = CreateOptions::new().only_if_contains(Some(pattern));
+    let diffs = compare_directories(&fixture.source(), &fixture.target(), &options).unwrap();
+    let mut paths: Vec<&Path> = diffs
+        .iter()
+        .map(|d| match d {
+            DiffType::Modified(info) => info.relative_path.as_path(),
+            other => panic!("unexpected change: {:?}", other),
+        })
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [Path::new("lib/also.js"), Path::new("vulnerable.js")]
+    );
+}
+
+#[test]
+fn fast_mode_reads_neither_side_of_a_matching_pair() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("same.bin", "old build"), ("app.txt", "v1")],
+    );
+    write_files(
+        &fixture.target(),
+        &[("same.bin", "new build"), ("app.txt", "v2")],
+    );
+    let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+    for dir in [fixture.source(), fixture.target()] {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(dir.join("same.bin"))
+            .unwrap();
+        file.set_modified(mtime).unwrap();
+    }
+
+    // Every file that is read is recorded in the hash cache
+    let cache = fixture.path("cache.json");
+    let options = CreateOptions::new()
+        .fast(true)
+        .hash_cache(Some(cache.clone()));
+    let diffs = compare_directories(&fixture.source(), &fixture.target(), &options).unwrap();
+    let changed: Vec<String> = diffs.iter().map(|d| format!("{:?}", d)).collect();
+    assert_eq!(changed.len(), 1, "{:?}", changed);
+    assert!(changed[0].contains("app.txt"), "{:?}", changed);
+
+    let cached: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&std::fs::read(&cache).unwrap()).unwrap();
+    let hashed = |name: &str| cached.keys().filter(|key| key.ends_with(name)).count();
+    assert_eq!(hashed("app.txt"), 2);
+    assert_eq!(hashed("same.bin"), 0);
+}
+
+#[test]
+fn hash_cache_is_written_into_its_own_directory() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+
+    let cache_dir = fixture.path("cache/diffpatch");
+    let options = CreateOptions::new().hash_cache(Some(cache_dir.join("hash-cache.json")));
+    compare_directories(&fixture.source(), &fixture.target(), &options).unwrap();
+
+    // Only the cache itself is left, no staged copy next to it
+    let names: Vec<_> = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["hash-cache.json"]);
+}
+
+/// Create a patch between the two file sets and apply it to a copy of the source
+fn round_trip(source: &[(&str, &str)], target: &[(&str, &str)]) {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), source);
+    write_files(&fixture.target(), target);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    Patch::open(&patch).unwrap();
+
+    let install = fixture.install();
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+}
+
+#[test]
+fn patch_from_an_empty_source_adds_everything() {
+    round_trip(&[], &[("app.txt", "v1"), ("lib/nested/data.txt", "data")]);
+}
+
+#[test]
+fn patch_to_an_empty_target_removes_everything() {
+    round_trip(&[("app.txt", "v1"), ("lib/nested/data.txt", "data")], &[]);
+}
+
+#[test]
+fn patch_between_empty_directories_changes_nothing() {
+    round_trip(&[], &[]);
+}
+
+#[test]
+fn mapping_paths_covers_check_files_and_round_trips() {
+    let fixture = Fixture::new();
+    let check_file = "config/nested/app.cfg";
+    write_files(
+        &fixture.source(),
+        &[(check_file, "v1"), ("lib/deep/data.txt", "old")],
+    );
+    write_files(
+        &fixture.target(),
+        &[(check_file, "v1"), ("lib/deep/data.txt", "new")],
+    );
+    let hash = calculate_file_hash(&fixture.source().join(check_file), HashAlgorithm::Sha256);
+    let options = CreateOptions::new()
+        .check_files(vec![check_file.to_string()])
+        .check_hashes([(check_file.to_string(), hash.unwrap())].into());
+    let patch = fixture.create_patch(&options);
+    let opened = Patch::open(&patch).unwrap();
+    let original = serde_json::to_value(opened.data()).unwrap();
+
+    let mut data = PatchData::from_json(&serde_json::to_vec(opened.data()).unwrap()).unwrap();
+    data.map_paths(|path| PathBuf::from(path.to_string_lossy().replace('/', "\\")));
+    assert_eq!(data.check_files, ["config\\nested\\app.cfg"]);
+    assert!(data.check_hashes.contains_key("config\\nested\\app.cfg"));
+    assert_eq!(changed_paths(&data), [PathBuf::from("lib\\deep\\data.txt")]);
+
+    data.map_paths(|path| PathBuf::from(path.to_string_lossy().replace('\\', "/")));
+    assert_eq!(serde_json::to_value(&data).unwrap(), original);
+
+    // The nested check file is found and matched when the patch is applied
+    let install = fixture.install();
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+
+    // and refuses a directory where it has other content
+    let other = fixture.path("other");
+    copy_dir_all(&fixture.source(), &other).unwrap();
+    write_files(&other, &[(check_file, "v0")]);
+    let before = read_files(&other);
+    let error = apply_patch(&patch, &other, &ApplyOptions::new()).unwrap_err();
+    assert!(
+        error.to_string().contains("verification failed"),
+        "{:#}",
+        error
+    );
+    assert_eq!(read_files(&other), before);
+}
+
+#[test]
+fn same_inputs_build_byte_identical_patches() {
+    let fixture = Fixture::new();
+    let text = "unchanged line\n".repeat(100);
+    for i in 0..20 {
+        let path = format!("dir{}/file{}.txt", i % 4, i);
+        write_files(&fixture.source(), &[(&path, &format!("{}{}", i, text))]);
+        write_files(
+            &fixture.target(),
+            &[(&path, &format!("{}{}edited\n", i, text))],
+        );
+        write_files(
+            &fixture.target(),
+            &[(&format!("new/added{}.txt", i), &path)],
+        );
+    }
+    write_files(&fixture.source(), &[("old/removed.txt", "gone")]);
+
+    let options = CreateOptions::new().use_diff_patches(true);
+    let first = common::create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("first"),
+        &options,
+    );
+    // Files touched in between must not change the stored timestamps
+    let mtime = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+    for (name, _) in read_files(&fixture.target()) {
+        let path = fixture.target().join(name);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+    let second = common::create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("second"),
+        &options,
+    );
+    assert!(std::fs::read(&first).unwrap() == std::fs::read(&second).unwrap());
+}
+
+#[test]
+fn seeded_builds_are_identical_and_refuse_encryption() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1"), ("old.txt", "gone")]);
+    write_files(
+        &fixture.target(),
+        &[("app.txt", "v2"), ("lib/new.txt", "added")],
+    );
+    let scratch = fixture.path("scratch");
+    std::fs::create_dir(&scratch).unwrap();
+
+    let seeded = CreateOptions::new()
+        .seed(Some(42))
+        .temp_dir(Some(scratch.clone()));
+    let first = common::create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("first"),
+        &seeded,
+    );
+    let second = common::create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("second"),
+        &seeded,
+    );
+    assert!(std::fs::read(&first).unwrap() == std::fs::read(&second).unwrap());
+    assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+
+    let encrypted = seeded.passphrase(Some("secret".to_string()));
+    let diffs = compare_directories(&fixture.source(), &fixture.target(), &encrypted).unwrap();
+    let output = fixture.path("encrypted");
+    let result = create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &output,
+        diffs,
+        &encrypted,
+    );
+    assert!(result.is_err());
+}
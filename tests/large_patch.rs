@@ -0,0 +1,68 @@
+//! Patches carrying files much larger than the I/O buffers
+
+mod common;
+
+use common::{Fixture, run_diffpatch, write_noise};
+use diffpatch::diff::{
+    COPY_BLOCK_SIZE, HashAlgorithm, RESUMABLE_COPY_THRESHOLD, calculate_file_hash,
+};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+#[test]
+fn interrupted_large_file_copy_resumes_from_verified_blocks() {
+    let fixture = Fixture::new();
+    let size = RESUMABLE_COPY_THRESHOLD as usize + COPY_BLOCK_SIZE / 2;
+    let big = fixture.target().join("big.bin");
+    write_noise(&big, size);
+    let patch = fixture.path("big.dpatch");
+    let created = run_diffpatch(&[
+        &"create",
+        &"--source",
+        &fixture.source(),
+        &"--target",
+        &fixture.target(),
+        &"--output",
+        &patch,
+        &"--format",
+        &"archive",
+        &"--compression",
+        &"stored",
+        &"--allow-unverified",
+        &"--yes",
+    ]);
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+
+    // An interrupted copy left three good blocks and a damaged one in the staging file,
+    // streamed so this test doesn't hold the file in memory
+    let install = fixture.install();
+    let mut partial = File::create(install.join(".big.bin.diffpatch-tmp")).unwrap();
+    let blocks = File::open(&big).unwrap().take(4 * COPY_BLOCK_SIZE as u64);
+    io::copy(&mut BufReader::new(blocks), &mut partial).unwrap();
+    partial
+        .seek(SeekFrom::Start(3 * COPY_BLOCK_SIZE as u64))
+        .unwrap();
+    partial.write_all(b"damaged").unwrap();
+    drop(partial);
+
+    let applied = run_diffpatch(&[
+        &"apply-archive",
+        &patch,
+        &"--target",
+        &install,
+        &"--yes",
+        &"-v",
+    ]);
+    let log = String::from_utf8_lossy(&applied.stderr);
+    assert!(applied.status.success(), "{}", log);
+    assert!(log.contains("at block 3/"), "{}", log);
+    assert_eq!(
+        calculate_file_hash(&install.join("big.bin"), HashAlgorithm::Sha256).unwrap(),
+        calculate_file_hash(&big, HashAlgorithm::Sha256).unwrap()
+    );
+    assert!(!install.join(".big.bin.diffpatch-tmp").exists());
+}
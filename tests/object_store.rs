@@ -0,0 +1,87 @@
+mod common;
+
+use common::{Fixture, create_patch, write_files};
+use diffpatch::patch::apply_patch_to_target;
+use diffpatch::{ApplyOptions, CreateOptions, ObjectStoreTarget, utils};
+use futures::TryStreamExt;
+use object_store::memory::InMemory;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Every object below `prefix` with its content, by path relative to the prefix
+fn read_objects(store: &InMemory, prefix: &str) -> BTreeMap<String, Vec<u8>> {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let prefix = ObjectPath::from(prefix);
+        let objects: Vec<_> = store.list(Some(&prefix)).try_collect().await.unwrap();
+        let mut files = BTreeMap::new();
+        for object in objects {
+            let name = object.location.prefix_match(&prefix).unwrap();
+            let name = name
+                .map(|part| part.as_ref().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            let content = store
+                .get(&object.location)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            files.insert(name, content.to_vec());
+        }
+        files
+    })
+}
+
+#[test]
+fn patch_is_applied_to_an_object_store() {
+    let fixture = Fixture::new();
+    let unchanged = "unchanged\n".repeat(50);
+    write_files(
+        &fixture.source(),
+        &[
+            ("app.cfg", "mode = old\nretries = 3\n"),
+            ("lib/unchanged.txt", &unchanged),
+            ("lib/obsolete.txt", "no longer shipped\n"),
+        ],
+    );
+    write_files(
+        &fixture.target(),
+        &[
+            ("app.cfg", "mode = new\nretries = 3\n"),
+            ("lib/unchanged.txt", &unchanged),
+            ("lib/added/new.txt", "brand new\n"),
+        ],
+    );
+    let patch_file = create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("update"),
+        &CreateOptions::default(),
+    );
+
+    let store = Arc::new(InMemory::new());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for (name, content) in common::read_files(&fixture.source()) {
+        let location = ObjectPath::from(format!("app/{}", name));
+        runtime
+            .block_on(store.put(&location, PutPayload::from(content)))
+            .unwrap();
+    }
+    let outside = ObjectPath::from("other/app.cfg");
+    runtime
+        .block_on(store.put(&outside, PutPayload::from_static(b"untouched")))
+        .unwrap();
+
+    let target = ObjectStoreTarget::new(store.clone(), ObjectPath::from("app")).unwrap();
+    utils::set_assume_yes(true);
+    apply_patch_to_target(&patch_file, &target, &ApplyOptions::default()).unwrap();
+
+    let mut patched = read_objects(&store, "app");
+    patched.retain(|name, _| !name.starts_with(".diffpatch"));
+    assert_eq!(patched, common::read_files(&fixture.target()));
+    assert_eq!(read_objects(&store, "other")["app.cfg"], b"untouched");
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::{Fixture, create_patch, write_files};
+use diffpatch::patch_diff::diff_patch_data;
+use diffpatch::{CreateOptions, Patch};
+use std::path::PathBuf;
+
+#[test]
+fn patches_differing_by_one_added_file() {
+    let fixture = Fixture::new();
+    write_files(&fixture.source(), &[("app.txt", "v1")]);
+    write_files(&fixture.target(), &[("app.txt", "v2")]);
+    let old = fixture.create_patch(&CreateOptions::new());
+
+    write_files(
+        &fixture.target(),
+        &[("extra/new.txt", "only in the new patch")],
+    );
+    let new = create_patch(
+        &fixture.source(),
+        &fixture.target(),
+        &fixture.path("update-2"),
+        &CreateOptions::new(),
+    );
+
+    let old = Patch::open(&old).unwrap();
+    let new = Patch::open(&new).unwrap();
+    let deltas: Vec<_> = diff_patch_data(old.data(), new.data())
+        .into_iter()
+        .filter(|d| !d.is_empty())
+        .collect();
+    assert_eq!(deltas.len(), 1, "{:?}", deltas);
+    assert_eq!(deltas[0].category, "Added files");
+    assert_eq!(deltas[0].only_in_new, [PathBuf::from("extra/new.txt")]);
+    assert!(deltas[0].only_in_old.is_empty());
+    assert!(deltas[0].changed.is_empty());
+}
@@ -0,0 +1,40 @@
+//! How many I/O threads are used
+
+use diffpatch::utils::{StorageKind, default_thread_count, get_io_thread_count, io_thread_count};
+use diffpatch::{ApplyOptions, CreateOptions};
+use std::env;
+
+// The only test in this binary, since it changes the environment
+#[test]
+fn overrides_take_precedence_over_the_detected_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let cpus = num_cpus::get();
+    for kind in [
+        StorageKind::Ssd,
+        StorageKind::Hdd,
+        StorageKind::Network,
+        StorageKind::Unknown,
+    ] {
+        let threads = default_thread_count(kind);
+        assert!(
+            (1..=cpus.min(16)).contains(&threads),
+            "{:?}: {}",
+            kind,
+            threads
+        );
+    }
+    assert!(default_thread_count(StorageKind::Hdd) <= default_thread_count(StorageKind::Ssd));
+    let detected = get_io_thread_count(dir.path(), None);
+    assert!((1..=cpus.min(16)).contains(&detected), "{}", detected);
+
+    // SAFETY: no other thread reads the environment while this test runs
+    unsafe { env::set_var("DIFFPATCH_IO_THREADS", "3") };
+    assert_eq!(get_io_thread_count(dir.path(), None), 3);
+    assert_eq!(io_thread_count(StorageKind::Network, None), 3);
+
+    // Counts set in the options win over the environment, 0 meaning one per core
+    let create = CreateOptions::new().threads(Some(5));
+    assert_eq!(get_io_thread_count(dir.path(), create.threads), 5);
+    let apply = ApplyOptions::new().threads(Some(0));
+    assert_eq!(io_thread_count(StorageKind::Network, apply.threads), cpus);
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::{Fixture, apply_patch, read_files, write_files};
+use diffpatch::repair::repair;
+use diffpatch::utils::{file_mode, set_file_mode};
+use diffpatch::verify::{Drift, verify_applied};
+use diffpatch::{ApplyOptions, CreateOptions};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn applied_directory_passes_and_tampered_file_is_reported() {
+    let fixture = Fixture::new();
+    write_files(
+        &fixture.source(),
+        &[("a.txt", "v1"), ("old.txt", "removed")],
+    );
+    write_files(&fixture.target(), &[("a.txt", "v2"), ("new.txt", "added")]);
+    let patch = fixture.create_patch(&CreateOptions::new());
+    let install = fixture.install();
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+
+    assert!(verify_applied(&patch, &install).unwrap().is_empty());
+
+    write_files(&install, &[("new.txt", "tampered")]);
+    let drift = verify_applied(&patch, &install).unwrap();
+    assert_eq!(drift.len(), 1, "{:?}", drift);
+    assert!(
+        matches!(&drift[0], Drift::HashMismatch { path, .. } if path == Path::new("new.txt")),
+        "{:?}",
+        drift
+    );
+}
+
+/// Path a drift entry is about
+fn drift_path(drift: &Drift) -> &Path {
+    match drift {
+        Drift::Missing(path) | Drift::NotRemoved(path) => path,
+        Drift::HashMismatch { path, .. }
+        | Drift::WrongType { path, .. }
+        | Drift::WrongLink { path, .. } => path,
+    }
+}
+
+#[test]
+fn repair_rewrites_only_the_damaged_files() {
+    let fixture = Fixture::new();
+    let text = "line\n".repeat(200);
+    let moved = "moved content\n".repeat(20);
+    write_files(
+        &fixture.source(),
+        &[
+            ("app.txt", "v1"),
+            ("notes.txt", &text),
+            ("old.txt", "removed"),
+            ("from/moved.txt", &moved),
+            ("kept.txt", "kept"),
+        ],
+    );
+    write_files(
+        &fixture.target(),
+        &[
+            ("app.txt", "v2"),
+            ("notes.txt", &text.replacen("line", "edited", 1)),
+            ("new.txt", "added"),
+            ("to/moved.txt", &moved),
+            ("kept.txt", "kept"),
+        ],
+    );
+    set_file_mode(&fixture.target().join("new.txt"), Some(0o755)).unwrap();
+    set_file_mode(&fixture.target().join("notes.txt"), Some(0o750)).unwrap();
+    let patch = fixture.create_patch(&CreateOptions::new().use_diff_patches(true));
+    let install = fixture.install();
+    apply_patch(&patch, &install, &ApplyOptions::new()).unwrap();
+
+    // Damage an added, a diffed and a moved file and bring a removed one back
+    write_files(
+        &install,
+        &[
+            ("new.txt", "corrupted"),
+            ("notes.txt", &text),
+            ("old.txt", "back"),
+        ],
+    );
+    set_file_mode(&install.join("new.txt"), Some(0o600)).unwrap();
+    set_file_mode(&install.join("notes.txt"), Some(0o600)).unwrap();
+    fs::rename(install.join("to/moved.txt"), install.join("from/moved.txt")).unwrap();
+    let untouched = fs::metadata(install.join("app.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    let outcome = repair(&patch, &install).unwrap();
+    let mut repaired: Vec<&Path> = outcome.repaired.iter().map(drift_path).collect();
+    repaired.sort();
+    assert_eq!(
+        repaired,
+        ["new.txt", "notes.txt", "old.txt", "to/moved.txt"].map(Path::new)
+    );
+    assert!(outcome.remaining.is_empty(), "{:?}", outcome.remaining);
+    assert_eq!(read_files(&install), read_files(&fixture.target()));
+    assert_eq!(
+        fs::metadata(install.join("app.txt"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        untouched
+    );
+    // Rewritten files get back the mode the patch recorded
+    let mode = |dir: &Path, name| file_mode(&fs::metadata(dir.join(name)).unwrap());
+    for name in ["new.txt", "notes.txt"] {
+        assert_eq!(
+            mode(&install, name),
+            mode(&fixture.target(), name),
+            "{}",
+            name
+        );
+    }
+
+    let again = repair(&patch, &install).unwrap();
+    assert!(again.repaired.is_empty() && again.remaining.is_empty());
+}